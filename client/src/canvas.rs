@@ -7,6 +7,15 @@ use std::{collections::BTreeMap, error::Error};
 use std::{collections::BTreeSet, f64::consts::PI};
 use wasm_bindgen::JsCast;
 use wasm_bindgen::JsValue;
+use web_sys::CanvasWindingRule;
+
+// The length of the sides of a task's triangle, in map units.
+const TASK_SIZE: f64 = 15.0;
+// How much of the screen's width the minimap takes up, up to a limit.
+const MINIMAP_FRACTION: f64 = 0.4;
+const MINIMAP_MAX_WIDTH: f64 = 480.0;
+// How big the player and tasks are on the minimap, in pixels.
+const MINIMAP_MARKER_SIZE: f64 = 8.0;
 
 fn find_canvas_in_document() -> Result<
   (
@@ -42,6 +51,8 @@ pub struct Canvas {
   camera: Camera,
   context: web_sys::CanvasRenderingContext2d,
   canvas_element: web_sys::HtmlCanvasElement,
+  // Toggled by the player, to show the whole map in a corner at night.
+  show_minimap: bool,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -225,9 +236,14 @@ impl Canvas {
       camera: Camera::get_global_camera((width, height)),
       width,
       height,
+      show_minimap: false,
     })
   }
 
+  pub fn set_minimap_visible(&mut self, visible: bool) {
+    self.show_minimap = visible;
+  }
+
   pub fn find_in_document() -> Result<Canvas, JsValue> {
     let (canvas_element, context) =
       find_canvas_in_document().map_err(|e| JsValue::from(format!("{}", e)))?;
@@ -265,7 +281,8 @@ impl Canvas {
     match &game.state.status {
       GameStatus::Connecting => (),
       GameStatus::Disconnected => {
-        self.draw_big_centered_text("Disconnected! D:")?;
+        let message = game.fatal_error.as_deref().unwrap_or("Disconnected! D:");
+        self.draw_big_centered_text(message)?;
       }
       GameStatus::Won(team) => {
         let message = match game.has_won(team) {
@@ -273,10 +290,16 @@ impl Canvas {
           Some(false) => "You lose!".to_string(),
           None => format!("{:?} win!", team),
         };
-        self.draw_big_centered_text(&message)?;
+        match &game.game_stats {
+          None => self.draw_big_centered_text(&message)?,
+          Some(stats) => {
+            self.camera = Camera::get_global_camera((self.width, self.height));
+            self.draw_scoreboard(&message, stats)?;
+          }
+        }
       }
       GameStatus::Lobby | GameStatus::Playing(PlayState::Night) => {
-        self.draw_night(&game)?;
+        self.draw_night(game)?;
       }
       GameStatus::Playing(PlayState::Voting(vote_state)) => {
         self.camera = Camera::get_global_camera((self.width, self.height));
@@ -291,13 +314,9 @@ impl Canvas {
           vote_state.get_votes_against()
         };
         self.draw_voting_grid(
-          &game,
-          &vote_state
-            .votes
-            .iter()
-            .map(|(uuid, _target)| *uuid)
-            .collect(),
-          voting_ui_state.map(|s| s.highlighted_player).flatten(),
+          game,
+          &vote_state.votes.keys().copied().collect(),
+          voting_ui_state.and_then(|s| s.highlighted_player),
           &votes,
           vote_state.time_remaining,
           " remaining to vote",
@@ -306,7 +325,7 @@ impl Canvas {
       GameStatus::Playing(PlayState::TallyingVotes(tally_state)) => {
         self.camera = Camera::get_global_camera((self.width, self.height));
         self.draw_voting_grid(
-          &game,
+          game,
           &BTreeSet::new(),
           None,
           &tally_state.votes_against,
@@ -336,10 +355,47 @@ impl Canvas {
       .collect();
     if game.state.status == GameStatus::Lobby && game.local_player().is_some() {
       messages.push(Message::PlainString(format!(
-        "In the lobby. {}/10 players",
-        game.state.players.len()
+        "In the lobby. {}/{} players. Press C to change color, M for the map",
+        game.state.players.len(),
+        game.state.settings.max_players.min(Color::all().len())
       )));
-      messages.push(Message::PlainString(format!("Press P to start")));
+      if let Some(countdown) = game.state.start_countdown {
+        messages.push(Message::PlainString(format!(
+          "The game starts in {}...",
+          (countdown.as_secs_f64().ceil() as u64).max(1)
+        )));
+      }
+      if game.state.settings.require_ready {
+        let ready = game.local_player().map(|p| p.ready).unwrap_or(false);
+        messages.push(Message::PlainString(if ready {
+          "You're ready! Press R if you're not".to_string()
+        } else {
+          "Press R when you're ready".to_string()
+        }));
+      }
+      match game.state.host.and_then(|h| game.state.players.get(&h)) {
+        Some(host) if host.uuid == game.my_uuid => {
+          messages.push(Message::PlainString(
+            "You're the host. Q next to a player kicks them, B bans them".to_string(),
+          ));
+          messages.push(Message::PlainString("Press P to start".to_string()));
+        }
+        Some(host) => messages.push(Message::FormattingString(vec![
+          FormattedText {
+            text: "Waiting for ".to_string(),
+            color: None,
+          },
+          FormattedText {
+            text: host.name.clone(),
+            color: Some(host.color),
+          },
+          FormattedText {
+            text: " to start the game".to_string(),
+            color: None,
+          },
+        ])),
+        None => (),
+      }
     }
     for (i, message) in messages.into_iter().enumerate() {
       self.context.begin_path();
@@ -355,35 +411,162 @@ impl Canvas {
           self.context.fill_text(s, text_pos.0, text_pos.1)?;
         }
         Message::FormattingString(parts) => {
-          let mut x = 0.0;
-          for part in parts {
-            self.context.set_fill_style(
-              &part
-                .color
-                .map(|c| c.to_str().into())
-                .unwrap_or_else(|| JsValue::from("#000")),
-            );
-            self.context.set_stroke_style(
-              &part
-                .color
-                .map(|c| c.text_outline_color().into())
-                .unwrap_or_else(|| JsValue::from("#fff")),
-            );
-            let metrics = self.context.measure_text(&part.text)?;
-            self
-              .context
-              .stroke_text(&part.text, text_pos.0 + x, text_pos.1)?;
-            self
-              .context
-              .fill_text(&part.text, text_pos.0 + x, text_pos.1)?;
-            x += metrics.width();
-          }
+          self.draw_formatted_text(parts, text_pos.0, text_pos.1)?;
         }
       }
     }
     Ok(())
   }
 
+  // Draws text left aligned from (x, y), with each part in its own color.
+  fn draw_formatted_text(&self, parts: &[FormattedText], x: f64, y: f64) -> Result<(), JsValue> {
+    let mut offset = 0.0;
+    for part in parts {
+      self.context.set_fill_style(
+        &part
+          .color
+          .map(|c| c.to_str().into())
+          .unwrap_or_else(|| JsValue::from("#000")),
+      );
+      self.context.set_stroke_style(
+        &part
+          .color
+          .map(|c| c.text_outline_color().into())
+          .unwrap_or_else(|| JsValue::from("#fff")),
+      );
+      let metrics = self.context.measure_text(&part.text)?;
+      self.context.stroke_text(&part.text, x + offset, y)?;
+      self.context.fill_text(&part.text, x + offset, y)?;
+      offset += metrics.width();
+    }
+    Ok(())
+  }
+
+  // The end of game screen, with who was who, what they got up to, and how
+  // each meeting went.
+  fn draw_scoreboard(&self, headline: &str, stats: &GameStats) -> Result<(), JsValue> {
+    self.context.begin_path();
+    self.context.rect(0.0, 0.0, self.width, self.height);
+    self.context.set_fill_style(&JsValue::from_str("#f3f3f3"));
+    self.context.fill();
+
+    self.context.set_text_align("center");
+    self.context.set_text_baseline("middle");
+    self.context.set_font("48px Arial Black");
+    self.context.set_line_width(4.0);
+    self.context.set_fill_style(&JsValue::from("#000"));
+    self.context.set_stroke_style(&JsValue::from("#fff"));
+    self.context.stroke_text(headline, self.width / 2.0, 50.0)?;
+    self.context.fill_text(headline, self.width / 2.0, 50.0)?;
+
+    let name = |uuid: UUID| match stats.player(uuid) {
+      Some(p) => FormattedText {
+        text: p.name.clone(),
+        color: Some(p.color),
+      },
+      None => FormattedText {
+        text: "someone who left".to_string(),
+        color: None,
+      },
+    };
+    let plain = |text: String| FormattedText { text, color: None };
+    let ejected: BTreeSet<UUID> = stats
+      .meetings
+      .iter()
+      .filter_map(|m| match m.outcome {
+        Some(VoteOutcome::Player { uuid }) => Some(uuid),
+        _ => None,
+      })
+      .collect();
+
+    let mut lines: Vec<Vec<FormattedText>> = Vec::new();
+    let mut players: Vec<&PlayerStats> = stats.players.iter().collect();
+    // Impostors first, so everyone can see who they were.
+    players.sort_by_key(|p| !p.impostor);
+    for player in players {
+      let fate = if player.disconnected {
+        "left the game"
+      } else if ejected.contains(&player.uuid) {
+        "thrown out the airlock"
+      } else if player.dead {
+        "killed"
+      } else {
+        "survived"
+      };
+      let record = if player.impostor {
+        format!(
+          "impostor, {} kill{}",
+          player.kills,
+          if player.kills == 1 { "" } else { "s" }
+        )
+      } else {
+        format!(
+          "crew, {}/{} tasks",
+          player.tasks_finished, player.tasks_total
+        )
+      };
+      lines.push(vec![
+        name(player.uuid),
+        plain(format!(" – {}, {}", record, fate)),
+      ]);
+    }
+
+    for (i, meeting) in stats.meetings.iter().enumerate() {
+      lines.push(vec![]);
+      lines.push(vec![
+        plain(format!("Meeting {}: ", i + 1)),
+        name(meeting.called_by),
+        plain(" reported the body of ".to_string()),
+        stats
+          .players
+          .iter()
+          .find(|p| p.color == meeting.body)
+          .map(|p| name(p.uuid))
+          .unwrap_or_else(|| plain(format!("{:?}", meeting.body))),
+      ]);
+      let mut votes = vec![plain("  Votes: ".to_string())];
+      if meeting.votes.is_empty() {
+        votes.push(plain("none".to_string()));
+      }
+      for (j, (voter, target)) in meeting.votes.iter().enumerate() {
+        if j > 0 {
+          votes.push(plain(", ".to_string()));
+        }
+        votes.push(name(*voter));
+        votes.push(plain(" → ".to_string()));
+        votes.push(match target {
+          VoteTarget::Player { uuid } => name(*uuid),
+          VoteTarget::Skip => plain("skip".to_string()),
+        });
+      }
+      lines.push(votes);
+      lines.push(match meeting.outcome {
+        None => vec![plain(
+          "  The game ended before the votes were counted".to_string(),
+        )],
+        Some(VoteOutcome::Tie) => vec![plain("  Tie, no one was thrown out".to_string())],
+        Some(VoteOutcome::Skip) => vec![plain("  Skipped, no one was thrown out".to_string())],
+        Some(VoteOutcome::Player { uuid }) => vec![
+          plain("  ".to_string()),
+          name(uuid),
+          plain(" was thrown out the airlock".to_string()),
+        ],
+      });
+    }
+
+    let font_height = 20.0;
+    self
+      .context
+      .set_font(&format!("{}px Arial Black", font_height));
+    self.context.set_line_width(4.0);
+    self.context.set_text_align("left");
+    for (i, line) in lines.iter().enumerate() {
+      let y = 110.0 + (font_height + 6.0) * (i as f64);
+      self.draw_formatted_text(line, 40.0, y)?;
+    }
+    Ok(())
+  }
+
   fn draw_big_centered_text(&self, message: &str) -> Result<(), JsValue> {
     self.context.begin_path();
     self.context.set_text_align("center");
@@ -467,11 +650,10 @@ impl Canvas {
       Some(p) => p.dead || p.impostor,
     };
 
-    let can_see = |other: &Position| match local_player {
-      Some(p) => p.can_see(&game.state.settings, &game.state.status, other),
-      None => {
-        return true;
-      }
+    let visible_area = game.visible_area();
+    let can_see = |other: &Position| match &visible_area {
+      Some(area) => area.contains(other),
+      None => true,
     };
 
     // Draw tasks, then bodies, then players on top, so tasks are behind everything, then
@@ -482,7 +664,7 @@ impl Canvas {
         if task.finished {
           continue;
         }
-        self.draw_task(*task, local_player.impostor)?;
+        self.draw_task(*task, local_player.impostor, TASK_SIZE)?;
       }
     }
     for body in game.state.bodies.iter() {
@@ -490,15 +672,23 @@ impl Canvas {
         self.draw_body(*body)?;
       }
     }
-    for (_, player) in game.state.players.iter() {
+    let host = match game.state.status {
+      GameStatus::Lobby => game.state.host,
+      _ => None,
+    };
+    for (uuid, player) in game.state.players.iter() {
+      if game.hidden_players.contains(uuid) {
+        // We don't know where they are.
+        continue;
+      }
       if (show_dead_people || !player.dead) && can_see(&player.position) {
-        self.draw_player(player)?
+        self.draw_player(player, host == Some(player.uuid))?
       }
     }
 
     // Draw a semitransparant overlay for fog of war.
     let vision = game.vision();
-    if let Some(vision) = vision {
+    if let (Some(vision), Some(area)) = (vision, &visible_area) {
       if let Some(player) = local_player {
         let vision = vision * self.camera.zoom;
         let (x, y) = self.camera.offset(player.position.x, player.position.y);
@@ -510,16 +700,120 @@ impl Canvas {
         gradient.add_color_stop(0.4, "#0009")?;
         gradient.add_color_stop(1.0, "#0000")?;
 
+        // Inside what they can see, the fog fades in towards the edge of
+        // their vision.
+        self.context.save();
+        self.context.begin_path();
+        self.trace_polygon(area.points());
+        self.context.clip();
         self.context.begin_path();
         self.context.set_fill_style(&gradient);
         self.context.rect(0.0, 0.0, self.width, self.height);
         self.context.fill();
+        self.context.restore();
+
+        // Everywhere else, including behind the furniture, it's all fog.
+        self.context.begin_path();
+        self.context.rect(0.0, 0.0, self.width, self.height);
+        self.trace_polygon(area.points());
+        self.context.set_fill_style(&"#000c".into());
+        self
+          .context
+          .fill_with_canvas_winding_rule(CanvasWindingRule::Evenodd);
+      }
+    }
+
+    if self.show_minimap {
+      if let Some(player) = local_player {
+        self.draw_minimap(game, player)?;
       }
     }
 
     Ok(())
   }
 
+  // The whole map in the top right corner, with where the player is, where
+  // their tasks are, and where meetings are held.
+  fn draw_minimap(&mut self, game: &GameAsPlayer, player: &Player) -> Result<(), JsValue> {
+    let map = &game.state.map;
+    let margin = 10.0;
+    let width = (self.width * MINIMAP_FRACTION).min(MINIMAP_MAX_WIDTH);
+    let zoom = width / map.width();
+    let height = map.height() * zoom;
+    let (left, top) = (self.width - width - margin, margin);
+
+    self.context.begin_path();
+    self.context.rect(left, top, width, height);
+    self.context.set_fill_style(&"#f3f3f3dd".into());
+    self.context.set_stroke_style(&"#000".into());
+    self.context.set_line_width(1.0);
+    self.context.fill();
+    self.context.stroke();
+
+    // Draw the map the same way as the night, just much smaller.
+    let camera = self.camera;
+    self.camera = Camera {
+      zoom,
+      left: -left / zoom,
+      right: (self.width - left) / zoom,
+      top: -top / zoom,
+      bottom: (self.height - top) / zoom,
+    };
+    for shape in map.static_geometry.iter() {
+      self.draw_shape(shape)?;
+    }
+
+    let (meeting_center, meeting_radius) = map.meeting_circle();
+    self.context.begin_path();
+    self.arc(
+      meeting_center.x,
+      meeting_center.y,
+      meeting_radius,
+      0.0,
+      2.0 * PI,
+    )?;
+    self.context.set_fill_style(&"#35885544".into());
+    self.context.fill();
+
+    // Markers are a fixed size on screen, however small the map is.
+    self.context.set_line_width(1.0);
+    for task in player.tasks.iter() {
+      if !task.finished {
+        self.draw_task(*task, player.impostor, MINIMAP_MARKER_SIZE / zoom)?;
+      }
+    }
+    self.context.begin_path();
+    self.arc(
+      player.position.x,
+      player.position.y,
+      MINIMAP_MARKER_SIZE / 2.0 / zoom,
+      0.0,
+      2.0 * PI,
+    )?;
+    self
+      .context
+      .set_fill_style(&JsValue::from_str(player.color.to_str()));
+    self.context.set_stroke_style(&"#000".into());
+    self.context.fill();
+    self.context.stroke();
+
+    self.camera = camera;
+    Ok(())
+  }
+
+  // Adds the polygon to the current path, without starting a new one.
+  fn trace_polygon(&self, points: &[Position]) {
+    for (i, point) in points.iter().enumerate() {
+      let (x, y) = self.camera.offset(point.x, point.y);
+      if i == 0 {
+        self.context.move_to(x, y);
+      } else {
+        self.context.line_to(x, y);
+      }
+    }
+    self.context.close_path();
+  }
+
   fn draw_shape(&self, shape: &Shape) -> Result<(), JsValue> {
     match shape {
       Shape::Circle {
@@ -548,7 +842,7 @@ impl Canvas {
     Ok(())
   }
 
-  fn draw_player(&self, player: &Player) -> Result<(), &'static str> {
+  fn draw_player(&self, player: &Player, is_host: bool) -> Result<(), &'static str> {
     // draw circle
     self.context.begin_path();
     let radius = Player::radius();
@@ -590,6 +884,16 @@ impl Canvas {
       self.context.set_line_width(self.camera.zoom);
       self.stroke_text(&player.name, player.position.x, player.position.y - 14.0)?;
       self.fill_text(&player.name, player.position.x, player.position.y - 14.0)?;
+      if is_host {
+        self.context.set_fill_style(&JsValue::from("#d4a017"));
+        self.stroke_text("host", player.position.x, player.position.y - 28.0)?;
+        self.fill_text("host", player.position.x, player.position.y - 28.0)?;
+      }
+      if player.ready {
+        self.context.set_fill_style(&JsValue::from("#2a2"));
+        self.stroke_text("ready", player.position.x, player.position.y + 24.0)?;
+        self.fill_text("ready", player.position.x, player.position.y + 24.0)?;
+      }
     }
 
     Ok(())
@@ -611,9 +915,9 @@ impl Canvas {
     Ok(())
   }
 
-  fn draw_task(&self, task: Task, fake: bool) -> Result<(), &'static str> {
+  // len is the length of the triangle's sides, in map units.
+  fn draw_task(&self, task: Task, fake: bool, len: f64) -> Result<(), &'static str> {
     self.context.begin_path();
-    let len: f64 = 15.0;
     let pos = task.position;
     // drawing an equilateral triangle...
     let height = (len.powf(2.0) - (len / 2.0).powf(2.0)).sqrt();
@@ -630,10 +934,12 @@ impl Canvas {
     }
     self.context.fill();
     self.context.stroke();
-    self.move_to(pos.x + (len / 2.0), pos.y + 3.0);
-    self.line_to(pos.x + (len / 2.0), pos.y + 9.0);
-    self.move_to(pos.x + (len / 2.0), pos.y + 10.0);
-    self.line_to(pos.x + (len / 2.0), pos.y + 12.0);
+    // And an exclamation mark inside it.
+    let unit = len / TASK_SIZE;
+    self.move_to(pos.x + (len / 2.0), pos.y + 3.0 * unit);
+    self.line_to(pos.x + (len / 2.0), pos.y + 9.0 * unit);
+    self.move_to(pos.x + (len / 2.0), pos.y + 10.0 * unit);
+    self.line_to(pos.x + (len / 2.0), pos.y + 12.0 * unit);
     self.context.stroke();
     Ok(())
   }
@@ -661,7 +967,8 @@ impl Canvas {
     self.context.stroke();
 
     // draw boxes
-    let num_rows = 1 + (((Color::all().len() as f64) / 2.0).ceil() as u32);
+    let player_rows = voting_grid_player_rows(game.state.players.len());
+    let num_rows = player_rows + 1;
     self.context.begin_path();
     self.context.move_to(self.width / 2.0, 0.0);
     self.context.line_to(self.width / 2.0, self.height);
@@ -815,7 +1122,7 @@ impl Canvas {
 
     {
       // Draw the 'skip' option
-      let top_left = (line_width, (row_height * 5.0) + line_width);
+      let top_left = (line_width, (row_height * player_rows as f64) + line_width);
 
       let mut is_selected = false;
       if selected == Some(VoteTarget::Skip) {
@@ -877,7 +1184,10 @@ impl Canvas {
 
     {
       // Draw the time remaining
-      let top_right = (self.width - line_width, (row_height * 5.0) + line_width);
+      let top_right = (
+        self.width - line_width,
+        (row_height * player_rows as f64) + line_width,
+      );
 
       self.context.set_font(&format!(
         "{}px Arial Black",
@@ -890,7 +1200,7 @@ impl Canvas {
       self.context.set_text_baseline("middle");
       self
         .context
-        .set_fill_style(&&if time_remaining < Duration::from_secs(20) {
+        .set_fill_style(&if time_remaining < Duration::from_secs(20) {
           JsValue::from("#d22")
         } else {
           JsValue::from("#fff")
@@ -935,7 +1245,7 @@ impl Canvas {
     let (x, y) = self.camera.offset(x, y);
     self
       .context
-      .fill_text(&text, x, y)
+      .fill_text(text, x, y)
       .map_err(|_| "Failed to fill in text.")
   }
 
@@ -943,7 +1253,7 @@ impl Canvas {
     let (x, y) = self.camera.offset(x, y);
     self
       .context
-      .stroke_text(&text, x, y)
+      .stroke_text(text, x, y)
       .map_err(|_| "Failed to draw text outline.")
   }
 }
@@ -20,6 +20,9 @@ pub struct GameWrapper {
   previous_frame_time: Instant,
   game: Arc<Mutex<Option<GameAsPlayer>>>,
   playback_server: Option<PlaybackServer>,
+  // When playing back a recording, the inputs aren't passed along to the
+  // game, so we keep track of them here.
+  playback_inputs: InputState,
 }
 
 #[wasm_bindgen]
@@ -27,10 +30,8 @@ impl GameWrapper {
   #[allow(clippy::too_many_arguments)]
   pub fn set_inputs(
     &mut self,
-    up: bool,
-    down: bool,
-    left: bool,
-    right: bool,
+    dx: f64,
+    dy: f64,
     kill: bool,
     report: bool,
     activate: bool,
@@ -38,6 +39,9 @@ impl GameWrapper {
     skip_back: bool,
     skip_forward: bool,
     pause_playback: bool,
+    cycle_perspective: bool,
+    ban: bool,
+    change_color: bool,
   ) -> Result<(), JsValue> {
     let mut game = self
       .game
@@ -47,12 +51,8 @@ impl GameWrapper {
       return Ok(());
     }
     let game = game.as_mut().unwrap();
-    let prev_input = game.inputs();
     let input = InputState {
-      up,
-      down,
-      left,
-      right,
+      direction: Velocity { dx, dy },
       kill,
       report,
       activate,
@@ -60,8 +60,13 @@ impl GameWrapper {
       skip_back,
       skip_forward,
       pause_playback,
+      cycle_perspective,
+      ban,
+      change_color,
     };
     if let Some(playback_server) = &mut self.playback_server {
+      let prev_input = self.playback_inputs;
+      self.playback_inputs = input;
       if input.skip_back && !prev_input.skip_back {
         let time = playback_server.current_time();
         playback_server
@@ -88,7 +93,13 @@ impl GameWrapper {
         if !playback_server.paused() {
           self.previous_frame_time = Instant::now();
         }
+      } else if input.cycle_perspective && !prev_input.cycle_perspective {
+        playback_server
+          .cycle_perspective(game)
+          .map_err(|e| JsValue::from(format!("{}", e)))?;
       }
+      // The recording decides what the players do, not our inputs.
+      return Ok(());
     }
     if game.state.status.finished() {
       return Ok(());
@@ -151,10 +162,7 @@ impl GameWrapper {
     let window = web_sys::window().unwrap_throw();
     let href = window.location().href().unwrap_throw();
     let url = web_sys::Url::new(&href).unwrap_throw();
-    let time = match url.search_params().get("time") {
-      None => return None,
-      Some(t) => t,
-    };
+    let time = url.search_params().get("time")?;
     let time: f64 = time.parse().ok()?;
     Some(Duration::from_secs_f64(time))
   }
@@ -162,6 +170,31 @@ impl GameWrapper {
   pub fn draw(&mut self) -> Result<(), JsValue> {
     self.canvas.draw(self.game.clone())
   }
+
+  pub fn set_minimap_visible(&mut self, visible: bool) {
+    self.canvas.set_minimap_visible(visible);
+  }
+
+  // The current settings, encoded as JSON.
+  pub fn settings(&self) -> Result<String, JsValue> {
+    let game = self.game.lock().unwrap_throw();
+    let settings = match game.as_ref() {
+      None => Settings::default(),
+      Some(game) => game.state.settings.clone(),
+    };
+    serde_json::to_string(&settings).map_err(|e| JsValue::from(format!("{}", e)))
+  }
+
+  // Changes the settings for the next game, given settings encoded as JSON.
+  pub fn update_settings(&mut self, settings: &str) -> Result<(), JsValue> {
+    let settings: Settings =
+      serde_json::from_str(settings).map_err(|e| JsValue::from(format!("{}", e)))?;
+    let mut game = self.game.lock().unwrap_throw();
+    match game.as_mut() {
+      None => Err(JsValue::from("Not connected to a game")),
+      Some(game) => game.update_settings(settings).map_err(JsValue::from),
+    }
+  }
 }
 
 fn get_recorded_game() -> Result<Option<RecordedGame>, JsValue> {
@@ -204,6 +237,8 @@ pub fn save_recorded_game(encoded_game: &str) -> Result<(), JsValue> {
   Ok(())
 }
 
+// The game is only ever touched from the browser's main thread.
+#[allow(clippy::arc_with_non_send_sync)]
 #[wasm_bindgen]
 pub fn make_game(name: String) -> Result<GameWrapper, JsValue> {
   crate::utils::set_panic_hook();
@@ -217,6 +252,7 @@ pub fn make_game(name: String) -> Result<GameWrapper, JsValue> {
       canvas: Canvas::find_in_document()?,
       game: Arc::new(Mutex::new(None)),
       playback_server: None,
+      playback_inputs: InputState::default(),
     };
     let join = if spectate {
       JoinRequest::JoinAsSpectator
@@ -233,8 +269,9 @@ pub fn make_game(name: String) -> Result<GameWrapper, JsValue> {
       Some(recording) => recording,
     };
     console_log!(
-      "Starting replay of version {} inside game with version {}",
+      "Starting replay recorded by version {} in format {} inside game with version {}",
       recording.version,
+      recording.format_version,
       get_version_sha()
     );
     let connection = Box::new(PlaybackTx {});
@@ -243,7 +280,10 @@ pub fn make_game(name: String) -> Result<GameWrapper, JsValue> {
     wrapper = GameWrapper {
       previous_frame_time: Instant::now(),
       canvas: Canvas::find_in_document()?,
-      playback_server: Some(PlaybackServer::new(recording)),
+      playback_server: Some(
+        PlaybackServer::new(recording).map_err(|e| JsValue::from(format!("{}", e)))?,
+      ),
+      playback_inputs: InputState::default(),
       game: Arc::new(Mutex::new(Some(game_as_player))),
     };
     if let Some(offset) = wrapper.read_time_offset_from_url() {
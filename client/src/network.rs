@@ -6,6 +6,7 @@ use rust_us_core::GameAsPlayer;
 use rust_us_core::GameTx;
 use rust_us_core::JoinRequest;
 use rust_us_core::ServerToClientMessage;
+use rust_us_core::UUID;
 use std::sync::Arc;
 use std::sync::Mutex;
 use wasm_bindgen::closure::Closure;
@@ -48,7 +49,7 @@ fn get_websocket_url() -> Result<String, JsValue> {
     return Ok(format!("wss://{}/", hostname));
   }
 
-  if port != "" && port != "443" {
+  if !port.is_empty() && port != "443" {
     // we're in dev mode, use the dev mode port
     return Ok(format!("ws://{}:3012/", hostname));
   }
@@ -145,6 +146,7 @@ pub fn create_websocket_and_listen(
     tx.send(&ClientToServerMessage::Join {
       version: get_version_sha().to_string(),
       details: join.clone(),
+      reconnect_token: get_reconnect_token(),
     })
     .unwrap();
   }) as Box<dyn FnMut(JsValue)>);
@@ -152,3 +154,18 @@ pub fn create_websocket_and_listen(
   onopen_callback.forget();
   Ok(())
 }
+
+// A random token that identifies this browser across page loads, so that the
+// server can tell when a banned player comes back.
+fn get_reconnect_token() -> Option<UUID> {
+  let local_storage = web_sys::window()?.local_storage().ok()??;
+  if let Ok(Some(encoded)) = local_storage.get("reconnect token") {
+    if let Ok(token) = serde_json::from_str(&encoded) {
+      return Some(token);
+    }
+  }
+  let token = UUID::random();
+  let encoded = serde_json::to_string(&token).ok()?;
+  local_storage.set("reconnect token", &encoded).ok()?;
+  Some(token)
+}
@@ -0,0 +1,173 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_us_core::*;
+
+// A map much bigger than the first one, with a lot of small furniture, like
+// a map made of hundreds of wall segments would have.
+fn cluttered_map(num_shapes: usize) -> Map {
+  let mut rng = StdRng::seed_from_u64(0);
+  let (width, height) = (6000.0, 3000.0);
+  let shapes: Vec<Shape> = (0..num_shapes)
+    .map(|_| Shape::Circle {
+      radius: rng.gen_range(5.0, 40.0),
+      center: Position {
+        x: rng.gen_range(0.0, width),
+        y: rng.gen_range(0.0, height),
+      },
+      fill_color: "#666",
+      outline_width: 1.0,
+      outline_color: "#000",
+    })
+    .collect();
+  let middle = (
+    Position {
+      x: width / 2.0,
+      y: height / 2.0,
+    },
+    100.0,
+  );
+  Map::new(
+    width,
+    height,
+    middle,
+    middle,
+    Box::leak(shapes.into_boxed_slice()),
+  )
+}
+
+fn maps() -> Vec<(String, Map)> {
+  vec![
+    ("first map".to_string(), GameState::new().map),
+    ("500 shapes".to_string(), cluttered_map(500)),
+  ]
+}
+
+// Where players might be, all over the map.
+fn positions(map: &Map, count: usize) -> Vec<Position> {
+  let mut rng = StdRng::seed_from_u64(1);
+  (0..count)
+    .map(|_| Position {
+      x: rng.gen_range(0.0, map.width()),
+      y: rng.gen_range(0.0, map.height()),
+    })
+    .collect()
+}
+
+const MOVEMENT: Velocity = Velocity { dx: 2.0, dy: -2.0 };
+
+fn bench_collisions(c: &mut Criterion) {
+  let mut group = c.benchmark_group("collide");
+  for (name, map) in maps() {
+    let positions = positions(&map, 100);
+    // What simulate_night used to do.
+    group.bench_with_input(BenchmarkId::new("every shape", &name), &map, |b, map| {
+      b.iter(|| {
+        for position in positions.iter() {
+          let mut movement = MOVEMENT;
+          for shape in map.static_geometry.iter() {
+            movement = shape.collide(*position, Player::radius(), movement, 0.10);
+          }
+          black_box(movement);
+        }
+      })
+    });
+    group.bench_with_input(BenchmarkId::new("grid", &name), &map, |b, map| {
+      b.iter(|| {
+        for position in positions.iter() {
+          black_box(map.collide(*position, MOVEMENT));
+        }
+      })
+    });
+  }
+  group.finish();
+}
+
+fn bench_simulating(c: &mut Criterion) {
+  let mut group = c.benchmark_group("simulate a tick");
+  for (name, map) in maps() {
+    let mut state = GameState::new();
+    state.status = GameStatus::Lobby;
+    for position in positions(&map, 10) {
+      let uuid = UUID::random();
+      let mut player = Player::new(uuid, "Player".to_string(), Color::Red, position);
+      player.velocity = MOVEMENT;
+      state.players.insert(uuid, player);
+    }
+    state.map = map;
+    group.bench_function(&name, |b| {
+      b.iter_batched_ref(
+        || state.clone(),
+        |state| state.simulate(TICK),
+        criterion::BatchSize::SmallInput,
+      )
+    });
+  }
+  group.finish();
+}
+
+fn bench_gen_tasks(c: &mut Criterion) {
+  let settings = Settings::default();
+  let mut group = c.benchmark_group("gen_tasks");
+  for (name, map) in maps() {
+    group.bench_function(&name, |b| b.iter(|| map.gen_tasks::<Vec<Task>>(&settings)));
+  }
+  group.finish();
+}
+
+// The client works this out every frame.
+fn bench_visible_area(c: &mut Criterion) {
+  let settings = Settings::default();
+  let mut group = c.benchmark_group("visible area");
+  for (name, map) in maps() {
+    let positions = positions(&map, 10);
+    group.bench_function(&name, |b| {
+      b.iter(|| {
+        for position in positions.iter() {
+          black_box(VisibilityPolygon::new(
+            &map,
+            *position,
+            settings.crew_vision,
+          ));
+        }
+      })
+    });
+  }
+  group.finish();
+}
+
+// Checking every player, against building a grid of them first. See
+// `closest_within`.
+fn bench_closest(c: &mut Criterion) {
+  let map = GameState::new().map;
+  let players = positions(&map, 15);
+  let target = Position {
+    x: 1000.0,
+    y: 400.0,
+  };
+  let mut group = c.benchmark_group("closest player");
+  group.bench_function("every player", |b| {
+    b.iter(|| closest_within(target, 100.0, players.iter().copied().enumerate()))
+  });
+  group.bench_function("grid", |b| {
+    b.iter(|| {
+      let mut grid = Grid::new(map.width(), map.height(), 128.0);
+      for (i, position) in players.iter().enumerate() {
+        grid.insert(i, *position, 0.0);
+      }
+      let nearby = grid.near(target, 100.0);
+      closest_within(target, 100.0, nearby.into_iter().map(|i| (i, players[i])))
+    })
+  });
+  group.finish();
+}
+
+criterion_group!(
+  benches,
+  bench_collisions,
+  bench_simulating,
+  bench_gen_tasks,
+  bench_visible_area,
+  bench_closest
+);
+criterion_main!(benches);
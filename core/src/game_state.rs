@@ -6,10 +6,17 @@ use serde::Deserializer;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::fmt::Display;
+use std::str::FromStr;
 use std::{collections::BTreeMap, iter::FromIterator};
 use std::{f64::consts::PI, fmt};
 
-#[derive(PartialEq, Clone, Debug)]
+// How much time passes in one step of the server's simulation. The server
+// always simulates in steps of exactly this long, so that the game plays out
+// the same however its tasks get scheduled.
+pub const TICK: Duration = Duration::from_millis(16);
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
   pub speed: f64,
   pub kill_distance: f64,
@@ -19,6 +26,16 @@ pub struct Settings {
   pub impostor_vision: f64,
   pub voting_time: Duration,
   pub num_tasks: usize,
+  pub num_impostors: usize,
+  // The game can't start with fewer players than this.
+  pub min_players: usize,
+  // No one else can join once there are this many players. Every player needs
+  // their own color, so there can never be more than Color::all().len().
+  pub max_players: usize,
+  // Whether everyone has to say they're ready before the game can start.
+  pub require_ready: bool,
+  // How long after the host starts the game it actually begins.
+  pub start_countdown: Duration,
 }
 
 impl Default for Settings {
@@ -32,7 +49,62 @@ impl Default for Settings {
       impostor_vision: 256.0,
       voting_time: Duration::from_secs(120),
       num_tasks: 6,
+      num_impostors: 1,
+      min_players: 4,
+      max_players: 10,
+      require_ready: false,
+      start_countdown: Duration::from_secs(5),
+    }
+  }
+}
+
+impl Settings {
+  // Why these settings can't be used, if they can't. The host sends them, so
+  // they can be anything, and a game with a million tasks never starts.
+  pub fn validate(&self) -> Result<(), String> {
+    let distances = [
+      ("speed", self.speed, 0.5, 20.0),
+      ("kill_distance", self.kill_distance, 0.0, 512.0),
+      ("task_distance", self.task_distance, 0.0, 512.0),
+      ("report_distance", self.report_distance, 0.0, 512.0),
+      ("crew_vision", self.crew_vision, 32.0, 4096.0),
+      ("impostor_vision", self.impostor_vision, 32.0, 4096.0),
+    ];
+    for (name, value, min, max) in distances.iter() {
+      // Written this way around so that NaN fails too.
+      if !(value >= min && value <= max) {
+        return Err(format!("{} has to be from {} to {}", name, min, max));
+      }
     }
+    let durations = [
+      ("voting_time", self.voting_time, Duration::from_secs(600)),
+      (
+        "start_countdown",
+        self.start_countdown,
+        Duration::from_secs(60),
+      ),
+    ];
+    for (name, value, max) in durations.iter() {
+      if value > max {
+        return Err(format!("{} can be at most {} seconds", name, max.as_secs()));
+      }
+    }
+    let max_players = Color::all().len();
+    let counts = [
+      ("num_tasks", self.num_tasks, 0, 30),
+      ("num_impostors", self.num_impostors, 1, max_players),
+      ("min_players", self.min_players, 1, max_players),
+      ("max_players", self.max_players, 1, max_players),
+    ];
+    for (name, value, min, max) in counts.iter() {
+      if value < min || value > max {
+        return Err(format!("{} has to be from {} to {}", name, min, max));
+      }
+    }
+    if self.min_players > self.max_players {
+      return Err("min_players can't be more than max_players".to_string());
+    }
+    Ok(())
   }
 }
 
@@ -47,6 +119,10 @@ impl Default for GameState {
 pub struct GameState {
   pub status: GameStatus,
   pub settings: Settings,
+  // The player in charge of the lobby. The first to join, until they leave.
+  pub host: Option<UUID>,
+  // Time left before the game starts, once the host has started it.
+  pub start_countdown: Option<Duration>,
   pub map: Map,
   pub players: BTreeMap<UUID, Player>,
   pub bodies: Vec<DeadBody>,
@@ -57,6 +133,8 @@ impl GameState {
     GameState {
       status: GameStatus::Connecting,
       settings: Settings::default(),
+      host: None,
+      start_countdown: None,
       players: BTreeMap::new(),
       bodies: Vec::new(),
       map: Map::first_map(),
@@ -65,6 +143,9 @@ impl GameState {
 
   pub fn simulate(&mut self, elapsed: Duration) -> bool {
     self.status.progress_time(elapsed);
+    if let Some(countdown) = &mut self.start_countdown {
+      *countdown = countdown.checked_sub(elapsed).unwrap_or_default();
+    }
     match &self.status {
       GameStatus::Lobby | GameStatus::Playing(PlayState::Night) => self.simulate_night(elapsed),
       GameStatus::Playing(PlayState::Voting(day_state)) => {
@@ -96,9 +177,9 @@ impl GameState {
           }
           self.bodies.clear();
           self.map.place_players_at_night_start(&mut self.players);
-          self.check_for_victories();
-          // Now it's night!
+          // Now it's night! Unless that was the end of the game.
           self.status = GameStatus::Playing(PlayState::Night);
+          self.check_for_victories();
         }
       }
       GameStatus::Connecting | GameStatus::Disconnected | GameStatus::Won(_) => {
@@ -123,13 +204,12 @@ impl GameState {
   }
 
   fn simulate_night(&mut self, elapsed: Duration) {
-    // elapsed is the time, in milliseconds, that has passed since the
-    // last time we simulated.
-    // By making our simulations relative to the amount of time that's passed,
-    // the game will progress the same regardless of the frame rate, which may
+    // elapsed is the time that has passed since the last time we simulated.
+    // The server always simulates one TICK at a time, but clients make their
+    // simulations relative to the amount of time that's passed, so that the
+    // game progresses the same regardless of the frame rate, which may
     // vary between 30fps and 144fps even if our performance is perfect!
-    let time_steps_passed =
-      (elapsed.as_nanos() as f64) / (Duration::from_millis(16).as_nanos() as f64);
+    let time_steps_passed = (elapsed.as_nanos() as f64) / (TICK.as_nanos() as f64);
 
     for (_, player) in self.players.iter_mut() {
       if player.velocity.dx == 0.0 && player.velocity.dy == 0.0 {
@@ -143,9 +223,7 @@ impl GameState {
 
       // Unless you're a ghost, you gotta worry about colliding with the furniture.
       if !player.dead {
-        for shape in self.map.static_geometry.iter() {
-          movement_vector = shape.collide(player.position, Player::radius(), movement_vector, 0.10);
-        }
+        movement_vector = self.map.collide(player.position, movement_vector);
       }
 
       // Advance the player
@@ -173,9 +251,11 @@ impl GameState {
       .keys()
       .map(|k| (*k, PlayerStartInfo::new(&self.map, &self.settings)))
       .collect();
-    let impostor_index = rand::thread_rng().gen_range(0, self.players.len());
+    let num_impostors = self.settings.num_impostors.min(self.players.len());
+    let impostor_indexes =
+      rand::seq::index::sample(&mut rand::thread_rng(), self.players.len(), num_impostors);
     for (i, (_uuid, player_start_info)) in assignments.iter_mut().enumerate() {
-      if i == impostor_index {
+      if impostor_indexes.iter().any(|index| index == i) {
         player_start_info.team = Team::Impostors;
       }
     }
@@ -195,6 +275,7 @@ impl GameState {
       if let Some(player) = self.players.get_mut(uuid) {
         player.impostor = start_info.team == Team::Impostors;
         player.tasks = start_info.tasks.clone();
+        player.ready = false;
       } else {
         return Err(format!(
           "Unable to find player with uuid {} when starting game.",
@@ -203,10 +284,26 @@ impl GameState {
       }
     }
     self.status = GameStatus::Playing(PlayState::Night);
+    self.start_countdown = None;
     self.map.place_players_at_game_start(&mut self.players);
     Ok(())
   }
 
+  // After a game is over, the same group can play again. They keep their names
+  // and colors, but everything else about the last game is forgotten.
+  pub fn return_to_lobby(&mut self) {
+    for player in self.players.values_mut() {
+      player.dead = false;
+      player.impostor = false;
+      player.tasks.clear();
+      player.ready = false;
+    }
+    self.bodies.clear();
+    self.start_countdown = None;
+    self.map.place_players_at_game_start(&mut self.players);
+    self.status = GameStatus::Lobby;
+  }
+
   pub fn note_death(&mut self, body: DeadBody) -> Result<(), String> {
     for (_, player) in self.players.iter_mut() {
       if player.color == body.color {
@@ -282,6 +379,9 @@ impl GameState {
 
   pub fn handle_disconnection(&mut self, disconnected_player: UUID) {
     self.players.remove(&disconnected_player);
+    if self.host == Some(disconnected_player) {
+      self.host = self.players.keys().next().copied();
+    }
     // The game might be over, because we're out of players
     if self.players.is_empty() {
       self.status = GameStatus::Disconnected;
@@ -312,6 +412,8 @@ pub struct Map {
   start_circle: (Position, f64),
   meeting_circle: (Position, f64),
   pub static_geometry: &'static [Shape],
+  // Where each of static_geometry's shapes are, by index.
+  shapes: Grid<usize>,
 }
 
 const FIRST_CONFERENCE_TABLE: Position = Position { x: 275.0, y: 275.0 };
@@ -371,17 +473,44 @@ const FIRST_MAP_GEOMETRY: &[Shape] = &[
   },
 ];
 
+// Enough for a player squeezing between a couple of shapes.
+const MAX_COLLISION_PASSES: usize = 4;
+// A few times bigger than a player, so that most moves only look at one cell.
+const GRID_CELL_SIZE: f64 = 128.0;
+
 impl Map {
-  fn first_map() -> Map {
+  pub fn new(
+    width: f64,
+    height: f64,
+    start_circle: (Position, f64),
+    meeting_circle: (Position, f64),
+    static_geometry: &'static [Shape],
+  ) -> Map {
+    let mut shapes = Grid::new(width, height, GRID_CELL_SIZE);
+    for (index, shape) in static_geometry.iter().enumerate() {
+      let (center, radius) = shape.bounding_circle();
+      shapes.insert(index, center, radius);
+    }
     Map {
-      width: 3036.0,
-      height: 768.0,
-      start_circle: (FIRST_CONFERENCE_TABLE, 100.0),
-      meeting_circle: (FIRST_CONFERENCE_TABLE, 100.0),
-      static_geometry: FIRST_MAP_GEOMETRY,
+      width,
+      height,
+      start_circle,
+      meeting_circle,
+      static_geometry,
+      shapes,
     }
   }
 
+  fn first_map() -> Map {
+    Map::new(
+      3036.0,
+      768.0,
+      (FIRST_CONFERENCE_TABLE, 100.0),
+      (FIRST_CONFERENCE_TABLE, 100.0),
+      FIRST_MAP_GEOMETRY,
+    )
+  }
+
   pub fn width(&self) -> f64 {
     self.width
   }
@@ -390,14 +519,56 @@ impl Map {
     self.height
   }
 
+  // Where everyone's sent when a meeting ends.
+  pub fn meeting_circle(&self) -> (Position, f64) {
+    self.meeting_circle
+  }
+
   pub fn constrain_circle_within_bounds(&self, center: Position, radius: f64) -> Position {
     Position {
       x: center.x.min(self.width - radius).max(0.0 + radius),
-      y: center
-        .y
-        .min(self.height - Player::radius())
-        .max(0.0 + Player::radius()),
+      y: center.y.min(self.height - radius).max(0.0 + radius),
+    }
+  }
+
+  // How far a player can go along movement_vector without running into the
+  // map's furniture, sliding along anything they hit.
+  pub fn collide(&self, position: Position, movement_vector: Velocity) -> Velocity {
+    // Sliding along shapes never takes a player further than they were
+    // going, so only shapes within reach can get in the way.
+    let reach = Player::radius() + movement_vector.magnitude();
+    let nearby = self.shapes.near(position, reach);
+    let mut movement_vector = movement_vector;
+    // Sliding off of one shape can send a player into another that was
+    // already checked, so keep going until none of them get in the way.
+    for _ in 0..MAX_COLLISION_PASSES {
+      let before = movement_vector;
+      for index in nearby.iter() {
+        movement_vector =
+          self.static_geometry[*index].collide(position, Player::radius(), movement_vector, 0.10);
+      }
+      if movement_vector == before {
+        return movement_vector;
+      }
     }
+    // Wedged between shapes, so stay put rather than end up inside one.
+    Velocity::default()
+  }
+
+  // Whether a circle here would overlap any of the map's furniture.
+  pub fn collides_with(&self, position: Position, radius: f64) -> bool {
+    self
+      .shapes_near(position, radius)
+      .any(|shape| shape.collides_with(position, radius))
+  }
+
+  // The furniture that might overlap the given circle.
+  pub fn shapes_near(&self, position: Position, radius: f64) -> impl Iterator<Item = &Shape> {
+    self
+      .shapes
+      .near(position, radius)
+      .into_iter()
+      .map(move |index| &self.static_geometry[index])
   }
 
   pub fn gen_tasks<T: FromIterator<Task>>(&self, settings: &Settings) -> T {
@@ -410,11 +581,7 @@ impl Map {
           x: rng.gen_range(30.0, self.width - 30.0),
           y: rng.gen_range(30.0, self.height - 30.0),
         };
-        if self
-          .static_geometry
-          .iter()
-          .any(|s| s.collides_with(position, 30.0))
-        {
+        if self.collides_with(position, 30.0) {
           continue;
         }
         return Task {
@@ -487,6 +654,11 @@ impl Shape {
         // Circle on circle collision
         // https://www.gamasutra.com/view/feature/131424/pool_hall_lessons_fast_accurate_.php?print=1
 
+        // Not moving, so there's nothing to collide.
+        if movement_vector.magnitude() == 0.0 {
+          return movement_vector;
+        }
+
         // Quick check to see whether, given starting locations and the magnitude of the
         // movement these two could collide if the player moved directly at this shape.
         let sum_radii = radius + self_radius;
@@ -533,15 +705,14 @@ impl Shape {
         let angle = n.angle_between(&vector_between_centers);
         let angle_multiplier = angle * 2.0 / PI;
 
-        let slope_of_tangent_line = -(1.0 / self_center.slope(&new_position));
-        let tangent_point: Position = vector_between_centers
-          .normalize::<Position>()
-          .times(*self_radius);
-        let second_tangent_point = Position {
-          x: tangent_point.x + 1.0,
-          y: tangent_point.y + slope_of_tangent_line,
-        };
-        let tangent_vector: Velocity = tangent_point.sub(second_tangent_point).normalize();
+        // The tangent is perpendicular to the line between the centers. Working
+        // it out from the slope of that line would divide by zero when it's
+        // horizontal.
+        let tangent_vector: Velocity = Velocity {
+          dx: -vector_between_centers.dy,
+          dy: vector_between_centers.dx,
+        }
+        .normalize();
         let reversed_tangent_vector: Velocity = tangent_vector.times(-1.0);
         let tangent_vector = if tangent_vector.distance(&n) < reversed_tangent_vector.distance(&n) {
           tangent_vector
@@ -550,10 +721,40 @@ impl Shape {
         };
 
         let leftover_magnitude = movement_magnitude - distance;
-        return new_velocity.add(
+        new_velocity.add(
           &tangent_vector
             .times::<Velocity>(leftover_magnitude * angle_multiplier * (1.0 - friction)),
-        );
+        )
+      }
+    }
+  }
+
+  // A circle that the whole shape fits inside.
+  pub fn bounding_circle(&self) -> (Position, f64) {
+    match self {
+      Shape::Circle { radius, center, .. } => (*center, *radius),
+    }
+  }
+
+  // How far along a ray from origin, going in direction (which should be a
+  // unit vector), it first hits this shape. None if it misses, or if it
+  // starts inside the shape.
+  pub fn ray_hit(&self, origin: Position, direction: Velocity) -> Option<f64> {
+    match self {
+      Shape::Circle { radius, center, .. } => {
+        let from_center: Velocity = origin.minus(center);
+        let b = from_center.dot_product(&direction);
+        let c = from_center.dot_product(&from_center) - radius * radius;
+        let discriminant = b * b - c;
+        if c < 0.0 || discriminant < 0.0 {
+          return None;
+        }
+        let distance = -b - discriminant.sqrt();
+        if distance < 0.0 {
+          // It's behind us.
+          return None;
+        }
+        Some(distance)
       }
     }
   }
@@ -564,7 +765,7 @@ impl Shape {
         let distance = center.distance(&other_position);
         let collided = distance < (radius + other_radius);
         if collided {
-          console_log!("{:?} collided with {:?}", center, other_position);
+          tracing::trace!("{:?} collided with {:?}", center, other_position);
         }
         collided
       }
@@ -626,6 +827,9 @@ impl<'de> Visitor<'de> for UUIDVisitor {
   where
     E: de::Error,
   {
+    if !value.is_ascii() {
+      return Err(E::custom("expected UUID string to be hex"));
+    }
     if value.len() != 32 {
       return Err(E::custom(format!(
         "expected UUID string to be len 32, was {}",
@@ -649,6 +853,14 @@ impl<'de> Visitor<'de> for UUIDVisitor {
     Ok(UUID { v: bytes })
   }
 }
+impl FromStr for UUID {
+  type Err = String;
+  fn from_str(s: &str) -> Result<UUID, String> {
+    UUIDVisitor
+      .visit_str::<serde::de::value::Error>(s)
+      .map_err(|e| e.to_string())
+  }
+}
 impl<'de> Deserialize<'de> for UUID {
   fn deserialize<D>(deserializer: D) -> Result<UUID, D::Error>
   where
@@ -658,6 +870,31 @@ impl<'de> Deserialize<'de> for UUID {
   }
 }
 
+// Whichever of `things` is closest to `position`, if any are closer than
+// `max_distance`. On a tie, the first one wins.
+//
+// Players, bodies and tasks move or come and go every tick, so there's no
+// grid of them to look things up in, unlike the map's shapes. Building one
+// for each lookup is much slower than checking the dozen or so there are:
+// the "closest player" benchmark takes around 2.5µs with a grid, and 35ns
+// without.
+pub fn closest_within<T>(
+  position: Position,
+  max_distance: f64,
+  things: impl IntoIterator<Item = (T, Position)>,
+) -> Option<T> {
+  let mut closest = None;
+  let mut closest_distance = max_distance;
+  for (thing, thing_position) in things {
+    let distance = position.distance(&thing_position);
+    if distance < closest_distance {
+      closest = Some(thing);
+      closest_distance = distance;
+    }
+  }
+  closest
+}
+
 pub trait Vector2d {
   #[must_use]
   fn x(&self) -> f64;
@@ -707,7 +944,10 @@ pub trait Vector2d {
 
   #[must_use]
   fn angle_between(&self, other: &impl Vector2d) -> f64 {
-    (self.dot_product(other) / (self.magnitude() * other.magnitude())).acos()
+    // Rounding can push the cosine just past 1, where acos is NaN.
+    (self.dot_product(other) / (self.magnitude() * other.magnitude()))
+      .clamp(-1.0, 1.0)
+      .acos()
   }
 
   #[must_use]
@@ -736,6 +976,7 @@ impl Vector2d for Position {
 }
 
 impl Position {
+  #[allow(clippy::should_implement_trait)]
   pub fn sub(self, other: Position) -> impl Vector2d {
     Position {
       x: self.x - other.x,
@@ -788,6 +1029,16 @@ pub enum Color {
   Yellow,
   Purple,
   Gray,
+  Brown,
+  Cyan,
+  Teal,
+  Maroon,
+  Rose,
+  Tan,
+  Coral,
+  Navy,
+  Olive,
+  Gold,
 }
 
 impl Color {
@@ -803,6 +1054,16 @@ impl Color {
       Color::Yellow,
       Color::Purple,
       Color::Gray,
+      Color::Brown,
+      Color::Cyan,
+      Color::Teal,
+      Color::Maroon,
+      Color::Rose,
+      Color::Tan,
+      Color::Coral,
+      Color::Navy,
+      Color::Olive,
+      Color::Gold,
     ]
   }
 
@@ -818,6 +1079,16 @@ impl Color {
       Color::Yellow => "#ffff66",
       Color::Purple => "#8a2be2",
       Color::Gray => "#333333",
+      Color::Brown => "#71491e",
+      Color::Cyan => "#38fedc",
+      Color::Teal => "#008080",
+      Color::Maroon => "#6b2b3c",
+      Color::Rose => "#ecc0d3",
+      Color::Tan => "#928776",
+      Color::Coral => "#d76464",
+      Color::Navy => "#1d2a6b",
+      Color::Olive => "#6b7f2e",
+      Color::Gold => "#b8860b",
     }
   }
 
@@ -826,8 +1097,26 @@ impl Color {
   // Determined by just kinda... eyeballing it, you know?
   pub fn text_outline_color(&self) -> &'static str {
     match self {
-      Color::Red | Color::Blue | Color::Black | Color::Purple | Color::Gray => "#fff",
-      Color::Orange | Color::Yellow | Color::White | Color::Pink | Color::Green => "#000",
+      Color::Red
+      | Color::Blue
+      | Color::Black
+      | Color::Purple
+      | Color::Gray
+      | Color::Brown
+      | Color::Maroon
+      | Color::Tan
+      | Color::Navy
+      | Color::Olive
+      | Color::Teal => "#fff",
+      Color::Orange
+      | Color::Yellow
+      | Color::White
+      | Color::Pink
+      | Color::Green
+      | Color::Cyan
+      | Color::Rose
+      | Color::Coral
+      | Color::Gold => "#000",
     }
   }
 
@@ -853,6 +1142,9 @@ pub struct Player {
   pub impostor: bool,
   pub tasks: Vec<Task>,
   pub velocity: Velocity,
+  // In the lobby, whether the player is ready for the game to start.
+  #[serde(default)]
+  pub ready: bool,
 }
 
 impl Player {
@@ -866,6 +1158,7 @@ impl Player {
       impostor: false,
       tasks: vec![],
       velocity: Velocity::default(),
+      ready: false,
     }
   }
 
@@ -888,13 +1181,24 @@ impl Player {
     }
   }
 
-  pub fn can_see(&self, settings: &Settings, status: &GameStatus, other: &Position) -> bool {
-    let vision = match self.vision(&settings, status) {
-      None => return true,
-      Some(v) => v,
-    };
-    let distance = self.position.distance(other);
-    distance <= vision
+  // What this player can see of the map, or None if they can see everything.
+  pub fn visible_area(
+    &self,
+    settings: &Settings,
+    status: &GameStatus,
+    map: &Map,
+  ) -> Option<VisibilityPolygon> {
+    self
+      .vision(settings, status)
+      .map(|vision| VisibilityPolygon::new(map, self.position, vision))
+  }
+
+  // To check lots of positions, get the visible_area once and use that.
+  pub fn can_see(&self, state: &GameState, other: &Position) -> bool {
+    match self.visible_area(&state.settings, &state.status, &state.map) {
+      None => true,
+      Some(area) => area.contains(other),
+    }
   }
 }
 
@@ -942,6 +1246,16 @@ impl GameStatus {
     }
   }
 
+  pub fn kind(&self) -> &'static str {
+    match self {
+      GameStatus::Connecting => "Connecting",
+      GameStatus::Lobby => "Lobby",
+      GameStatus::Playing(_) => "Playing",
+      GameStatus::Won(_) => "Won",
+      GameStatus::Disconnected => "Disconnected",
+    }
+  }
+
   pub fn is_same_kind(&self, other: &GameStatus) -> bool {
     match self {
       GameStatus::Connecting => matches!(other, GameStatus::Connecting),
@@ -952,15 +1266,23 @@ impl GameStatus {
     }
   }
 
+  // Whether the game is over for good. After a win the players can go back to
+  // the lobby and play again, so only a disconnection is final.
   pub fn finished(&self) -> bool {
     match self {
       GameStatus::Connecting => false,
       GameStatus::Lobby => false,
       GameStatus::Playing(_) => false,
-      GameStatus::Won(_) => true,
+      GameStatus::Won(_) => false,
       GameStatus::Disconnected => true,
     }
   }
+
+  // Whether this round is over, either because someone won or because the
+  // game is finished.
+  pub fn round_over(&self) -> bool {
+    matches!(self, GameStatus::Won(_)) || self.finished()
+  }
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
@@ -1044,7 +1366,10 @@ impl TallyingState {
 
 #[cfg(test)]
 mod tests {
+  use crate::game_state::{FIRST_MAP_GEOMETRY, MAX_COLLISION_PASSES, PI};
   use crate::*;
+  use proptest::prelude::*;
+  use std::time::Duration;
 
   #[test]
   fn test_vote_outcome_no_votes() {
@@ -1111,6 +1436,164 @@ mod tests {
     );
     assert_eq!(state.determine_outcome_of_election(), VoteOutcome::Tie);
   }
+
+  // Anywhere on the map, and a little way off of it.
+  fn any_position() -> impl Strategy<Value = Position> {
+    (-100.0..3136.0, -100.0..868.0).prop_map(|(x, y)| Position { x, y })
+  }
+
+  // Up to ten times the default speed in any direction, including not
+  // moving at all and moving along just one axis.
+  fn any_velocity() -> impl Strategy<Value = Velocity> {
+    let component = prop_oneof![Just(0.0), Just(2.0), Just(-2.0), -20.0..20.0];
+    (component.clone(), component).prop_map(|(dx, dy)| Velocity { dx, dy })
+  }
+
+  // Clients simulate whatever time passed since their last frame, which can
+  // be a lot more than a tick.
+  fn any_elapsed() -> impl Strategy<Value = Duration> {
+    (1u64..100).prop_map(Duration::from_millis)
+  }
+
+  fn is_inside_a_shape(map: &Map, position: Position) -> bool {
+    // Allow for rounding errors when sliding along a shape's edge.
+    map
+      .static_geometry
+      .iter()
+      .any(|shape| shape.collides_with(position, Player::radius() - 0.01))
+  }
+
+  fn game_with_player(position: Position, velocity: Velocity) -> GameState {
+    let mut state = GameState::new();
+    let uuid = UUID::random();
+    let mut player = Player::new(uuid, "Test".to_string(), Color::all()[0], position);
+    player.velocity = velocity;
+    state.players.insert(uuid, player);
+    state
+  }
+
+  fn only_player(state: &GameState) -> &Player {
+    state.players.values().next().unwrap()
+  }
+
+  // A map with lots of small pieces of furniture, which may overlap.
+  fn any_cluttered_map() -> impl Strategy<Value = Map> {
+    let shape = (0.0..1000.0, 0.0..500.0, 1.0..60.0).prop_map(|(x, y, radius)| Shape::Circle {
+      radius,
+      center: Position { x, y },
+      fill_color: "#000",
+      outline_width: 1.0,
+      outline_color: "#000",
+    });
+    prop::collection::vec(shape, 0..100).prop_map(|shapes| {
+      let middle = (Position { x: 500.0, y: 250.0 }, 100.0);
+      Map::new(
+        1000.0,
+        500.0,
+        middle,
+        middle,
+        Box::leak(shapes.into_boxed_slice()),
+      )
+    })
+  }
+
+  proptest! {
+    #[test]
+    fn test_map_collisions_match_checking_every_shape(
+      map in any_cluttered_map(),
+      position in (0.0..1000.0, 0.0..500.0).prop_map(|(x, y)| Position { x, y }),
+      movement in any_velocity(),
+    ) {
+      // Players inside a shape get pushed out further than they were going,
+      // but they never get inside one in the first place.
+      if is_inside_a_shape(&map, position) {
+        return Ok(());
+      }
+      let mut expected = movement;
+      for _ in 0..MAX_COLLISION_PASSES {
+        let before = expected;
+        for shape in map.static_geometry.iter() {
+          expected = shape.collide(position, Player::radius(), expected, 0.10);
+        }
+        if expected == before {
+          break;
+        }
+      }
+      if expected != map.collide(position, movement) {
+        // Only possible if it was wedged, and stopped.
+        prop_assert_eq!(map.collide(position, movement), Velocity::default());
+      }
+      prop_assert_eq!(
+        map.collides_with(position, Player::radius()),
+        map.static_geometry.iter().any(|s| s.collides_with(position, Player::radius()))
+      );
+    }
+
+    #[test]
+    fn test_constrained_circles_are_within_bounds(
+      center in any_position(),
+      radius in 0.0..100.0,
+    ) {
+      let map = Map::first_map();
+      let constrained = map.constrain_circle_within_bounds(center, radius);
+      prop_assert!(constrained.x - radius >= 0.0 && constrained.x + radius <= map.width());
+      prop_assert!(constrained.y - radius >= 0.0 && constrained.y + radius <= map.height());
+      // Circles that were already inside stay where they are.
+      if center.x - radius >= 0.0
+        && center.x + radius <= map.width()
+        && center.y - radius >= 0.0
+        && center.y + radius <= map.height()
+      {
+        prop_assert_eq!(constrained, center);
+      }
+    }
+
+    #[test]
+    fn test_collisions_never_make_nan(
+      shape in prop::sample::select(FIRST_MAP_GEOMETRY),
+      angle in prop_oneof![Just(0.0), Just(PI / 2.0), Just(PI), 0.0..(2.0 * PI)],
+      closeness in prop_oneof![Just(1.0), 0.5..1.5],
+      movement in any_velocity(),
+    ) {
+      // Around the shape in every direction, from touching it to overlapping it.
+      let Shape::Circle { center, radius, .. } = shape;
+      let distance = (radius + Player::radius()) * closeness;
+      let position = Position {
+        x: center.x + distance * angle.cos(),
+        y: center.y + distance * angle.sin(),
+      };
+      let collided = shape.collide(position, Player::radius(), movement, 0.1);
+      prop_assert!(!collided.dx.is_nan() && !collided.dy.is_nan(), "{:?}", collided);
+    }
+
+    #[test]
+    fn test_moving_players_stay_on_the_map(
+      position in any_position(),
+      velocity in any_velocity(),
+      steps in prop::collection::vec(any_elapsed(), 1..50),
+    ) {
+      let map = Map::first_map();
+      let position = map.constrain_circle_within_bounds(position, Player::radius());
+      let mut state = game_with_player(position, velocity);
+      let starts_outside_shapes = !is_inside_a_shape(&map, position);
+      for elapsed in steps {
+        state.simulate_night(elapsed);
+        let player = only_player(&state);
+        prop_assert!(!player.position.x.is_nan() && !player.position.y.is_nan());
+        prop_assert_eq!(
+          map.constrain_circle_within_bounds(player.position, Player::radius()),
+          player.position
+        );
+        if starts_outside_shapes {
+          prop_assert!(
+            !is_inside_a_shape(&map, player.position),
+            "moved into a shape at {:?}",
+            player.position
+          );
+        }
+      }
+    }
+  }
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize, PartialOrd, Ord)]
@@ -0,0 +1,120 @@
+use crate::Position;
+use std::ops::Range;
+
+// Buckets things on a map into square cells, so that finding what's near a
+// point only has to look at the cells around it, however big the map is.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Grid<T> {
+  cell_size: f64,
+  columns: usize,
+  rows: usize,
+  // Row by row, everything that overlaps each cell.
+  cells: Vec<Vec<T>>,
+}
+
+impl<T: Copy + Ord> Grid<T> {
+  pub fn new(width: f64, height: f64, cell_size: f64) -> Self {
+    let columns = ((width / cell_size).ceil() as usize).max(1);
+    let rows = ((height / cell_size).ceil() as usize).max(1);
+    Grid {
+      cell_size,
+      columns,
+      rows,
+      cells: vec![Vec::new(); columns * rows],
+    }
+  }
+
+  // Adds an item that covers the given circle.
+  pub fn insert(&mut self, item: T, center: Position, radius: f64) {
+    let (columns, rows) = self.cells_covering(center, radius);
+    for row in rows {
+      for column in columns.clone() {
+        self.cells[row * self.columns + column].push(item);
+      }
+    }
+  }
+
+  // Everything that might overlap the given circle, each just once and in
+  // order. Things that don't overlap it may be included too, but nothing
+  // that does is left out.
+  pub fn near(&self, center: Position, radius: f64) -> Vec<T> {
+    let (columns, rows) = self.cells_covering(center, radius);
+    let mut found = vec![];
+    for row in rows {
+      for column in columns.clone() {
+        found.extend_from_slice(&self.cells[row * self.columns + column]);
+      }
+    }
+    found.sort_unstable();
+    found.dedup();
+    found
+  }
+
+  // Anything off the edge of the map goes in the cells along that edge.
+  fn cells_covering(&self, center: Position, radius: f64) -> (Range<usize>, Range<usize>) {
+    let cell = |coordinate: f64, count: usize| {
+      ((coordinate / self.cell_size).floor().max(0.0) as usize).min(count - 1)
+    };
+    (
+      cell(center.x - radius, self.columns)..cell(center.x + radius, self.columns) + 1,
+      cell(center.y - radius, self.rows)..cell(center.y + radius, self.rows) + 1,
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Vector2d;
+  use proptest::prelude::*;
+
+  #[test]
+  fn test_near_looks_in_neighboring_cells() {
+    let mut grid = Grid::new(300.0, 300.0, 100.0);
+    grid.insert(0, Position { x: 50.0, y: 50.0 }, 10.0);
+    grid.insert(1, Position { x: 250.0, y: 250.0 }, 10.0);
+    // Spans the middle four cells.
+    grid.insert(2, Position { x: 200.0, y: 200.0 }, 10.0);
+    assert_eq!(grid.near(Position { x: 60.0, y: 60.0 }, 5.0), vec![0]);
+    assert_eq!(grid.near(Position { x: 190.0, y: 190.0 }, 5.0), vec![2]);
+    assert_eq!(
+      grid.near(Position { x: 150.0, y: 150.0 }, 100.0),
+      vec![0, 1, 2]
+    );
+    // Off the map, and nowhere near anything.
+    assert!(grid.near(Position { x: 1000.0, y: 50.0 }, 5.0).is_empty());
+    assert_eq!(
+      grid.near(
+        Position {
+          x: -1000.0,
+          y: 60.0
+        },
+        5.0
+      ),
+      vec![0]
+    );
+  }
+
+  fn any_circle() -> impl Strategy<Value = (Position, f64)> {
+    (-50.0..1050.0, -50.0..550.0, 0.0..200.0).prop_map(|(x, y, r)| (Position { x, y }, r))
+  }
+
+  proptest! {
+    #[test]
+    fn test_near_finds_everything_that_overlaps(
+      circles in prop::collection::vec(any_circle(), 0..50),
+      query in any_circle(),
+    ) {
+      let mut grid = Grid::new(1000.0, 500.0, 64.0);
+      for (i, (center, radius)) in circles.iter().enumerate() {
+        grid.insert(i, *center, *radius);
+      }
+      let near = grid.near(query.0, query.1);
+      for (i, (center, radius)) in circles.iter().enumerate() {
+        if center.distance(&query.0) < radius + query.1 {
+          prop_assert!(near.contains(&i), "{} overlaps but wasn't found", i);
+        }
+      }
+    }
+  }
+}
@@ -1,17 +1,31 @@
 #![warn(rust_2018_idioms)]
 
 mod game_state;
+mod grid;
+mod names;
 mod player;
 mod protocol;
+mod recording_format;
 mod replay;
 mod server;
+mod stats;
+mod vision;
 pub use crate::game_state::*;
+pub use crate::grid::Grid;
 pub use crate::player::*;
 pub use crate::protocol::*;
+pub use crate::recording_format::RecordingFormatVersion;
 pub use crate::replay::*;
 pub use crate::server::*;
+pub use crate::stats::{GameStats, MeetingStats, PlayerStats};
+pub use crate::vision::VisibilityPolygon;
 mod tests;
 
+// Re-exported so that console_log! works in crates that don't depend on
+// tracing themselves.
+#[doc(hidden)]
+pub use tracing;
+
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
@@ -31,9 +45,11 @@ macro_rules! console_log {
     ($($t:tt)*) => (crate::log(&format_args!($($t)*).to_string()))
 }
 
-// If running outside of wasm, console_log! prints to stdout.
+// If running outside of wasm, console_log! logs at debug level, and it's up
+// to the binary to decide where that goes, if anywhere. Code that logs
+// something more or less important than that can use tracing directly.
 #[cfg(not(target_arch = "wasm32"))]
 #[macro_export]
 macro_rules! console_log {
-    ($($t:tt)*) => (println!($($t)*))
+    ($($t:tt)*) => ($crate::tracing::debug!($($t)*))
 }
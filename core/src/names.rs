@@ -0,0 +1,108 @@
+// Player names are shown all over the place: above players, on the voting
+// screen, in messages. So we're a bit picky about what we accept.
+
+pub const MAX_NAME_LENGTH: usize = 20;
+
+// Strips out characters that don't display as anything, like control and
+// zero width characters, and collapses runs of whitespace into one space.
+pub fn sanitize_name(name: &str) -> String {
+  let visible: String = name
+    .chars()
+    .map(|c| if c.is_whitespace() { ' ' } else { c })
+    .filter(|c| !c.is_control() && !is_invisible(*c))
+    .collect();
+  visible.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Why a sanitized name can't be used, if it can't.
+pub fn name_problem(name: &str) -> Option<String> {
+  if name.is_empty() {
+    return Some("Your name can't be empty".to_string());
+  }
+  if name.chars().count() > MAX_NAME_LENGTH {
+    return Some(format!(
+      "Your name can be at most {} characters long",
+      MAX_NAME_LENGTH
+    ));
+  }
+  None
+}
+
+// Names that only differ in case or surrounding space count as the same name.
+pub fn normalize_name(name: &str) -> String {
+  name.trim().to_lowercase()
+}
+
+// If someone already has this name, adds a number to the end to tell them apart.
+pub fn unique_name<'a>(name: String, taken: impl Iterator<Item = &'a str>) -> String {
+  let taken: Vec<String> = taken.map(normalize_name).collect();
+  if !taken.contains(&normalize_name(&name)) {
+    return name;
+  }
+  (2..)
+    .map(|n| {
+      let suffix = format!(" {}", n);
+      let base: String = name
+        .chars()
+        .take(MAX_NAME_LENGTH.saturating_sub(suffix.len()))
+        .collect();
+      format!("{}{}", base.trim_end(), suffix)
+    })
+    .find(|candidate| !taken.contains(&normalize_name(candidate)))
+    .unwrap()
+}
+
+fn is_invisible(c: char) -> bool {
+  matches!(c,
+    '\u{00AD}' // soft hyphen
+    | '\u{200B}'..='\u{200F}' // zero width spaces and joiners, direction marks
+    | '\u{202A}'..='\u{202E}' // direction embedding and overrides
+    | '\u{2060}'..='\u{2064}' // word joiner and invisible operators
+    | '\u{2066}'..='\u{2069}' // direction isolates
+    | '\u{FEFF}' // zero width no-break space
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sanitize_name() {
+    assert_eq!(sanitize_name("  Alice  "), "Alice");
+    assert_eq!(sanitize_name("Al\u{200B}ice\n"), "Alice");
+    assert_eq!(sanitize_name("Big\t\tBob"), "Big Bob");
+    assert_eq!(sanitize_name("\u{202E}evE"), "evE");
+    assert_eq!(sanitize_name("\u{0007}\u{FEFF}"), "");
+  }
+
+  #[test]
+  fn test_name_problem() {
+    assert_eq!(name_problem("Alice"), None);
+    assert!(name_problem("").is_some());
+    assert_eq!(name_problem(&"é".repeat(MAX_NAME_LENGTH)), None);
+    assert!(name_problem(&"a".repeat(MAX_NAME_LENGTH + 1)).is_some());
+  }
+
+  #[test]
+  fn test_unique_name() {
+    let taken = ["Alice", "bob", "Bob 2"];
+    assert_eq!(
+      unique_name("Carol".to_string(), taken.iter().copied()),
+      "Carol"
+    );
+    assert_eq!(
+      unique_name("alice".to_string(), taken.iter().copied()),
+      "alice 2"
+    );
+    assert_eq!(
+      unique_name("Bob".to_string(), taken.iter().copied()),
+      "Bob 3"
+    );
+    let long = "x".repeat(MAX_NAME_LENGTH);
+    let taken = [long.as_str()];
+    let unique = unique_name(long.clone(), taken.iter().copied());
+    assert_eq!(unique.chars().count(), MAX_NAME_LENGTH);
+    assert!(unique.ends_with(" 2"));
+  }
+}
@@ -5,12 +5,12 @@ use std::collections::BTreeSet;
 
 // The state of user input at some point in time. i.e. what buttons is
 // the user holding down?
-#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct InputState {
-  pub up: bool,
-  pub down: bool,
-  pub left: bool,
-  pub right: bool,
+  // Which way the player wants to go, with each component from -1 to 1. The
+  // arrow keys are all or nothing, but sticks and the touch screen's joystick
+  // can be anywhere in between.
+  pub direction: Velocity,
   pub kill: bool,
   pub activate: bool,
   pub report: bool,
@@ -18,17 +18,30 @@ pub struct InputState {
   pub skip_back: bool,
   pub skip_forward: bool,
   pub pause_playback: bool,
+  pub cycle_perspective: bool,
+  pub ban: bool,
+  pub change_color: bool,
 }
 
 impl InputState {
   // Returns an InputState with buttons set to true if they
   // aren't pressed on self, but are set on newer_input.
+  // A direction counts as pressed once it's pushed far enough that way.
   fn get_new_presses(&self, newer_input: InputState) -> InputState {
+    let pressed = |old: f64, new: f64| {
+      if new >= DIRECTION_PRESSED && old < DIRECTION_PRESSED {
+        1.0
+      } else if new <= -DIRECTION_PRESSED && old > -DIRECTION_PRESSED {
+        -1.0
+      } else {
+        0.0
+      }
+    };
     InputState {
-      up: !self.up && newer_input.up,
-      down: !self.down && newer_input.down,
-      left: !self.left && newer_input.left,
-      right: !self.right && newer_input.right,
+      direction: Velocity {
+        dx: pressed(self.direction.dx, newer_input.direction.dx),
+        dy: pressed(self.direction.dy, newer_input.direction.dy),
+      },
       kill: !self.kill && newer_input.kill,
       activate: !self.activate && newer_input.activate,
       report: !self.report && newer_input.report,
@@ -36,10 +49,36 @@ impl InputState {
       skip_back: !self.skip_back && newer_input.skip_back,
       skip_forward: !self.skip_forward && newer_input.skip_forward,
       pause_playback: !self.pause_playback && newer_input.pause_playback,
+      cycle_perspective: !self.cycle_perspective && newer_input.cycle_perspective,
+      ban: !self.ban && newer_input.ban,
+      change_color: !self.change_color && newer_input.change_color,
     }
   }
+
+  pub fn up(&self) -> bool {
+    self.direction.dy <= -DIRECTION_PRESSED
+  }
+
+  pub fn down(&self) -> bool {
+    self.direction.dy >= DIRECTION_PRESSED
+  }
+
+  pub fn left(&self) -> bool {
+    self.direction.dx <= -DIRECTION_PRESSED
+  }
+
+  pub fn right(&self) -> bool {
+    self.direction.dx >= DIRECTION_PRESSED
+  }
 }
 
+// How far a stick has to be pushed to count as pressing that direction, for
+// moving around menus and the like.
+const DIRECTION_PRESSED: f64 = 0.5;
+// Analog directions are rounded to this many steps each way, so that a
+// slightly wobbly stick doesn't send the server a new speed every frame.
+const DIRECTION_STEPS: f64 = 8.0;
+
 // A game from the perspective of a specific player
 pub struct GameAsPlayer {
   pub my_uuid: UUID,
@@ -48,6 +87,15 @@ pub struct GameAsPlayer {
   pub socket: Box<dyn GameTx>,
   pub contextual_state: ContextualState,
   pub displayed_messages: Vec<DisplayMessage>,
+  // Sent by the server once the game is over.
+  pub game_stats: Option<GameStats>,
+  // Why the server closed the connection, if it told us.
+  pub fatal_error: Option<String>,
+  // The server's tick as of the last snapshot we got.
+  pub server_tick: u64,
+  // Players the server didn't tell us about in its last snapshot, because we
+  // can't see them. We keep what we last knew of them, but it's out of date.
+  pub hidden_players: BTreeSet<UUID>,
 }
 
 // A game from the perspective of a particular player.
@@ -60,6 +108,10 @@ impl GameAsPlayer {
       my_uuid: uuid,
       socket,
       displayed_messages: Vec::new(),
+      game_stats: None,
+      fatal_error: None,
+      server_tick: 0,
+      hidden_players: BTreeSet::new(),
     }
   }
 
@@ -103,10 +155,17 @@ impl GameAsPlayer {
         self.inputs = new_input;
         Ok(())
       }
+      GameStatus::Won(_) => {
+        let pressed = self.inputs.get_new_presses(new_input);
+        self.inputs = new_input;
+        if pressed.play && self.local_player().is_some() {
+          self.socket.send(&ClientToServerMessage::ReturnToLobby())?;
+        }
+        Ok(())
+      }
       GameStatus::Playing(PlayState::TallyingVotes(_))
       | GameStatus::Playing(PlayState::ViewingOutcome(_))
       | GameStatus::Connecting
-      | GameStatus::Won(_)
       | GameStatus::Disconnected => {
         // Nothing to do
         Ok(())
@@ -128,8 +187,13 @@ impl GameAsPlayer {
     let position = player.position;
     let activating = !current_input.activate && new_input.activate;
     let reporting = !current_input.report && new_input.report;
-    let starting_play =
-      self.state.status == GameStatus::Lobby && !current_input.play && new_input.play;
+    let in_lobby = self.state.status == GameStatus::Lobby;
+    let is_host = self.state.host == Some(self.my_uuid);
+    let starting_play = in_lobby && !current_input.play && new_input.play;
+    // In the lobby, the host can use the kill button to kick people out instead.
+    let is_kicking = in_lobby && is_host && !current_input.kill && new_input.kill;
+    let is_banning = in_lobby && is_host && !current_input.ban && new_input.ban;
+    let changing_color = in_lobby && !current_input.change_color && new_input.change_color;
     self.inputs = new_input;
     // ok, we're done touching player at this point. we redeclare it
     // below so we can use it again, next time mutably.
@@ -143,7 +207,20 @@ impl GameAsPlayer {
     if starting_play {
       self.start()?;
     }
-    if reporting {
+    if is_kicking || is_banning {
+      self.kick_player_near(position, is_banning)?;
+    }
+    if changing_color {
+      self.change_to_next_free_color()?;
+    }
+    // There are no bodies to report in the lobby, so the report button
+    // says whether you're ready instead.
+    if reporting && in_lobby {
+      let ready = !self.local_player().map(|p| p.ready).unwrap_or(false);
+      self
+        .socket
+        .send(&ClientToServerMessage::SetReady { ready })?;
+    } else if reporting {
       self.report_body_near(position)?;
     }
 
@@ -206,10 +283,13 @@ impl GameAsPlayer {
       .filter(|(_, (_, p))| !p.dead)
       .map(|(idx, (uuid, _p))| TargetInVotingTable::new(idx, VoteTarget::Player { uuid: *uuid }))
       .collect();
-    vote_targets.push(TargetInVotingTable::new(10, VoteTarget::Skip));
+    vote_targets.push(TargetInVotingTable::new(
+      voting_grid_player_rows(self.state.players.len()) * 2,
+      VoteTarget::Skip,
+    ));
     match voting_state.highlighted_player {
       None => {
-        if pressed.up || pressed.down || pressed.left || pressed.right {
+        if pressed.up() || pressed.down() || pressed.left() || pressed.right() {
           // Nothing was highlighted, so highlight the first target player.
           voting_state.highlighted_player = vote_targets.first().map(|vt| vt.target);
         }
@@ -219,7 +299,7 @@ impl GameAsPlayer {
           .iter()
           .find(|vt| vt.target == highlighted)
           .ok_or_else(|| "Internal Error: Highlighting a nonexistant player?".to_string())?;
-        if pressed.up {
+        if pressed.up() {
           let mut closest_same_column_above: Option<TargetInVotingTable> = None;
           let mut closest_above: Option<TargetInVotingTable> = None;
           for p in vote_targets.iter() {
@@ -235,7 +315,7 @@ impl GameAsPlayer {
           highlighted =
             closest_same_column_above.unwrap_or_else(|| closest_above.unwrap_or(highlighted));
         }
-        if pressed.down {
+        if pressed.down() {
           let mut closest_same_column_below: Option<TargetInVotingTable> = None;
           let mut closest_below: Option<TargetInVotingTable> = None;
           for p in vote_targets.iter() {
@@ -251,7 +331,7 @@ impl GameAsPlayer {
           highlighted =
             closest_same_column_below.unwrap_or_else(|| closest_below.unwrap_or(highlighted));
         }
-        if pressed.left && highlighted.x == 1 {
+        if pressed.left() && highlighted.x == 1 {
           let mut closest_left_column_above: Option<TargetInVotingTable> = None;
           let mut first_in_left_column: Option<TargetInVotingTable> = None;
           for p in vote_targets.iter() {
@@ -267,7 +347,7 @@ impl GameAsPlayer {
           highlighted = closest_left_column_above
             .unwrap_or_else(|| first_in_left_column.unwrap_or(highlighted));
         }
-        if pressed.right && highlighted.x == 0 {
+        if pressed.right() && highlighted.x == 0 {
           let mut closest_right_column_above: Option<TargetInVotingTable> = None;
           let mut first_in_right_column: Option<TargetInVotingTable> = None;
           for p in vote_targets.iter() {
@@ -296,39 +376,35 @@ impl GameAsPlayer {
   }
 
   fn get_speed(&self) -> Velocity {
-    let mut dx = 0.0;
-    let mut dy = 0.0;
-    if self.inputs.up && !self.inputs.down {
-      dy = -self.state.settings.speed
-    } else if self.inputs.down {
-      dy = self.state.settings.speed
-    }
-    if self.inputs.left && !self.inputs.right {
-      dx = -self.state.settings.speed
-    } else if self.inputs.right {
-      dx = self.state.settings.speed
+    let speed = self.state.settings.speed;
+    let component = |c: f64| {
+      if !c.is_finite() {
+        return 0.0;
+      }
+      (c.clamp(-1.0, 1.0) * DIRECTION_STEPS).round() / DIRECTION_STEPS * speed
+    };
+    Velocity {
+      dx: component(self.inputs.direction.dx),
+      dy: component(self.inputs.direction.dy),
     }
-    Velocity { dx, dy }
   }
 
   fn kill_player_near(&mut self, position: Position) -> Result<(), String> {
-    let mut killed_player: Option<DeadBody> = None;
-    let mut closest_distance = self.state.settings.kill_distance;
-
-    for (_, player) in self.state.players.iter_mut() {
-      if player.impostor || player.uuid == self.my_uuid || player.dead {
-        continue;
-      }
-
-      let distance = position.distance(&player.position);
-      if distance < closest_distance {
-        killed_player = Some(DeadBody {
-          position: player.position,
-          color: player.color,
-        });
-        closest_distance = distance;
-      }
-    }
+    let my_uuid = self.my_uuid;
+    let victims = self
+      .state
+      .players
+      .values()
+      .filter(|p| !p.impostor && p.uuid != my_uuid && !p.dead)
+      .filter(|p| !self.hidden_players.contains(&p.uuid))
+      .map(|p| {
+        let body = DeadBody {
+          position: p.position,
+          color: p.color,
+        };
+        (body, p.position)
+      });
+    let killed_player = closest_within(position, self.state.settings.kill_distance, victims);
 
     if let Some(body) = killed_player {
       self.state.note_death(body)?;
@@ -342,22 +418,61 @@ impl GameAsPlayer {
     Ok(())
   }
 
+  fn kick_player_near(&mut self, position: Position, ban: bool) -> Result<(), String> {
+    let my_uuid = self.my_uuid;
+    let others = self
+      .state
+      .players
+      .values()
+      .filter(|p| p.uuid != my_uuid)
+      .map(|p| (p.uuid, p.position));
+    let target = closest_within(position, self.state.settings.kill_distance, others);
+    if let Some(target) = target {
+      self.socket.send(&if ban {
+        ClientToServerMessage::Ban { target }
+      } else {
+        ClientToServerMessage::Kick { target }
+      })?;
+    }
+    Ok(())
+  }
+
+  fn change_to_next_free_color(&mut self) -> Result<(), String> {
+    let current = match self.local_player() {
+      None => return Ok(()),
+      Some(player) => player.color,
+    };
+    let taken: BTreeSet<Color> = self.state.players.values().map(|p| p.color).collect();
+    let colors = Color::all();
+    let current_index = colors.iter().position(|c| *c == current).unwrap_or(0);
+    let next_free = colors
+      .iter()
+      .cycle()
+      .skip(current_index + 1)
+      .take(colors.len())
+      .find(|c| !taken.contains(c));
+    if let Some(color) = next_free {
+      self
+        .socket
+        .send(&ClientToServerMessage::ChangeColor { color: *color })?;
+    }
+    Ok(())
+  }
+
   fn activate_near(&mut self, position: Position) -> Result<(), String> {
-    let mut closest_distance = self.state.settings.task_distance;
+    let task_distance = self.state.settings.task_distance;
     let local_player = match self.local_player_mut() {
       Some(player) => player,
       None => return Ok(()),
     };
     let is_imp = local_player.impostor;
 
-    let mut finished_task: Option<FinishedTask> = None;
-    for (index, task) in local_player.tasks.iter().enumerate() {
-      let distance = position.distance(&task.position);
-      if distance < closest_distance {
-        finished_task = Some(FinishedTask { index });
-        closest_distance = distance;
-      }
-    }
+    let tasks = local_player
+      .tasks
+      .iter()
+      .enumerate()
+      .map(|(index, task)| (FinishedTask { index }, task.position));
+    let finished_task = closest_within(position, task_distance, tasks);
     if let Some(finished_task) = finished_task {
       if !is_imp {
         self.state.note_finished_task(self.my_uuid, finished_task)?;
@@ -370,15 +485,8 @@ impl GameAsPlayer {
   }
 
   fn report_body_near(&mut self, position: Position) -> Result<(), String> {
-    let mut closest_distance = self.state.settings.report_distance;
-    let mut nearest_body_color: Option<Color> = None;
-    for body in self.state.bodies.iter() {
-      let distance = position.distance(&body.position);
-      if distance < closest_distance {
-        nearest_body_color = Some(body.color);
-        closest_distance = distance;
-      }
-    }
+    let bodies = self.state.bodies.iter().map(|b| (b.color, b.position));
+    let nearest_body_color = closest_within(position, self.state.settings.report_distance, bodies);
     if let Some(color) = nearest_body_color {
       self.socket.send(&ClientToServerMessage::ReportBody {
         dead_body_color: color,
@@ -388,10 +496,7 @@ impl GameAsPlayer {
   }
 
   pub fn disconnected(&mut self) -> Result<(), String> {
-    match self.state.status {
-      GameStatus::Won(_) => (), // do nothing, this is expected
-      _ => self.update_status(GameStatus::Disconnected),
-    };
+    self.update_status(GameStatus::Disconnected);
     Ok(())
   }
 
@@ -400,8 +505,15 @@ impl GameAsPlayer {
   pub fn vision(&self) -> Option<f64> {
     self
       .local_player()
-      .map(|p| p.vision(&self.state.settings, &self.state.status))
-      .flatten()
+      .and_then(|p| p.vision(&self.state.settings, &self.state.status))
+  }
+
+  // What this client should be able to see, or None if they should see
+  // everything.
+  pub fn visible_area(&self) -> Option<VisibilityPolygon> {
+    self
+      .local_player()
+      .and_then(|p| p.visible_area(&self.state.settings, &self.state.status, &self.state.map))
   }
 
   // Returns whether this client is for a player that won.
@@ -428,18 +540,36 @@ impl GameAsPlayer {
         self.my_uuid = uuid;
       }
       ServerToClientMessage::Snapshot(Snapshot {
+        tick,
         status,
+        settings,
+        host,
+        start_countdown,
         bodies,
         players,
+        hidden_players,
       }) => {
+        if self.state.status.round_over() && status == GameStatus::Lobby {
+          // Back to the lobby for another round.
+          self.game_stats = None;
+        }
+        // The server moves everyone around when the game starts or ends, so
+        // don't keep our own idea of where people are across that.
+        let status_changed = !self.state.status.is_same_kind(&status);
+        self.server_tick = tick;
         self.update_status(status);
+        self.state.settings = settings;
+        self.state.host = host;
+        self.state.start_countdown = start_countdown;
         self.state.bodies = bodies;
         // handle disconnections
-        let server_uuids: BTreeSet<_> = players.iter().map(|p| p.uuid).collect();
-        let local_uuids: BTreeSet<_> = self.state.players.iter().map(|(u, _)| *u).collect();
+        let mut server_uuids: BTreeSet<_> = players.iter().map(|p| p.uuid).collect();
+        server_uuids.extend(hidden_players.iter().copied());
+        let local_uuids: BTreeSet<_> = self.state.players.keys().copied().collect();
         for uuid in local_uuids.difference(&server_uuids) {
           self.state.players.remove(uuid);
         }
+        self.hidden_players = hidden_players;
 
         for player in players {
           match self.state.players.get_mut(&player.uuid) {
@@ -456,19 +586,21 @@ impl GameAsPlayer {
                 tasks,
                 position,
                 velocity: speed,
+                ready,
               } = player;
               local_player.name = name;
               local_player.color = color;
               local_player.dead = dead;
               local_player.impostor = impostor;
               local_player.tasks = tasks;
+              local_player.ready = ready;
               // Always trust our local speed over the server
               if player.uuid != self.my_uuid {
                 local_player.velocity = speed;
               }
               // Avoid jitter by ignoring position updates (and instead use local reconning
               // based on speeds) unless the distance is greater than some small amount.
-              if position.distance(&local_player.position) > 30.0 {
+              if status_changed || position.distance(&local_player.position) > 30.0 {
                 local_player.position = position;
               }
             }
@@ -481,10 +613,36 @@ impl GameAsPlayer {
       ServerToClientMessage::DisplayMessage(display_message) => {
         self.displayed_messages.push(display_message);
       }
+      ServerToClientMessage::GameOver(stats) => {
+        self.game_stats = Some(stats);
+      }
+      ServerToClientMessage::Error { code, message } => {
+        console_log!("Server sent an error: {:?} {}", code, message);
+        if code.is_fatal() {
+          self.fatal_error = Some(message);
+        } else {
+          self.displayed_messages.push(DisplayMessage {
+            message: Message::PlainString(message),
+            duration: Duration::from_secs(10),
+            delay_before_show: Duration::from_secs(0),
+          });
+        }
+      }
     }
     Ok(())
   }
 
+  // Asks the server to change the settings for the next game.
+  pub fn update_settings(&mut self, settings: Settings) -> Result<(), String> {
+    if self.state.status != GameStatus::Lobby || self.state.host != Some(self.my_uuid) {
+      return Err("Settings can only be changed by the host, in the lobby".to_string());
+    }
+    self
+      .socket
+      .send(&ClientToServerMessage::UpdateSettings(settings))
+  }
+
+  // The server tells us if the game can't start yet, and why.
   fn start(&mut self) -> Result<(), String> {
     self.socket.send(&ClientToServerMessage::StartGame())?;
     Ok(())
@@ -524,6 +682,12 @@ pub trait GameTx {
   fn send(&self, message: &ClientToServerMessage) -> Result<(), String>;
 }
 
+// The voting screen shows players in two columns, with room for at least ten
+// of them. Skipping goes on the row after the players.
+pub fn voting_grid_player_rows(num_players: usize) -> usize {
+  num_players.max(10).div_ceil(2)
+}
+
 #[derive(Clone, Copy)]
 struct TargetInVotingTable {
   x: usize,
@@ -2,6 +2,7 @@ use crate::*;
 use core::fmt::Debug;
 use core::time::Duration;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientToServerMessage {
@@ -11,6 +12,9 @@ pub enum ClientToServerMessage {
   Join {
     version: String,
     details: JoinRequest,
+    // Identifies the browser across connections, so that a ban sticks.
+    #[serde(default)]
+    reconnect_token: Option<UUID>,
   },
   Vote {
     target: VoteTarget,
@@ -19,6 +23,24 @@ pub enum ClientToServerMessage {
     dead_body_color: Color,
   },
   StartGame(),
+  // Once a game is won, go back to the lobby to play again.
+  ReturnToLobby(),
+  UpdateSettings(Settings),
+  // Only the host can remove players. A ban also keeps them from coming back.
+  Kick {
+    target: UUID,
+  },
+  Ban {
+    target: UUID,
+  },
+  // In the lobby, says whether the player is ready for the game to start.
+  SetReady {
+    ready: bool,
+  },
+  // In the lobby, asks for a color that no one else has.
+  ChangeColor {
+    color: Color,
+  },
 }
 
 impl ClientToServerMessage {
@@ -32,16 +54,64 @@ impl ClientToServerMessage {
       ClientToServerMessage::StartGame() => "StartGame",
       ClientToServerMessage::Vote { .. } => "Vote",
       ClientToServerMessage::ReportBody { .. } => "ReportBody",
+      ClientToServerMessage::ReturnToLobby() => "ReturnToLobby",
+      ClientToServerMessage::UpdateSettings(_) => "UpdateSettings",
+      ClientToServerMessage::Kick { .. } => "Kick",
+      ClientToServerMessage::Ban { .. } => "Ban",
+      ClientToServerMessage::SetReady { .. } => "SetReady",
+      ClientToServerMessage::ChangeColor { .. } => "ChangeColor",
     }
   }
 }
 
+// Almost every message is a snapshot, so there's nothing to gain by boxing them.
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServerToClientMessage {
   Welcome { connection_id: UUID },
   Snapshot(Snapshot),
   Replay(RecordedGame),
   DisplayMessage(DisplayMessage),
+  GameOver(GameStats),
+  // Something the client asked for went wrong. If the error is fatal, the
+  // server closes the connection right after sending it.
+  Error { code: ErrorCode, message: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+  // The client is running a different version of the game than the server.
+  VersionMismatch,
+  // We couldn't make sense of a message from the client.
+  MalformedMessage,
+  // The client asked to do something it can't do right now.
+  ActionRejected,
+  RoomFull,
+  InvalidName,
+  Banned,
+  Kicked,
+  // The server is restarting, so the game is over.
+  ServerRestarting,
+  // Someone running the server ended the game.
+  GameEnded,
+  // The client sent messages too big or too often, and was disconnected.
+  ProtocolViolation,
+}
+
+impl ErrorCode {
+  pub fn is_fatal(&self) -> bool {
+    match self {
+      ErrorCode::MalformedMessage | ErrorCode::ActionRejected => false,
+      ErrorCode::VersionMismatch
+      | ErrorCode::RoomFull
+      | ErrorCode::InvalidName
+      | ErrorCode::Banned
+      | ErrorCode::Kicked
+      | ErrorCode::ServerRestarting
+      | ErrorCode::GameEnded
+      | ErrorCode::ProtocolViolation => true,
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -61,6 +131,8 @@ impl ServerToClientMessage {
       ServerToClientMessage::Snapshot(_) => "Snapshot",
       ServerToClientMessage::Replay(_) => "Replay",
       ServerToClientMessage::DisplayMessage(_) => "DisplayMessage",
+      ServerToClientMessage::GameOver(_) => "GameOver",
+      ServerToClientMessage::Error { .. } => "Error",
     }
   }
 }
@@ -82,9 +154,17 @@ pub struct FinishedTask {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Snapshot {
+  // How many ticks into the round the server was when it took the snapshot.
+  pub tick: u64,
   pub status: GameStatus,
+  pub settings: Settings,
+  pub host: Option<UUID>,
+  pub start_countdown: Option<Duration>,
   pub bodies: Vec<DeadBody>,
   pub players: Vec<Player>,
+  // Players who are in the game, but who the recipient can't see right now,
+  // so they're left out of `players`.
+  pub hidden_players: BTreeSet<UUID>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
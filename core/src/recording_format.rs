@@ -0,0 +1,140 @@
+use crate::TICK;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+// The version of the format that games are recorded in.
+//
+// Bump the minor version for additions, like a new message or a new field
+// with a default, which leave older recordings readable as they are. Bump the
+// major version when older recordings need changing to be read, and add a
+// migration from the previous major version to MIGRATIONS below.
+//
+// 1.0: Pinned to the git SHA of the code that recorded it, rather than versioned.
+// 2.0: Versioned explicitly.
+// 2.1: Records the winning team.
+// 2.2: Messages for playing again and changing the settings.
+// 2.3: The host's Kick and Ban messages.
+// 2.4: The ready toggle, and the start countdown as its own event.
+// 2.5: More colors, and a message for changing yours.
+// 3.0: Entries are numbered by the tick they happened on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RecordingFormatVersion {
+  pub major: u32,
+  pub minor: u32,
+}
+
+impl RecordingFormatVersion {
+  pub const CURRENT: RecordingFormatVersion = RecordingFormatVersion { major: 3, minor: 0 };
+
+  // Recordings made before the format was versioned only know the git SHA
+  // of the code that recorded them.
+  const UNVERSIONED: RecordingFormatVersion = RecordingFormatVersion { major: 1, minor: 0 };
+
+  // Newer minor versions can have messages we don't know about, so only
+  // this version and older minor versions of it can be played back.
+  pub fn can_play_back(&self) -> bool {
+    self.major == Self::CURRENT.major && *self <= Self::CURRENT
+  }
+}
+
+impl Display for RecordingFormatVersion {
+  fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(fmt, "{}.{}", self.major, self.minor)
+  }
+}
+
+type Migration = fn(Value) -> Result<Value, String>;
+
+// MIGRATIONS[i] upgrades a recording from major version i + 1 to major version i + 2.
+const MIGRATIONS: &[Migration] = &[migrate_v1_to_v2, migrate_v2_to_v3];
+
+// Upgrades an encoded recording from whatever format it was saved in to the
+// current format.
+pub(crate) fn migrate_recording(mut recording: Value) -> Result<Value, String> {
+  let mut version = read_format_version(&recording)?;
+  if version > RecordingFormatVersion::CURRENT {
+    return Err(format!(
+      "Recording is in format {}, which is newer than this version of airlock.chat understands ({})",
+      version,
+      RecordingFormatVersion::CURRENT
+    ));
+  }
+  while version.major < RecordingFormatVersion::CURRENT.major {
+    let migration = version
+      .major
+      .checked_sub(1)
+      .and_then(|i| MIGRATIONS.get(i as usize))
+      .ok_or_else(|| {
+        format!(
+          "Don't know how to migrate a recording in format {}",
+          version
+        )
+      })?;
+    recording = migration(recording)?;
+    let migrated_version = read_format_version(&recording)?;
+    if migrated_version.major <= version.major {
+      return Err(format!(
+        "Internal error: migrating a recording from format {} didn't upgrade it",
+        version
+      ));
+    }
+    version = migrated_version;
+  }
+  Ok(recording)
+}
+
+fn read_format_version(recording: &Value) -> Result<RecordingFormatVersion, String> {
+  match recording.get("format_version") {
+    None => Ok(RecordingFormatVersion::UNVERSIONED),
+    Some(version) => serde_json::from_value(version.clone())
+      .map_err(|e| format!("Unable to read the recording's format version: {}", e)),
+  }
+}
+
+// Version 2 replaced pinning recordings to a git SHA with an explicit format
+// version. The entries themselves are unchanged.
+fn migrate_v1_to_v2(recording: Value) -> Result<Value, String> {
+  set_format_version(recording, 2)
+}
+
+// Version 3 numbered each entry with the tick of the server's simulation it
+// happened on, and playback goes by that rather than the time. Older servers
+// simulated roughly one tick every TICK, so that's close enough.
+fn migrate_v2_to_v3(mut recording: Value) -> Result<Value, String> {
+  let entries = recording
+    .get_mut("entries")
+    .and_then(|entries| entries.as_array_mut())
+    .ok_or_else(|| "Expected a version 2 recording to have a list of entries".to_string())?;
+  for entry in entries.iter_mut() {
+    let since_start: Duration = entry
+      .get("since_start")
+      .ok_or_else(|| "Expected every entry to say when it happened".to_string())
+      .and_then(|since_start| {
+        serde_json::from_value(since_start.clone())
+          .map_err(|e| format!("Unable to read when an entry happened: {}", e))
+      })?;
+    let tick = (since_start.as_nanos() / TICK.as_nanos()) as u64;
+    entry
+      .as_object_mut()
+      .ok_or_else(|| "Expected every entry to be a JSON object".to_string())?
+      .insert("tick".to_string(), tick.into());
+  }
+  set_format_version(recording, 3)
+}
+
+fn set_format_version(mut recording: Value, major: u32) -> Result<Value, String> {
+  let fields = recording.as_object_mut().ok_or_else(|| {
+    format!(
+      "Expected a version {} recording to be a JSON object",
+      major - 1
+    )
+  })?;
+  fields.insert(
+    "format_version".to_string(),
+    serde_json::json!({ "major": major, "minor": 0 }),
+  );
+  Ok(recording)
+}
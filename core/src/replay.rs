@@ -1,27 +1,73 @@
+use crate::recording_format::migrate_recording;
 use crate::ServerToClientMessage;
 use crate::*;
 use core::time::Duration;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::BTreeSet;
 use std::error::Error;
 use std::sync::Arc;
 use std::sync::Mutex;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct RecordedGame {
-  // The version of the software this was recorded with.
+  pub format_version: RecordingFormatVersion,
+  // The version of the software this was recorded with. Just for reference,
+  // the format version is what decides whether we can play it back.
   pub version: String,
   pub entries: Vec<RecordingEntry>,
+  // Who won the game, if it was over when it was recorded. Added in format 2.1.
+  pub winner: Option<Team>,
 }
 impl RecordedGame {
-  pub fn new(entries: Vec<RecordingEntry>) -> Self {
+  pub fn new(entries: Vec<RecordingEntry>, winner: Option<Team>) -> Self {
     Self {
+      format_version: RecordingFormatVersion::CURRENT,
       version: get_version_sha().to_string(),
       entries,
+      winner,
     }
   }
 }
+
+// Recordings may have been saved in an older format, so upgrade them
+// before decoding.
+impl<'de> Deserialize<'de> for RecordedGame {
+  fn deserialize<D>(deserializer: D) -> Result<RecordedGame, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let encoded = serde_json::Value::deserialize(deserializer)?;
+    let encoded = migrate_recording(encoded).map_err(de::Error::custom)?;
+    // Decode from a string rather than from the Value, because serde_json's
+    // Value can't decode empty tuple variants like StartGame().
+    let CurrentFormatRecordedGame {
+      format_version,
+      version,
+      entries,
+      winner,
+    } = serde_json::from_str(&encoded.to_string()).map_err(de::Error::custom)?;
+    Ok(RecordedGame {
+      format_version,
+      version,
+      entries,
+      winner,
+    })
+  }
+}
+#[derive(Deserialize)]
+struct CurrentFormatRecordedGame {
+  format_version: RecordingFormatVersion,
+  version: String,
+  entries: Vec<RecordingEntry>,
+  #[serde(default)]
+  winner: Option<Team>,
+}
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RecordingEntry {
+  // How many ticks the server had simulated when this happened. Playback
+  // handles the entry at the same point, so it lines up tick for tick.
+  pub tick: u64,
+  // Just for reference, the tick decides when the entry is played back.
   pub since_start: Duration,
   pub event: RecordingEvent,
 }
@@ -29,6 +75,8 @@ pub struct RecordingEntry {
 pub enum RecordingEvent {
   Message(PlaybackMessage),
   Disconnect(UUID),
+  // The countdown to the game starting ran out.
+  CountdownFinished(StartInfo),
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PlaybackMessage {
@@ -43,23 +91,59 @@ pub enum MaybeDecisionIfPlayingBackRecording {
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServerDecision {
+  // The game started right away.
   StartInfo(StartInfo),
+  // The game will start once the countdown runs out.
+  CountdownStarted(Duration),
   NewPlayerPosition(Position),
+  // The player was banned, so they weren't let in.
+  JoinRefused,
 }
 
+fn ticks_to_duration(ticks: u64) -> Duration {
+  Duration::from_nanos(TICK.as_nanos() as u64 * ticks)
+}
+
+// Who a message the game server sent during playback was for.
+enum Recipients {
+  Everyone,
+  EveryoneExcept(BTreeSet<UUID>),
+  Player(UUID),
+}
+
+// Messages the game server has sent during playback, with who they were for.
+type PendingMessages = Arc<Mutex<Vec<(Recipients, ServerToClientMessage)>>>;
+
 struct PlaybackBroadcaster {
-  pending_messages: Arc<Mutex<Vec<ServerToClientMessage>>>,
+  pending_messages: PendingMessages,
 }
 impl Broadcaster for PlaybackBroadcaster {
-  fn broadcast(&self, message: &ServerToClientMessage) -> Result<(), Box<dyn Error>> {
+  fn broadcast_except(
+    &self,
+    message: &ServerToClientMessage,
+    except: &BTreeSet<UUID>,
+  ) -> Result<(), Box<dyn Error>> {
+    let recipients = if except.is_empty() {
+      Recipients::Everyone
+    } else {
+      Recipients::EveryoneExcept(except.clone())
+    };
     let mut messages = self.pending_messages.lock().unwrap();
-    messages.push(message.clone());
+    messages.push((recipients, message.clone()));
     Ok(())
   }
-  fn send_to_player(&self, _: &UUID, _: &ServerToClientMessage) -> Result<(), Box<dyn Error>> {
-    // ??? what to do here
+  fn send_to_player(
+    &self,
+    uuid: &UUID,
+    message: &ServerToClientMessage,
+  ) -> Result<(), Box<dyn Error>> {
+    let mut messages = self.pending_messages.lock().unwrap();
+    messages.push((Recipients::Player(*uuid), message.clone()));
     Ok(())
   }
+  fn disconnect_player(&self, _uuid: &UUID) -> Result<(), Box<dyn Error>> {
+    Ok(()) // no one to disconnect
+  }
 }
 pub struct PlaybackTx {}
 impl GameTx for PlaybackTx {
@@ -69,61 +153,82 @@ impl GameTx for PlaybackTx {
 }
 
 pub struct PlaybackServer {
-  current_time: Duration,
+  // Time that's passed which hasn't made up a whole tick yet.
+  unsimulated: Duration,
   current_index: usize,
   paused: bool,
   recording: RecordedGame,
   game_server: GameServer,
-  pending_messages: Arc<Mutex<Vec<ServerToClientMessage>>>,
+  pending_messages: PendingMessages,
+  // The player whose point of view we're watching from, or None to
+  // watch as an all seeing spectator.
+  perspective: Option<UUID>,
 }
 
 impl PlaybackServer {
-  pub fn new(recording: RecordedGame) -> Self {
-    let pending_messages = Arc::new(Mutex::new(Vec::new()));
-    let mut game_server = GameServer::new(
-      Box::new(PlaybackBroadcaster {
-        pending_messages: pending_messages.clone(),
-      }),
-      false,
-    );
-    game_server.version = recording.version.clone();
-    game_server.state.status = GameStatus::Lobby;
-    Self {
-      current_time: Duration::from_secs(0),
+  pub fn new(recording: RecordedGame) -> Result<Self, Box<dyn Error>> {
+    if !recording.format_version.can_play_back() {
+      return Err(
+        format!(
+          "Unable to play back a recording in format {}, this version of airlock.chat plays back format {}",
+          recording.format_version,
+          RecordingFormatVersion::CURRENT
+        )
+        .into(),
+      );
+    }
+    let pending_messages: PendingMessages = Arc::default();
+    Ok(Self {
+      unsimulated: Duration::from_secs(0),
       current_index: 0,
       paused: false,
-      game_server,
+      game_server: Self::new_game_server(&pending_messages),
       recording,
       pending_messages,
-    }
+      perspective: None,
+    })
   }
 
-  pub fn restart(&mut self) {
+  fn new_game_server(pending_messages: &PendingMessages) -> GameServer {
     let mut game_server = GameServer::new(
       Box::new(PlaybackBroadcaster {
-        pending_messages: self.pending_messages.clone(),
+        pending_messages: pending_messages.clone(),
       }),
       false,
     );
-    game_server.version = self.recording.version.clone();
     game_server.state.status = GameStatus::Lobby;
-    self.game_server = game_server;
-    self.current_time = Duration::from_millis(0);
+    game_server.playing_back = true;
+    game_server
+  }
+
+  pub fn restart(&mut self) {
+    self.game_server = Self::new_game_server(&self.pending_messages);
+    self.unsimulated = Duration::from_secs(0);
     self.current_index = 0;
+    self.pending_messages.lock().unwrap().clear();
   }
 
   pub fn duration(&self) -> Duration {
     // Assume that the final message marks the end of the recording.
-    self
-      .recording
-      .entries
-      .last()
-      .map(|e| e.since_start)
-      .unwrap_or_else(|| Duration::from_secs(0))
+    ticks_to_duration(self.recording.entries.last().map(|e| e.tick).unwrap_or(0))
   }
 
   pub fn current_time(&self) -> Duration {
-    self.current_time
+    ticks_to_duration(self.game_server.tick)
+  }
+
+  pub fn recording(&self) -> &RecordedGame {
+    &self.recording
+  }
+
+  // The number of recorded entries that have been played back so far.
+  pub fn entries_played(&self) -> usize {
+    self.current_index
+  }
+
+  // The state of the game as the server saw it, at the current time.
+  pub fn game_state(&self) -> &GameState {
+    &self.game_server.state
   }
 
   pub fn skip_to(
@@ -131,14 +236,14 @@ impl PlaybackServer {
     from_start: Duration,
     player: &mut GameAsPlayer,
   ) -> Result<(), Box<dyn Error>> {
-    if from_start < self.current_time {
+    if from_start < self.current_time() {
       self.restart();
       player.displayed_messages.clear();
+      player.game_stats = None;
     }
-    while self.current_time < from_start {
-      let elapsed = Duration::from_millis(16);
-      let finished = self.simulate(elapsed, player, true)?;
-      player.simulate(elapsed);
+    while self.current_time() < from_start {
+      let finished = self.simulate(TICK, player, true)?;
+      player.simulate(TICK);
       if finished {
         // the simulation is done, can't skip past this point
         break;
@@ -149,6 +254,61 @@ impl PlaybackServer {
     Ok(())
   }
 
+  pub fn perspective(&self) -> Option<UUID> {
+    self.perspective
+  }
+
+  // Watch the replay from the point of view of the given player, or as an
+  // all seeing spectator if None. Because the player only knows about the
+  // private messages they were sent, we replay up to the current time
+  // from the start.
+  pub fn set_perspective(
+    &mut self,
+    perspective: Option<UUID>,
+    player: &mut GameAsPlayer,
+  ) -> Result<(), Box<dyn Error>> {
+    self.perspective = perspective;
+    player.my_uuid = perspective.unwrap_or_else(UUID::random);
+    player.state = GameState::new();
+    player.state.status = GameStatus::Lobby;
+    player.contextual_state = ContextualState::Blank;
+    let current_time = self.current_time();
+    self.restart();
+    player.displayed_messages.clear();
+    player.game_stats = None;
+    self.skip_to(current_time, player)?;
+    let message = match player.local_player() {
+      None => Message::PlainString("Watching as a spectator".to_string()),
+      Some(p) => Message::FormattingString(vec![
+        FormattedText {
+          color: None,
+          text: "Watching as ".to_string(),
+        },
+        FormattedText {
+          color: Some(p.color),
+          text: p.name.clone(),
+        },
+      ]),
+    };
+    player.displayed_messages.push(DisplayMessage {
+      message,
+      duration: Duration::from_secs(5),
+      delay_before_show: Duration::from_secs(0),
+    });
+    Ok(())
+  }
+
+  // Switches to the next player in the game, and from the last player back
+  // to the spectator's view.
+  pub fn cycle_perspective(&mut self, player: &mut GameAsPlayer) -> Result<(), Box<dyn Error>> {
+    let mut uuids = self.game_server.state.players.keys();
+    let next = match self.perspective {
+      None => uuids.next().copied(),
+      Some(current) => uuids.skip_while(|u| **u != current).nth(1).copied(),
+    };
+    self.set_perspective(next, player)
+  }
+
   pub fn toggle_pause(&mut self) {
     self.paused = !self.paused;
   }
@@ -166,7 +326,22 @@ impl PlaybackServer {
     if self.paused && !force {
       return Ok(true);
     }
-    let new_time = self.current_time + elapsed;
+    self.unsimulated += elapsed;
+    while self.unsimulated >= TICK {
+      self.unsimulated -= TICK;
+      if self.play_tick()? {
+        self.unsimulated = Duration::from_secs(0);
+        self.deliver_messages(player)?;
+        return Ok(true);
+      }
+    }
+    self.deliver_messages(player)?;
+    Ok(false)
+  }
+
+  // Handles the entries recorded before the server's next tick, then
+  // simulates that tick. Returns whether the recording is over.
+  fn play_tick(&mut self) -> Result<bool, Box<dyn Error>> {
     let mut server_messages = 0;
     loop {
       let entry = match self.recording.entries.get(self.current_index) {
@@ -174,7 +349,7 @@ impl PlaybackServer {
         None => break,
         Some(entry) => entry,
       };
-      if entry.since_start > new_time {
+      if entry.tick > self.game_server.tick {
         break;
       }
       self.current_index += 1;
@@ -187,24 +362,43 @@ impl PlaybackServer {
         RecordingEvent::Disconnect(uuid) => {
           self.game_server.disconnected(*uuid)?;
         }
+        RecordingEvent::CountdownFinished(start_info) => {
+          self.game_server.countdown_finished(start_info)?;
+        }
       };
       server_messages += 1;
     }
-    if self.game_server.state.status.finished() && server_messages == 0 {
+    if self.game_server.state.status.round_over() && server_messages == 0 {
       return Ok(true);
     }
-    self.game_server.simulate(elapsed)?;
-    self.current_time = new_time;
-    self.deliver_messages(player)?;
+    self.game_server.simulate_tick()?;
     Ok(false)
   }
 
   fn deliver_messages(&mut self, player: &mut GameAsPlayer) -> Result<(), Box<dyn Error>> {
     let mut pending_messages = self.pending_messages.lock().unwrap();
-    for message in pending_messages.iter() {
-      player.handle_msg(message.clone())?;
+    for (recipient, message) in pending_messages.iter() {
+      let for_this_viewer = match (recipient, self.perspective) {
+        (Recipients::Everyone, _) => true,
+        (Recipients::EveryoneExcept(except), Some(uuid)) => !except.contains(&uuid),
+        (Recipients::EveryoneExcept(_), None) => true,
+        (Recipients::Player(uuid), perspective) => Some(*uuid) == perspective,
+      };
+      if for_this_viewer {
+        player.handle_msg(message.clone())?;
+      }
     }
     pending_messages.clear();
+    // A live client trusts its own speed over the server's, but in a replay
+    // the recording is the only source of truth.
+    if let Some(uuid) = self.perspective {
+      if let (Some(recorded), Some(local)) = (
+        self.game_server.state.players.get(&uuid),
+        player.state.players.get_mut(&uuid),
+      ) {
+        local.velocity = recorded.velocity;
+      }
+    }
     Ok(())
   }
 }
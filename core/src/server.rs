@@ -1,5 +1,7 @@
+use crate::names::{name_problem, normalize_name, sanitize_name, unique_name};
 use crate::replay::MaybeDecisionIfPlayingBackRecording::*;
 use crate::replay::{RecordingEntry, RecordingEvent};
+use crate::stats::StatsTracker;
 use crate::*;
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
@@ -10,24 +12,59 @@ use std::time::Duration;
 use instant::Instant;
 
 pub trait Broadcaster: Send {
-  fn broadcast(&self, message: &ServerToClientMessage) -> Result<(), Box<dyn Error>>;
+  fn broadcast(&self, message: &ServerToClientMessage) -> Result<(), Box<dyn Error>> {
+    self.broadcast_except(message, &BTreeSet::new())
+  }
+  // Sends the message to every connection but the given ones.
+  fn broadcast_except(
+    &self,
+    message: &ServerToClientMessage,
+    except: &BTreeSet<UUID>,
+  ) -> Result<(), Box<dyn Error>>;
   fn send_to_player(
     &self,
     uuid: &UUID,
     message: &ServerToClientMessage,
   ) -> Result<(), Box<dyn Error>>;
+  // Closes the connection to the given player, after sending any messages
+  // already on their way.
+  fn disconnect_player(&self, uuid: &UUID) -> Result<(), Box<dyn Error>>;
 }
 
+// How long a game can go without hearing from any players before it's ended.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * /* minutes */ 60);
+
 // Implements logic for a game server without knowing about the transport layer.
 // Useful so that we can implement a real game server with web sockets, and the test
 // game server, and potentially a future peer to peer in-client server.
 pub struct GameServer {
   pub version: String,
   pub state: GameState,
+  pub idle_timeout: Duration,
+  // How many ticks the current round has been simulated for.
+  pub tick: u64,
   start_time: Instant,
   last_message_received_at: Instant,
   broadcaster: Box<dyn Broadcaster>,
   recording: Option<Vec<RecordingEntry>>,
+  // Which round of the game this is, counting from 1. Each is recorded separately.
+  round: u32,
+  // Recordings of the rounds before this one, waiting to be saved.
+  finished_rounds: Vec<(u32, RecordedGame)>,
+  stats: StatsTracker,
+  // Who won the current round, once we've told everyone.
+  announced_winner: Option<Team>,
+  // The reconnect token that each connection joined with, if any.
+  reconnect_tokens: BTreeMap<UUID, UUID>,
+  // The name each player asked for, before it was made unique.
+  requested_names: BTreeMap<UUID, String>,
+  banned_tokens: BTreeSet<UUID>,
+  banned_names: BTreeSet<String>,
+  // When playing back a recording, the game starts when the recording says
+  // the countdown finished, rather than when our own countdown does.
+  pub(crate) playing_back: bool,
+  // The server is going to restart, so no new rounds can start.
+  restarting: bool,
 }
 
 impl GameServer {
@@ -36,49 +73,348 @@ impl GameServer {
     Self {
       version: get_version_sha().to_string(),
       state: GameState::new(),
+      idle_timeout: DEFAULT_IDLE_TIMEOUT,
+      tick: 0,
       start_time: now,
       last_message_received_at: now,
       broadcaster,
       recording: if record_game { Some(Vec::new()) } else { None },
+      round: 1,
+      finished_rounds: Vec::new(),
+      stats: StatsTracker::default(),
+      announced_winner: None,
+      reconnect_tokens: BTreeMap::new(),
+      requested_names: BTreeMap::new(),
+      banned_tokens: BTreeSet::new(),
+      banned_names: BTreeSet::new(),
+      playing_back: false,
+      restarting: false,
     }
   }
 
-  pub fn simulate(&mut self, elapsed: Duration) -> Result<bool, Box<dyn Error>> {
-    let timeout_duration = std::time::Duration::from_secs(15 * /* minutes */60);
-    let timed_out = self.last_message_received_at.elapsed() > timeout_duration;
+  // Moves the game forward by one TICK. Returns whether the game is finished.
+  pub fn simulate_tick(&mut self) -> Result<bool, Box<dyn Error>> {
+    self.tick += 1;
+    self.simulate(TICK)
+  }
+
+  fn simulate(&mut self, elapsed: Duration) -> Result<bool, Box<dyn Error>> {
+    let timed_out = self.last_message_received_at.elapsed() > self.idle_timeout;
     if self.state.status != GameStatus::Connecting && timed_out {
       self.state.status = GameStatus::Disconnected;
     }
     let finished = self.state.simulate(elapsed);
-    if let GameStatus::Won(_) = self.state.status {
-      console_log!("Game won, trying to transmit save game");
-      if let Some(recording) = &self.recording {
-        console_log!("Recording exists, transmitting...");
-        let replay = &ServerToClientMessage::Replay(RecordedGame::new(recording.to_vec()));
-        match self.broadcaster.broadcast(replay) {
-          Ok(()) => console_log!("Transmit successful!"),
-          Err(e) => console_log!("Error broadcasting replay: {}", e),
-        }
-      }
+    if self.state.start_countdown == Some(Duration::from_secs(0)) && !self.playing_back {
+      let start_info = self.state.get_game_start_info();
+      self.record_event(&RecordingEvent::CountdownFinished(start_info.clone()));
+      self.start_game(&start_info)?;
     }
+    self.stats.note_status(&self.state.status);
     if let GameStatus::Won(team) = self.state.status {
+      if self.announced_winner.is_none() {
+        self.announced_winner = Some(team);
+        self.announce_winner(team)?;
+      }
+    }
+    Ok(finished)
+  }
+
+  fn announce_winner(&mut self, team: Team) -> Result<(), Box<dyn Error>> {
+    console_log!("Game won, trying to transmit save game");
+    if let Some(recording) = self.get_recording() {
+      console_log!("Recording exists, transmitting...");
+      let replay = &ServerToClientMessage::Replay(recording);
+      match self.broadcaster.broadcast(replay) {
+        Ok(()) => console_log!("Transmit successful!"),
+        Err(e) => tracing::warn!("Error broadcasting replay: {}", e),
+      }
+    }
+    self
+      .broadcaster
+      .broadcast(&ServerToClientMessage::DisplayMessage(DisplayMessage {
+        message: Message::PlainString(format!("{:?} win!", team)),
+        duration: Duration::from_secs(15),
+        delay_before_show: Duration::from_secs(0),
+      }))?;
+    self
+      .broadcaster
+      .broadcast(&ServerToClientMessage::GameOver(
+        self.stats.finish(team, &self.state),
+      ))?;
+    // Only the host can take everyone back to the lobby.
+    let prompt = |text: &str| {
+      ServerToClientMessage::DisplayMessage(DisplayMessage {
+        message: Message::PlainString(text.to_string()),
+        duration: Duration::from_secs(60),
+        delay_before_show: Duration::from_secs(5),
+      })
+    };
+    let host: BTreeSet<UUID> = self.state.host.into_iter().collect();
+    for uuid in host.iter() {
       self
         .broadcaster
-        .broadcast(&&ServerToClientMessage::DisplayMessage(DisplayMessage {
-          message: Message::PlainString(format!("{:?} win!", team)),
-          duration: Duration::from_secs(15),
+        .send_to_player(uuid, &prompt("Press P to play again"))?;
+    }
+    self
+      .broadcaster
+      .broadcast_except(&prompt("Waiting for the host to start another game"), &host)?;
+    Ok(())
+  }
+
+  // When playing back a recording, the countdown finishes when the recording
+  // says it did.
+  pub fn countdown_finished(&mut self, start_info: &StartInfo) -> Result<(), Box<dyn Error>> {
+    if self.state.start_countdown.is_none() {
+      return Err("Internal error: bad recording. The countdown finished without starting".into());
+    }
+    self.start_game(start_info)
+  }
+
+  fn start_game(&mut self, start_info: &StartInfo) -> Result<(), Box<dyn Error>> {
+    self.state.note_game_started(start_info)?;
+    self.broadcast_snapshot()?;
+    for (uuid, player) in self.state.players.iter() {
+      self.broadcaster.send_to_player(
+        uuid,
+        &ServerToClientMessage::DisplayMessage(DisplayMessage {
+          message: Message::PlainString("The game has begun!".to_string()),
+          duration: Duration::from_secs(10),
           delay_before_show: Duration::from_secs(0),
-        }))?;
+        }),
+      )?;
+      if player.impostor {
+        self.broadcaster.send_to_player(
+          uuid,
+          &ServerToClientMessage::DisplayMessage(DisplayMessage {
+            message: Message::PlainString(
+              "You are an evil impostor. Try to find crewmates alone and kill them! (press Q)"
+                .to_string(),
+            ),
+            duration: Duration::from_secs(10),
+            delay_before_show: Duration::from_secs(3),
+          }),
+        )?;
+        self.broadcaster.send_to_player(
+          uuid,
+          &ServerToClientMessage::DisplayMessage(DisplayMessage {
+            message: Message::PlainString(
+              "But don't get caught! The crew can vote you out!".to_string(),
+            ),
+            duration: Duration::from_secs(10),
+            delay_before_show: Duration::from_secs(6),
+          }),
+        )?;
+      } else {
+        self.broadcaster.send_to_player(
+          uuid,
+          &ServerToClientMessage::DisplayMessage(DisplayMessage {
+            message: Message::PlainString(
+              "You are good crewmate. Find your tasks and complete them (press E)".to_string(),
+            ),
+            duration: Duration::from_secs(10),
+            delay_before_show: Duration::from_secs(3),
+          }),
+        )?;
+        let num_impostors = self
+          .state
+          .players
+          .iter()
+          .filter(|(_, p)| p.impostor)
+          .count();
+        self.broadcaster.send_to_player(
+          uuid,
+          &ServerToClientMessage::DisplayMessage(DisplayMessage {
+            message: Message::PlainString(format!("But beware, there's {} evil impostor{} on board. If you find a dead body, press R to report it", num_impostors, if num_impostors == 1 {""} else {"s"})),
+            duration: Duration::from_secs(10),
+            delay_before_show: Duration::from_secs(6),
+          }),
+        )?;
+      }
     }
-    Ok(finished)
+    Ok(())
+  }
+
+  // Whether the players are partway through a round, which it would be a
+  // shame to cut short.
+  pub fn round_in_progress(&self) -> bool {
+    matches!(self.state.status, GameStatus::Playing(_))
+  }
+
+  // The server will restart once the current round is over, so no more
+  // rounds can start.
+  pub fn prepare_for_restart(&mut self) -> Result<(), Box<dyn Error>> {
+    self.restarting = true;
+    self.cancel_countdown("the server is restarting")?;
+    self
+      .broadcaster
+      .broadcast(&ServerToClientMessage::DisplayMessage(DisplayMessage {
+        message: Message::PlainString(
+          "The server is restarting soon. Games that have started can finish, but new ones can't start"
+            .to_string(),
+        ),
+        duration: Duration::from_secs(30),
+        delay_before_show: Duration::from_secs(0),
+      }))?;
+    Ok(())
+  }
+
+  // Ends the game right away, because the server is restarting now.
+  pub fn end_for_restart(&mut self) -> Result<(), Box<dyn Error>> {
+    self.end_game(
+      ErrorCode::ServerRestarting,
+      "The server restarted. Reload the page to play again".to_string(),
+    )
+  }
+
+  // Ends the game right away, telling everyone why.
+  pub fn end_game(&mut self, code: ErrorCode, message: String) -> Result<(), Box<dyn Error>> {
+    self
+      .broadcaster
+      .broadcast(&ServerToClientMessage::Error { code, message })?;
+    self.state.status = GameStatus::Disconnected;
+    Ok(())
+  }
+
+  // Removes someone from the game at the request of someone outside of it,
+  // like whoever's running the server.
+  pub fn kick(&mut self, target: UUID, message: String) -> Result<(), Box<dyn Error>> {
+    self.record_event(&RecordingEvent::Disconnect(target));
+    self.send_error(&target, ErrorCode::Kicked, message)?;
+    self.remove_player(target, " was removed from the game")
+  }
+
+  // Shows a message to everyone in the game.
+  pub fn announce(&self, message: DisplayMessage) -> Result<(), Box<dyn Error>> {
+    self
+      .broadcaster
+      .broadcast(&ServerToClientMessage::DisplayMessage(message))
+  }
+
+  // Why the given player can't start the game right now, if they can't.
+  fn reason_game_cant_start(&self, sender: UUID) -> Option<String> {
+    if self.state.status != GameStatus::Lobby {
+      return Some("The game has already started".to_string());
+    }
+    if self.restarting {
+      return Some("The server is restarting soon, so new games can't start".to_string());
+    }
+    if self.state.host != Some(sender) {
+      return Some("Only the host can start the game".to_string());
+    }
+    if self.state.start_countdown.is_some() {
+      return Some("The game is already starting".to_string());
+    }
+    let settings = &self.state.settings;
+    let num_players = self.state.players.len();
+    if num_players < settings.min_players {
+      return Some(format!(
+        "Need at least {} players to start, but there {} only {}",
+        settings.min_players,
+        if num_players == 1 { "is" } else { "are" },
+        num_players
+      ));
+    }
+    if settings.num_impostors == 0 {
+      return Some("There has to be at least one impostor".to_string());
+    }
+    if settings.num_impostors * 2 >= num_players {
+      return Some(format!(
+        "There need to be fewer impostors than crew, so {} impostor{} need{} at least {} players",
+        settings.num_impostors,
+        if settings.num_impostors == 1 { "" } else { "s" },
+        if settings.num_impostors == 1 { "s" } else { "" },
+        settings.num_impostors * 2 + 1
+      ));
+    }
+    if settings.require_ready {
+      let not_ready: Vec<&str> = self
+        .state
+        .players
+        .values()
+        .filter(|p| !p.ready)
+        .map(|p| p.name.as_str())
+        .collect();
+      if !not_ready.is_empty() {
+        return Some(format!("Waiting for {} to be ready", not_ready.join(", ")));
+      }
+    }
+    None
+  }
+
+  // Stops the game from starting, if the countdown to it has begun.
+  fn cancel_countdown(&mut self, reason: &str) -> Result<(), Box<dyn Error>> {
+    if self.state.start_countdown.take().is_none() {
+      return Ok(());
+    }
+    self
+      .broadcaster
+      .broadcast(&ServerToClientMessage::DisplayMessage(DisplayMessage {
+        message: Message::PlainString(format!("Not starting the game, {}", reason)),
+        duration: Duration::from_secs(10),
+        delay_before_show: Duration::from_secs(0),
+      }))?;
+    Ok(())
+  }
+
+  // Tells the player what went wrong, and disconnects them if they can't carry on.
+  fn send_error(
+    &self,
+    uuid: &UUID,
+    code: ErrorCode,
+    message: String,
+  ) -> Result<(), Box<dyn Error>> {
+    self
+      .broadcaster
+      .send_to_player(uuid, &ServerToClientMessage::Error { code, message })?;
+    if code.is_fatal() {
+      self.broadcaster.disconnect_player(uuid)?;
+    }
+    Ok(())
+  }
+
+  // The transport layer calls this when it gets a message it can't decode.
+  pub fn malformed_message(&self, sender: UUID, problem: &str) -> Result<(), Box<dyn Error>> {
+    self.send_error(
+      &sender,
+      ErrorCode::MalformedMessage,
+      format!("The server couldn't understand a message: {}", problem),
+    )
+  }
+
+  // Disconnects a client that's been breaking the rules of the protocol, e.g.
+  // by flooding us with messages.
+  pub fn protocol_violation(&self, sender: UUID, problem: &str) -> Result<(), Box<dyn Error>> {
+    self.send_error(
+      &sender,
+      ErrorCode::ProtocolViolation,
+      format!("Disconnected for {}", problem),
+    )
   }
 
   pub fn disconnected(&mut self, disconnected_player: UUID) -> Result<(), Box<dyn Error>> {
+    // Kicked and banned players are already gone by the time their connection
+    // closes, and spectators were never in the game.
+    if !self.state.players.contains_key(&disconnected_player) {
+      self.reconnect_tokens.remove(&disconnected_player);
+      self.requested_names.remove(&disconnected_player);
+      return Ok(());
+    }
     self.record_event(&RecordingEvent::Disconnect(disconnected_player));
-    if let Some(player) = self.state.players.get(&disconnected_player) {
+    self.remove_player(disconnected_player, " disconnected")
+  }
+
+  // Takes a player out of the game, letting everyone know how they left.
+  fn remove_player(&mut self, removed_player: UUID, how: &str) -> Result<(), Box<dyn Error>> {
+    self.reconnect_tokens.remove(&removed_player);
+    self.requested_names.remove(&removed_player);
+    let previous_host = self.state.host;
+    if let Some(player) = self.state.players.get(&removed_player) {
+      if let GameStatus::Playing(_) = self.state.status {
+        self.stats.note_disconnection(player);
+      }
       self
         .broadcaster
-        .broadcast(&&ServerToClientMessage::DisplayMessage(DisplayMessage {
+        .broadcast(&ServerToClientMessage::DisplayMessage(DisplayMessage {
           message: Message::FormattingString(vec![
             FormattedText {
               color: Some(player.color),
@@ -86,14 +422,36 @@ impl GameServer {
             },
             FormattedText {
               color: None,
-              text: " disconnected".to_string(),
+              text: how.to_string(),
             },
           ]),
           duration: Duration::from_secs(10),
           delay_before_show: Duration::from_secs(0),
         }))?;
     }
-    self.state.handle_disconnection(disconnected_player);
+    self.state.handle_disconnection(removed_player);
+    self.cancel_countdown("a player left")?;
+    self.stats.note_status(&self.state.status);
+    if self.state.host != previous_host {
+      if let Some(host) = self.state.host.and_then(|h| self.state.players.get(&h)) {
+        self
+          .broadcaster
+          .broadcast(&ServerToClientMessage::DisplayMessage(DisplayMessage {
+            message: Message::FormattingString(vec![
+              FormattedText {
+                color: Some(host.color),
+                text: host.name.clone(),
+              },
+              FormattedText {
+                color: None,
+                text: " is now the host".to_string(),
+              },
+            ]),
+            duration: Duration::from_secs(10),
+            delay_before_show: Duration::from_secs(0),
+          }))?;
+      }
+    }
     self.broadcast_snapshot()?;
     Ok(())
   }
@@ -104,6 +462,21 @@ impl GameServer {
     message: ClientToServerMessage,
   ) -> Result<(), Box<dyn Error>> {
     let decision = self.handle_message_internal(sender, &message, &LiveGame)?;
+    if let ClientToServerMessage::ReturnToLobby() = message {
+      // This started a new recording, which doesn't need to know how.
+      return Ok(());
+    }
+    // Recordings are sent to every player, so keep reconnect tokens out of them.
+    let message = match message {
+      ClientToServerMessage::Join {
+        version, details, ..
+      } => ClientToServerMessage::Join {
+        version,
+        details,
+        reconnect_token: None,
+      },
+      message => message,
+    };
     self.record_event(&RecordingEvent::Message(PlaybackMessage {
       sender,
       message,
@@ -131,85 +504,51 @@ impl GameServer {
     prerecorded_decision: &MaybeDecisionIfPlayingBackRecording,
   ) -> Result<Option<ServerDecision>, Box<dyn Error>> {
     self.last_message_received_at = Instant::now();
-    console_log!("Game server handling {:?}", message);
+    if let ClientToServerMessage::Move(_) = message {
+      // Players send these every frame, so they'd drown out everything else.
+      tracing::trace!(player = %sender, "Game server handling {:?}", message);
+    } else {
+      tracing::debug!(player = %sender, "Game server handling {:?}", message);
+    }
     match message {
       ClientToServerMessage::StartGame() => {
-        if self.state.status != GameStatus::Lobby {
-          console_log!(
-            "Player {} tried to start a game from state {:?}",
-            sender,
-            self.state.status
-          );
-          return Ok(None);
-        }
-        let start_info = match prerecorded_decision {
-          LiveGame => self.state.get_game_start_info(),
-          Playback(Some(ServerDecision::StartInfo(start_info))) => start_info.clone(),
-          invalid => return Err(format!("Expected StartInfo when handling a recorded ClientToServerMessage::StartGame message, but got: {:?}", invalid).into()),
+        let decision = match prerecorded_decision {
+          LiveGame => {
+            if let Some(reason) = self.reason_game_cant_start(sender) {
+              console_log!("Player {} can't start the game: {}", sender, reason);
+              self.send_error(&sender, ErrorCode::ActionRejected, reason)?;
+              return Ok(None);
+            }
+            let countdown = self.state.settings.start_countdown;
+            if countdown == Duration::from_secs(0) {
+              ServerDecision::StartInfo(self.state.get_game_start_info())
+            } else {
+              ServerDecision::CountdownStarted(countdown)
+            }
+          }
+          // Recordings only have a decision if the game was started.
+          Playback(None) => return Ok(None),
+          Playback(Some(decision)) => decision.clone(),
         };
-        self.state.note_game_started(&start_info)?;
-        self.broadcast_snapshot()?;
-        for (uuid, player) in self.state.players.iter() {
-          self.broadcaster.send_to_player(
-            uuid,
-            &ServerToClientMessage::DisplayMessage(DisplayMessage {
-              message: Message::PlainString("The game has begun!".to_string()),
-              duration: Duration::from_secs(10),
-              delay_before_show: Duration::from_secs(0),
-            }),
-          )?;
-          if player.impostor {
-            self.broadcaster.send_to_player(
-              uuid,
-              &ServerToClientMessage::DisplayMessage(DisplayMessage {
-                message: Message::PlainString(
-                  "You are an evil impostor. Try to find crewmates alone and kill them! (press Q)"
-                    .to_string(),
-                ),
-                duration: Duration::from_secs(10),
-                delay_before_show: Duration::from_secs(3),
-              }),
-            )?;
-            self.broadcaster.send_to_player(
-              uuid,
-              &ServerToClientMessage::DisplayMessage(DisplayMessage {
-                message: Message::PlainString(
-                  "But don't get caught! The crew can vote you out!".to_string(),
-                ),
-                duration: Duration::from_secs(10),
-                delay_before_show: Duration::from_secs(6),
-              }),
-            )?;
-          } else {
-            self.broadcaster.send_to_player(
-              uuid,
-              &ServerToClientMessage::DisplayMessage(DisplayMessage {
-                message: Message::PlainString(
-                  "You are good crewmate. Find your tasks and complete them (press E)".to_string(),
-                ),
-                duration: Duration::from_secs(10),
-                delay_before_show: Duration::from_secs(3),
-              }),
-            )?;
-            let num_impostors = self
-              .state
-              .players
-              .iter()
-              .filter(|(_, p)| p.impostor)
-              .count();
-            self.broadcaster.send_to_player(
-              uuid,
-              &ServerToClientMessage::DisplayMessage(DisplayMessage {
-                message: Message::PlainString(format!("But beware, there's {} evil impostor{} on board. If you find a dead body, press R to report it", num_impostors, if num_impostors == 1 {""} else {"s"})),
-                duration: Duration::from_secs(10),
-                delay_before_show: Duration::from_secs(6),
-              }),
-            )?;
+        match &decision {
+          ServerDecision::StartInfo(start_info) => self.start_game(start_info)?,
+          ServerDecision::CountdownStarted(countdown) => {
+            self.state.start_countdown = Some(*countdown);
+            self.broadcast_snapshot()?;
           }
+          invalid => return Err(format!("Expected StartInfo or CountdownStarted when handling a recorded ClientToServerMessage::StartGame message, but got: {:?}", invalid).into()),
         }
-        return Ok(Some(ServerDecision::StartInfo(start_info)));
+        return Ok(Some(decision));
       }
       ClientToServerMessage::Killed(body) => {
+        let victim_was_alive = self
+          .state
+          .players
+          .values()
+          .any(|p| p.color == body.color && !p.dead);
+        if victim_was_alive {
+          self.stats.note_kill(sender);
+        }
         self.state.note_death(*body)?;
         self.broadcast_snapshot()?;
       }
@@ -261,6 +600,137 @@ impl GameServer {
           votes: BTreeMap::new(),
           time_remaining: self.state.settings.voting_time,
         }));
+        self.stats.note_meeting_called(sender, *dead_body_color);
+        self.broadcast_snapshot()?;
+      }
+      ClientToServerMessage::ReturnToLobby() => {
+        if !matches!(self.state.status, GameStatus::Won(_)) {
+          return Ok(None);
+        }
+        if self.state.host != Some(sender) {
+          self.send_error(
+            &sender,
+            ErrorCode::ActionRejected,
+            "Only the host can go back to the lobby".to_string(),
+          )?;
+          return Ok(None);
+        }
+        if let Some(recording) = self.get_recording() {
+          self.finished_rounds.push((self.round, recording));
+        }
+        self.state.return_to_lobby();
+        self.start_new_round();
+        self
+          .broadcaster
+          .broadcast(&ServerToClientMessage::DisplayMessage(DisplayMessage {
+            message: Message::PlainString("Back in the lobby, ready to play again!".to_string()),
+            duration: Duration::from_secs(10),
+            delay_before_show: Duration::from_secs(0),
+          }))?;
+        self.broadcast_snapshot()?;
+      }
+      ClientToServerMessage::UpdateSettings(settings) => {
+        if self.state.status != GameStatus::Lobby || self.state.host != Some(sender) {
+          return Ok(None);
+        }
+        if let Err(problem) = settings.validate() {
+          self.send_error(
+            &sender,
+            ErrorCode::ActionRejected,
+            format!("Those settings won't work: {}", problem),
+          )?;
+          return Ok(None);
+        }
+        self.state.settings = settings.clone();
+        self.cancel_countdown("the settings changed")?;
+        self.broadcast_snapshot()?;
+      }
+      ClientToServerMessage::Kick { target } | ClientToServerMessage::Ban { target } => {
+        if self.state.host != Some(sender) || *target == sender {
+          return Ok(None);
+        }
+        // Otherwise the host could throw out whoever they liked to decide the game.
+        if self.state.status != GameStatus::Lobby {
+          self.send_error(
+            &sender,
+            ErrorCode::ActionRejected,
+            "Players can only be kicked or banned in the lobby".to_string(),
+          )?;
+          return Ok(None);
+        }
+        let banning = matches!(message, ClientToServerMessage::Ban { .. });
+        if banning {
+          if let Some(token) = self.reconnect_tokens.get(target) {
+            self.banned_tokens.insert(*token);
+          }
+          // Their name may have had a number added to make it unique, which
+          // wouldn't stop them coming back as whoever they said they were.
+          let name = self
+            .requested_names
+            .get(target)
+            .or_else(|| self.state.players.get(target).map(|p| &p.name));
+          if let Some(name) = name {
+            self.banned_names.insert(normalize_name(name));
+          }
+        }
+        if banning {
+          self.send_error(
+            target,
+            ErrorCode::Banned,
+            "The host has banned you from this game".to_string(),
+          )?;
+        } else {
+          self.send_error(
+            target,
+            ErrorCode::Kicked,
+            "The host has kicked you out of this game".to_string(),
+          )?;
+        }
+        self.remove_player(
+          *target,
+          if banning {
+            " was banned by the host"
+          } else {
+            " was kicked out by the host"
+          },
+        )?;
+      }
+      ClientToServerMessage::ChangeColor { color } => {
+        if self.state.status != GameStatus::Lobby || !self.state.players.contains_key(&sender) {
+          return Ok(None);
+        }
+        let taken = self
+          .state
+          .players
+          .values()
+          .any(|p| p.color == *color && p.uuid != sender);
+        if taken {
+          self.send_error(
+            &sender,
+            ErrorCode::ActionRejected,
+            format!("{:?} is already taken", color),
+          )?;
+          return Ok(None);
+        }
+        if let Some(player) = self.state.players.get_mut(&sender) {
+          player.color = *color;
+        }
+        self.broadcast_snapshot()?;
+      }
+      ClientToServerMessage::SetReady { ready } => {
+        if self.state.status != GameStatus::Lobby {
+          return Ok(None);
+        }
+        let name = match self.state.players.get_mut(&sender) {
+          None => return Ok(None),
+          Some(player) => {
+            player.ready = *ready;
+            player.name.clone()
+          }
+        };
+        if !ready && self.state.settings.require_ready {
+          self.cancel_countdown(&format!("{} isn't ready", name))?;
+        }
         self.broadcast_snapshot()?;
       }
       ClientToServerMessage::FinishedTask(finished) => {
@@ -277,9 +747,17 @@ impl GameServer {
       ClientToServerMessage::Join {
         version,
         details: join,
+        reconnect_token,
       } => {
-        if version != &self.version {
-          // TODO: send an error and close the connection.
+        // A recorded join was already accepted when the game was played, whatever
+        // version we are now.
+        if matches!(prerecorded_decision, LiveGame) && version != &self.version {
+          self.send_error(
+            &sender,
+            ErrorCode::VersionMismatch,
+            "This game is running a different version of airlock.chat. Try reloading the page"
+              .to_string(),
+          )?;
           return Err(
             format!(
               "Bad version in client join, need {} but got {}",
@@ -288,6 +766,27 @@ impl GameServer {
             .into(),
           );
         }
+        if let Some(token) = reconnect_token {
+          self.reconnect_tokens.insert(sender, *token);
+        }
+        let refusal = match prerecorded_decision {
+          LiveGame => self.join_refusal(sender, join, *reconnect_token),
+          // Recordings don't know who was banned, only that someone was turned away.
+          Playback(Some(ServerDecision::JoinRefused)) => {
+            Some(self.join_refusal(sender, join, None).unwrap_or_else(|| {
+              (
+                ErrorCode::Banned,
+                "You've been banned from this game".to_string(),
+              )
+            }))
+          }
+          Playback(_) => None,
+        };
+        if let Some((code, message)) = refusal {
+          self.send_error(&sender, code, message)?;
+          self.reconnect_tokens.remove(&sender);
+          return Ok(Some(ServerDecision::JoinRefused));
+        }
         let mut decision = None;
         if self.state.status == GameStatus::Lobby {
           if let JoinRequest::JoinAsPlayer {
@@ -295,47 +794,49 @@ impl GameServer {
             preferred_color,
           } = join
           {
-            if self.state.players.get(&sender).is_some() {
+            if self.state.players.contains_key(&sender) {
               return Ok(None); // we know about this player already
             }
             // ok, it's a new player, and we have room for them. if their color is
             // already taken, give them a new one.
             let taken_colors: BTreeSet<Color> =
-              self.state.players.iter().map(|(_, p)| p.color).collect();
-            let add_player;
-            let mut color = preferred_color;
-            if taken_colors.contains(&color) {
-              match Color::all().iter().find(|c| !taken_colors.contains(c)) {
-                None => {
-                  add_player = false; // we can't add this player, all colors are taken!
-                }
-                Some(c) => {
-                  add_player = true;
-                  color = &*c;
-                }
-              }
+              self.state.players.values().map(|p| p.color).collect();
+            let color = if taken_colors.contains(preferred_color) {
+              Color::all().iter().find(|c| !taken_colors.contains(c))
             } else {
-              // player's preferred color wasn't taken, they're good to go!
-              add_player = true;
-            }
-            if add_player {
-              let position = match prerecorded_decision {
-                LiveGame => self.state.map.get_spawn_in_position(),
-                Playback(Some(ServerDecision::NewPlayerPosition(pos))) => *pos,
-                _ => {
-                  return Err(
-                    format!(
-                      "Internal error: bad recording. Expected NewPlayerPosition, got {:?}",
-                      prerecorded_decision
-                    )
-                    .into(),
+              Some(preferred_color)
+            };
+            let position = match prerecorded_decision {
+              LiveGame => Some(self.state.map.get_spawn_in_position()),
+              Playback(Some(ServerDecision::NewPlayerPosition(pos))) => Some(*pos),
+              // Older versions made players spectators, rather than turning them
+              // away, when every color was taken.
+              Playback(None) => None,
+              _ => {
+                return Err(
+                  format!(
+                    "Internal error: bad recording. Expected NewPlayerPosition, got {:?}",
+                    prerecorded_decision
                   )
-                }
-              };
+                  .into(),
+                )
+              }
+            };
+            if let (Some(color), Some(position)) = (color, position) {
               decision = Some(ServerDecision::NewPlayerPosition(position));
               // Add the new player (possibly with a new color)
-              let player = Player::new(sender, name.to_string(), *color, position);
+              // The same name twice would make it hard to tell who's who.
+              let requested_name = sanitize_name(name);
+              self.requested_names.insert(sender, requested_name.clone());
+              let name = unique_name(
+                requested_name,
+                self.state.players.values().map(|p| p.name.as_str()),
+              );
+              let player = Player::new(sender, name.clone(), *color, position);
               self.state.players.insert(sender, player);
+              if self.state.host.is_none() {
+                self.state.host = Some(sender);
+              }
               self
                 .broadcaster
                 .broadcast(&ServerToClientMessage::DisplayMessage(DisplayMessage {
@@ -352,6 +853,7 @@ impl GameServer {
                   duration: Duration::from_secs(10),
                   delay_before_show: Duration::from_secs(0),
                 }))?;
+              self.cancel_countdown(&format!("{} joined", name))?;
             }
           }
           // In all other cases, they're joining as a spectator.
@@ -366,7 +868,7 @@ impl GameServer {
         )?;
         self.broadcaster.send_to_player(
           &sender,
-          &&ServerToClientMessage::DisplayMessage(DisplayMessage {
+          &ServerToClientMessage::DisplayMessage(DisplayMessage {
             message: Message::PlainString("Welcome to airlock.chat!".to_string()),
             duration: Duration::from_secs(10),
             delay_before_show: Duration::from_secs(0),
@@ -389,27 +891,187 @@ impl GameServer {
             o.insert(*target);
           }
         }
+        self.stats.note_status(&self.state.status);
         self.broadcast_snapshot()?;
       }
     };
     Ok(None)
   }
 
+  // The recording of the game so far, if we're recording it.
+  pub fn get_recording(&self) -> Option<RecordedGame> {
+    self
+      .recording
+      .as_ref()
+      .map(|entries| RecordedGame::new(entries.clone(), self.winner()))
+  }
+
+  pub fn round(&self) -> u32 {
+    self.round
+  }
+
+  // The recordings of rounds that have finished since this was last called,
+  // with which round each was.
+  pub fn take_finished_rounds(&mut self) -> Vec<(u32, RecordedGame)> {
+    std::mem::take(&mut self.finished_rounds)
+  }
+
+  // Each round gets its own recording. So that it can be played back on its
+  // own, it starts with the players joining with the colors and positions they
+  // have now, and with any changes to the settings.
+  fn start_new_round(&mut self) {
+    self.round += 1;
+    self.tick = 0;
+    self.stats = StatsTracker::default();
+    self.announced_winner = None;
+    self.start_time = Instant::now();
+    let recording = match &mut self.recording {
+      None => return,
+      Some(r) => r,
+    };
+    recording.clear();
+    let mut messages = Vec::new();
+    // The host joins first, so that they're the host when played back too.
+    let host = self.state.host;
+    let mut players: Vec<&Player> = self.state.players.values().collect();
+    players.sort_by_key(|p| Some(p.uuid) != host);
+    for player in players {
+      messages.push(PlaybackMessage {
+        sender: player.uuid,
+        message: ClientToServerMessage::Join {
+          version: self.version.clone(),
+          details: JoinRequest::JoinAsPlayer {
+            preferred_color: player.color,
+            name: player.name.clone(),
+          },
+          reconnect_token: None,
+        },
+        decision: Some(ServerDecision::NewPlayerPosition(player.position)),
+      });
+    }
+    if let Some(host) = host {
+      if self.state.settings != Settings::default() {
+        messages.push(PlaybackMessage {
+          sender: host,
+          message: ClientToServerMessage::UpdateSettings(self.state.settings.clone()),
+          decision: None,
+        });
+      }
+    }
+    for message in messages {
+      self.record_event(&RecordingEvent::Message(message));
+    }
+  }
+
+  // Who won the current round, even if everyone has left since.
+  fn winner(&self) -> Option<Team> {
+    match self.state.status {
+      GameStatus::Won(team) => Some(team),
+      _ => self.announced_winner,
+    }
+  }
+
   pub fn get_uuid_for_new_connection(&self) -> UUID {
     UUID::random()
   }
 
+  // Players are only told where the people and bodies they can see are, so
+  // that a modified client can't show them the rest. Everyone else, like
+  // ghosts and spectators, gets the whole snapshot.
   pub fn broadcast_snapshot(&self) -> Result<(), Box<dyn Error>> {
+    let mut limited = BTreeSet::new();
+    for player in self.state.players.values() {
+      if let Some(snapshot) = self.snapshot_for(player) {
+        self
+          .broadcaster
+          .send_to_player(&player.uuid, &ServerToClientMessage::Snapshot(snapshot))?;
+        limited.insert(player.uuid);
+      }
+    }
     self
       .broadcaster
-      .broadcast(&ServerToClientMessage::Snapshot(Snapshot {
-        status: self.state.status.clone(),
-        bodies: self.state.bodies.clone(),
-        players: self.state.players.iter().map(|(_, p)| p.clone()).collect(),
-      }))?;
+      .broadcast_except(&ServerToClientMessage::Snapshot(self.snapshot()), &limited)?;
     Ok(())
   }
 
+  // The game as everyone in it sees it right now.
+  pub fn snapshot(&self) -> Snapshot {
+    Snapshot {
+      tick: self.tick,
+      status: self.state.status.clone(),
+      settings: self.state.settings.clone(),
+      host: self.state.host,
+      start_countdown: self.state.start_countdown,
+      bodies: self.state.bodies.clone(),
+      players: self.state.players.values().cloned().collect(),
+      hidden_players: BTreeSet::new(),
+    }
+  }
+
+  // The part of the game the given player can see, if they can't see all of
+  // it. Where people are only matters at night.
+  pub fn snapshot_for(&self, player: &Player) -> Option<Snapshot> {
+    if self.state.status != GameStatus::Playing(PlayState::Night) {
+      return None;
+    }
+    let area = player.visible_area(&self.state.settings, &self.state.status, &self.state.map)?;
+    let mut snapshot = self.snapshot();
+    snapshot.bodies.retain(|body| area.contains(&body.position));
+    let (players, hidden): (Vec<Player>, Vec<Player>) = snapshot
+      .players
+      .into_iter()
+      .partition(|p| p.uuid == player.uuid || area.contains(&p.position));
+    snapshot.players = players;
+    snapshot.hidden_players = hidden.iter().map(|p| p.uuid).collect();
+    Some(snapshot)
+  }
+
+  // Why the given connection can't join, if they can't.
+  fn join_refusal(
+    &self,
+    sender: UUID,
+    join: &JoinRequest,
+    reconnect_token: Option<UUID>,
+  ) -> Option<(ErrorCode, String)> {
+    let banned = || {
+      (
+        ErrorCode::Banned,
+        "You've been banned from this game".to_string(),
+      )
+    };
+    let banned_token = reconnect_token
+      .map(|t| self.banned_tokens.contains(&t))
+      .unwrap_or(false);
+    let name = match join {
+      JoinRequest::JoinAsPlayer { name, .. } => sanitize_name(name),
+      JoinRequest::JoinAsSpectator if banned_token => return Some(banned()),
+      JoinRequest::JoinAsSpectator => return None,
+    };
+    if banned_token || self.banned_names.contains(&normalize_name(&name)) {
+      return Some(banned());
+    }
+    // Once the game has started, new players can only watch, whatever their name.
+    let joining_lobby =
+      self.state.status == GameStatus::Lobby && !self.state.players.contains_key(&sender);
+    if !joining_lobby {
+      return None;
+    }
+    if let Some(problem) = name_problem(&name) {
+      return Some((ErrorCode::InvalidName, problem));
+    }
+    let max_players = self.state.settings.max_players.min(Color::all().len());
+    if self.state.players.len() >= max_players {
+      return Some((
+        ErrorCode::RoomFull,
+        format!(
+          "Sorry, this game is full. It has room for {} players",
+          max_players
+        ),
+      ));
+    }
+    None
+  }
+
   fn eligable_target(&self, target: VoteTarget) -> bool {
     match target {
       VoteTarget::Skip => {
@@ -428,6 +1090,7 @@ impl GameServer {
       Some(r) => r,
     };
     recording.push(RecordingEntry {
+      tick: self.tick,
       since_start: self.start_time.elapsed(),
       event: event.clone(),
     });
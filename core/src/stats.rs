@@ -0,0 +1,123 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// What happened over the course of a game, sent to everyone once it's over.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GameStats {
+  pub winner: Team,
+  pub players: Vec<PlayerStats>,
+  pub meetings: Vec<MeetingStats>,
+}
+
+impl GameStats {
+  pub fn impostors(&self) -> impl Iterator<Item = &PlayerStats> {
+    self.players.iter().filter(|p| p.impostor)
+  }
+
+  pub fn player(&self, uuid: UUID) -> Option<&PlayerStats> {
+    self.players.iter().find(|p| p.uuid == uuid)
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerStats {
+  pub uuid: UUID,
+  pub name: String,
+  pub color: Color,
+  pub impostor: bool,
+  pub dead: bool,
+  pub disconnected: bool,
+  pub kills: usize,
+  pub tasks_finished: usize,
+  pub tasks_total: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MeetingStats {
+  pub called_by: UUID,
+  pub body: Color,
+  // Who each player voted for. Players who didn't vote are missing.
+  pub votes: BTreeMap<UUID, VoteTarget>,
+  // None if the game ended before the votes were counted.
+  pub outcome: Option<VoteOutcome>,
+}
+
+// Keeps track of the things that happen during a game that we can't work out
+// from the game state at the end.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct StatsTracker {
+  kills: BTreeMap<UUID, usize>,
+  meetings: Vec<MeetingStats>,
+  // Players who left partway through, as they were when they left.
+  disconnected: Vec<Player>,
+}
+
+impl StatsTracker {
+  pub fn note_kill(&mut self, killer: UUID) {
+    *self.kills.entry(killer).or_insert(0) += 1;
+  }
+
+  pub fn note_meeting_called(&mut self, called_by: UUID, body: Color) {
+    self.meetings.push(MeetingStats {
+      called_by,
+      body,
+      votes: BTreeMap::new(),
+      outcome: None,
+    });
+  }
+
+  pub fn note_disconnection(&mut self, player: &Player) {
+    self.disconnected.push(player.clone());
+  }
+
+  // Called whenever the game status may have changed, to pick up the
+  // results of meetings as they happen.
+  pub fn note_status(&mut self, status: &GameStatus) {
+    let meeting = match self.meetings.last_mut() {
+      Some(meeting) => meeting,
+      None => return,
+    };
+    match status {
+      GameStatus::Playing(PlayState::Voting(voting)) => {
+        meeting.votes = voting.votes.clone();
+      }
+      GameStatus::Playing(PlayState::TallyingVotes(tally)) => {
+        meeting.votes = tally
+          .votes_against
+          .iter()
+          .flat_map(|(target, voters)| voters.iter().map(move |voter| (*voter, *target)))
+          .collect();
+      }
+      GameStatus::Playing(PlayState::ViewingOutcome(viewing)) => {
+        meeting.outcome = Some(viewing.outcome);
+      }
+      _ => (),
+    }
+  }
+
+  pub fn finish(&self, winner: Team, state: &GameState) -> GameStats {
+    let players = state
+      .players
+      .values()
+      .map(|p| (p, false))
+      .chain(self.disconnected.iter().map(|p| (p, true)))
+      .map(|(player, disconnected)| PlayerStats {
+        uuid: player.uuid,
+        name: player.name.clone(),
+        color: player.color,
+        impostor: player.impostor,
+        dead: player.dead,
+        disconnected,
+        kills: self.kills.get(&player.uuid).copied().unwrap_or(0),
+        tasks_finished: player.tasks.iter().filter(|t| t.finished).count(),
+        tasks_total: player.tasks.len(),
+      })
+      .collect();
+    GameStats {
+      winner,
+      players,
+      meetings: self.meetings.clone(),
+    }
+  }
+}
@@ -1,6 +1,7 @@
 #![cfg(test)]
 use crate::*;
 use core::time::Duration;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::error::Error;
 use std::sync::Arc;
@@ -16,20 +17,24 @@ fn pythagoras_was_right() {
 struct TestEnvironment {
   game_server: GameServer,
   server_to_client_queue: Arc<Mutex<HashMap<UUID, Vec<ServerToClientMessage>>>>,
+  disconnected_by_server: Arc<Mutex<BTreeSet<UUID>>>,
   players: HashMap<UUID, GameAsPlayer>,
   player_queue: HashMap<UUID, Arc<Mutex<Vec<ClientToServerMessage>>>>,
 }
 impl TestEnvironment {
   fn new() -> TestEnvironment {
     let messages: Arc<Mutex<HashMap<UUID, Vec<ServerToClientMessage>>>> = Arc::default();
+    let disconnected: Arc<Mutex<BTreeSet<UUID>>> = Arc::default();
     TestEnvironment {
       game_server: GameServer::new(
         Box::new(TestBroadcaster {
           players: messages.clone(),
+          disconnected: disconnected.clone(),
         }),
         true,
       ),
       server_to_client_queue: messages,
+      disconnected_by_server: disconnected,
       players: HashMap::default(),
       player_queue: HashMap::default(),
     }
@@ -112,13 +117,23 @@ impl TestEnvironment {
         }
         keep_going = true;
         let messages_local = messages.clone();
-        for message in messages_local {
-          console_log!("Passing {} from server to {}", message.kind(), uuid);
-          let player = self.players.get_mut(uuid).unwrap();
-          player.handle_msg(message)?;
+        if let Some(player) = self.players.get_mut(uuid) {
+          for message in messages_local {
+            console_log!("Passing {} from server to {}", message.kind(), uuid);
+            player.handle_msg(message)?;
+          }
         }
         messages.clear();
       }
+      // Once they've got everything the server sent them, close the connections
+      // that the server asked to.
+      for uuid in std::mem::take(&mut *self.disconnected_by_server.lock().unwrap()) {
+        queue.remove(&uuid);
+        self.player_queue.remove(&uuid);
+        if let Some(player) = self.players.get_mut(&uuid) {
+          player.disconnected()?;
+        }
+      }
       console_log!(
         "Finished one loop of dispatch_messages. Keep going? {}",
         keep_going
@@ -133,48 +148,114 @@ impl TestEnvironment {
   ) -> Result<GameState, Box<dyn Error>> {
     let server_state = self.game_server.state.clone();
     assert_eq!(self.players.len(), expected_num_players);
-    for (_, game_as_player) in self.players.iter() {
-      // Will eventually need to customize this more, because players will
-      // only know a subset.
-      assert_eq!(&server_state, &game_as_player.state);
+    for (uuid, game_as_player) in self.players.iter() {
+      // Players only know about the part of the game they can see, so leave
+      // out the rest.
+      let mut expected = server_state.clone();
+      let mut known = game_as_player.state.clone();
+      if let Some(snapshot) = expected
+        .players
+        .get(uuid)
+        .and_then(|player| self.game_server.snapshot_for(player))
+      {
+        expected.bodies = snapshot.bodies;
+      }
+      for hidden in game_as_player.hidden_players.iter() {
+        expected.players.remove(hidden);
+        known.players.remove(hidden);
+      }
+      assert_eq!(&expected, &known);
     }
     Ok(server_state)
   }
 
-  fn time_passes(&mut self, elapsed: Duration) {
-    self.game_server.state.simulate(elapsed);
-    for (_, player) in self.players.iter_mut() {
-      player.state.simulate(elapsed);
+  // Simulates whole ticks, at least as many as it takes for elapsed to pass.
+  fn time_passes(&mut self, elapsed: Duration) -> Result<(), Box<dyn Error>> {
+    let ticks = elapsed.as_nanos().div_ceil(TICK.as_nanos());
+    for _ in 0..ticks {
+      self.game_server.simulate_tick()?;
+      for (_, player) in self.players.iter_mut() {
+        player.simulate(TICK);
+      }
     }
+    Ok(())
   }
 
   fn create_and_connect_player(&mut self) -> Result<UUID, Box<dyn Error>> {
     let player_count = self.players.len();
+    let id = self.create_and_join("Test Player", None)?;
+    self.expect_everyone_agrees_on_game_state(player_count + 1)?;
+    Ok(id)
+  }
+
+  // Creates a player who asks to join with the given name and reconnect token.
+  fn create_and_join(
+    &mut self,
+    name: &str,
+    reconnect_token: Option<UUID>,
+  ) -> Result<UUID, Box<dyn Error>> {
     let id = self.create_player();
     self.game_server.handle_message(
       id,
       ClientToServerMessage::Join {
         version: get_version_sha().to_string(),
         details: JoinRequest::JoinAsPlayer {
-          name: "Test Player".to_string(),
+          name: name.to_string(),
           preferred_color: Color::random(),
         },
+        reconnect_token,
       },
     )?;
     self.dispatch_messages()?;
-    self.expect_everyone_agrees_on_game_state(player_count + 1)?;
     Ok(id)
   }
+
+  // Forgets about a player the server has disconnected.
+  fn forget_player(&mut self, uuid: UUID) {
+    self.players.remove(&uuid);
+    self.player_queue.remove(&uuid);
+  }
+
+  fn press(&mut self, uuid: UUID, input: InputState) -> Result<(), Box<dyn Error>> {
+    let player = self.players.get_mut(&uuid).unwrap();
+    player.take_input(input)?;
+    player.take_input(InputState::default())?;
+    self.dispatch_messages()
+  }
+
+  // The host starts the game, and we wait for the countdown to run out.
+  fn start_game(&mut self) -> Result<(), Box<dyn Error>> {
+    let host = self.game_server.state.host.unwrap();
+    self.press(
+      host,
+      InputState {
+        play: true,
+        ..InputState::default()
+      },
+    )?;
+    self.time_passes(self.game_server.state.settings.start_countdown)?;
+    self.dispatch_messages()
+  }
 }
 
 struct TestBroadcaster {
   players: Arc<Mutex<HashMap<UUID, Vec<ServerToClientMessage>>>>,
+  // Connections the server has closed, but which haven't been told yet.
+  disconnected: Arc<Mutex<BTreeSet<UUID>>>,
 }
 impl Broadcaster for TestBroadcaster {
-  fn broadcast(&self, message: &ServerToClientMessage) -> Result<(), Box<dyn Error>> {
+  fn broadcast_except(
+    &self,
+    message: &ServerToClientMessage,
+    except: &BTreeSet<UUID>,
+  ) -> Result<(), Box<dyn Error>> {
     console_log!("Broadcasting {} from server", message.kind());
     let mut players = self.players.lock().unwrap();
+    let disconnected = self.disconnected.lock().unwrap();
     for (uuid, messages) in players.iter_mut() {
+      if disconnected.contains(uuid) || except.contains(uuid) {
+        continue;
+      }
       console_log!("Sending to {}", uuid);
       messages.push(message.clone());
     }
@@ -187,12 +268,20 @@ impl Broadcaster for TestBroadcaster {
     message: &ServerToClientMessage,
   ) -> Result<(), Box<dyn Error>> {
     console_log!("Sending {} to player {} from server", message.kind(), uuid);
+    if self.disconnected.lock().unwrap().contains(uuid) {
+      return Ok(());
+    }
     let mut players = self.players.lock().unwrap();
     if let Some(messages) = players.get_mut(uuid) {
       messages.push(message.clone());
     }
     Ok(())
   }
+  fn disconnect_player(&self, uuid: &UUID) -> Result<(), Box<dyn Error>> {
+    console_log!("Disconnecting player {} from server", uuid);
+    self.disconnected.lock().unwrap().insert(*uuid);
+    Ok(())
+  }
 }
 
 #[derive(Default, Clone)]
@@ -222,11 +311,11 @@ fn test_connection_and_disconnection() -> Result<(), Box<dyn Error>> {
 
   // P2 disconnects
   let game = env.expect_everyone_agrees_on_game_state(3)?;
-  assert!(game.players.get(&player2_id).is_some());
+  assert!(game.players.contains_key(&player2_id));
   env.remove_player(player2_id)?;
   env.dispatch_messages()?;
   let game = env.expect_everyone_agrees_on_game_state(2)?;
-  assert!(game.players.get(&player2_id).is_none());
+  assert!(!game.players.contains_key(&player2_id));
 
   Ok(())
 }
@@ -243,8 +332,7 @@ fn test_movement() -> Result<(), Box<dyn Error>> {
   // Move p1 up and left
   let p1_client = env.players.get_mut(&player1_id).unwrap();
   p1_client.take_input(InputState {
-    up: true,
-    left: true,
+    direction: Velocity { dx: -1.0, dy: -1.0 },
     ..InputState::default()
   })?;
   let p1_position = p1_client.local_player().unwrap().position;
@@ -261,15 +349,14 @@ fn test_movement() -> Result<(), Box<dyn Error>> {
   // Move p3 down and right
   let p3_client = env.players.get_mut(&player3_id).unwrap();
   p3_client.take_input(InputState {
-    right: true,
-    down: true,
+    direction: Velocity { dx: 1.0, dy: 1.0 },
     ..InputState::default()
   })?;
   let p3_position = p3_client.local_player().unwrap().position;
 
   // Let four time ticks pass
   env.dispatch_messages()?;
-  env.time_passes(Duration::from_millis(64));
+  env.time_passes(Duration::from_millis(64))?;
 
   let player_positions: HashMap<UUID, Position> = env
     .game_server
@@ -295,3 +382,1123 @@ fn test_movement() -> Result<(), Box<dyn Error>> {
 
   Ok(())
 }
+
+#[test]
+fn test_analog_movement() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let player = env.create_and_connect_player()?;
+  let speed = env.game_server.state.settings.speed;
+  let mut push = |dx: f64, dy: f64| -> Result<Velocity, Box<dyn Error>> {
+    let client = env.players.get_mut(&player).unwrap();
+    client.take_input(InputState {
+      direction: Velocity { dx, dy },
+      ..InputState::default()
+    })?;
+    Ok(client.local_player().unwrap().velocity)
+  };
+
+  // A stick pushed halfway goes half as fast.
+  assert_eq!(
+    push(0.5, 0.0)?,
+    Velocity {
+      dx: speed / 2.0,
+      dy: 0.0
+    }
+  );
+  // A slight wobble doesn't change the speed.
+  assert_eq!(
+    push(0.51, 0.01)?,
+    Velocity {
+      dx: speed / 2.0,
+      dy: 0.0
+    }
+  );
+  // Pushing too far is no faster than the arrow keys, and nonsense is ignored.
+  assert_eq!(
+    push(-3.0, 1.0)?,
+    Velocity {
+      dx: -speed,
+      dy: speed
+    }
+  );
+  assert_eq!(push(f64::NAN, f64::INFINITY)?, Velocity::default());
+  Ok(())
+}
+
+#[test]
+fn test_replay_from_player_perspective() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  env.create_and_connect_player()?;
+  let player2_id = env.create_and_connect_player()?;
+  env.create_and_connect_player()?;
+  env.create_and_connect_player()?;
+
+  // P1, the host, starts the game
+  env.start_game()?;
+
+  let recording = env.game_server.get_recording().unwrap();
+  let mut playback = PlaybackServer::new(recording)?;
+  let mut viewer = GameAsPlayer::new(UUID::random(), Box::new(PlaybackTx {}));
+  viewer.state.status = GameStatus::Lobby;
+  let end = playback.duration() + Duration::from_millis(16);
+  playback.skip_to(end, &mut viewer)?;
+
+  // By default we watch as a spectator, who sees everything but doesn't get
+  // any player's private messages.
+  let got_private_message = |viewer: &GameAsPlayer| {
+    viewer
+      .displayed_messages
+      .iter()
+      .any(|m| m.message == Message::PlainString("The game has begun!".to_string()))
+  };
+  assert_eq!(playback.perspective(), None);
+  assert!(viewer.local_player().is_none());
+  assert_eq!(viewer.vision(), None);
+  assert!(!got_private_message(&viewer));
+
+  // Switching to P2 shows us what they saw.
+  playback.set_perspective(Some(player2_id), &mut viewer)?;
+  assert_eq!(viewer.my_uuid, player2_id);
+  let expected_tasks = &env
+    .game_server
+    .state
+    .players
+    .get(&player2_id)
+    .unwrap()
+    .tasks;
+  assert_eq!(&viewer.local_player().unwrap().tasks, expected_tasks);
+  assert!(viewer.vision().is_some());
+  assert!(got_private_message(&viewer));
+
+  // Cycling goes through every player in turn, and then back to the
+  // spectator's view.
+  playback.set_perspective(None, &mut viewer)?;
+  let players: Vec<UUID> = env.game_server.state.players.keys().copied().collect();
+  assert_eq!(players.len(), 4);
+  let mut seen = vec![];
+  for _ in 0..=players.len() {
+    playback.cycle_perspective(&mut viewer)?;
+    seen.push(playback.perspective());
+  }
+  let expected: Vec<Option<UUID>> = players.into_iter().map(Some).chain([None]).collect();
+  assert_eq!(seen, expected);
+  assert!(viewer.local_player().is_none());
+  assert!(!got_private_message(&viewer));
+
+  Ok(())
+}
+
+#[test]
+fn test_play_back_recording_from_format_v1() -> Result<(), Box<dyn Error>> {
+  // Recorded before the format was versioned, when it was pinned to a git SHA.
+  let recording: RecordedGame =
+    serde_json::from_str(include_str!("../fixtures/recording_v1.json"))?;
+  assert_eq!(recording.format_version, RecordingFormatVersion::CURRENT);
+  assert!(recording.format_version.can_play_back());
+  assert_eq!(recording.entries.len(), 6);
+  assert_eq!(recording.winner, None);
+  assert!(recording.entries.windows(2).all(|w| w[0].tick <= w[1].tick));
+
+  let mut playback = PlaybackServer::new(recording)?;
+  let mut viewer = GameAsPlayer::new(UUID::random(), Box::new(PlaybackTx {}));
+  viewer.state.status = GameStatus::Lobby;
+  let end = playback.duration() + Duration::from_millis(16);
+  playback.skip_to(end, &mut viewer)?;
+  assert_eq!(viewer.state.players.len(), 3);
+  assert_eq!(viewer.state.status, GameStatus::Won(Team::Impostors));
+
+  Ok(())
+}
+
+#[test]
+fn test_playback_lines_up_tick_for_tick() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  for _ in 0..4 {
+    env.create_and_connect_player()?;
+  }
+  env.start_game()?;
+  let mover = *env.players.keys().next().unwrap();
+  let walk = |env: &mut TestEnvironment, input: InputState| -> Result<(), Box<dyn Error>> {
+    env.players.get_mut(&mover).unwrap().take_input(input)?;
+    env.dispatch_messages()
+  };
+  walk(
+    &mut env,
+    InputState {
+      direction: Velocity { dx: 1.0, dy: 0.0 },
+      ..InputState::default()
+    },
+  )?;
+  env.time_passes(Duration::from_millis(100))?;
+  walk(
+    &mut env,
+    InputState {
+      direction: Velocity { dx: 0.0, dy: 1.0 },
+      ..InputState::default()
+    },
+  )?;
+  env.time_passes(Duration::from_millis(50))?;
+  walk(&mut env, InputState::default())?;
+  env.time_passes(Duration::from_millis(30))?;
+  // 7 ticks make up the first 100ms, 4 the next 50ms, then 2 more.
+  let start_tick = env.game_server.tick - 13;
+
+  // Snapshots say which tick they were taken on.
+  env.game_server.broadcast_snapshot()?;
+  env.dispatch_messages()?;
+  assert_eq!(env.players[&mover].server_tick, env.game_server.tick);
+
+  let recording = env.game_server.get_recording().unwrap();
+  let moves: Vec<u64> = recording
+    .entries
+    .iter()
+    .filter(|e| match &e.event {
+      RecordingEvent::Message(m) => matches!(m.message, ClientToServerMessage::Move(_)),
+      _ => false,
+    })
+    .map(|e| e.tick)
+    .collect();
+  assert_eq!(moves, vec![start_tick, start_tick + 7, start_tick + 11]);
+
+  // However long the playback's frames are, it ends up in the same place.
+  let mut playback = PlaybackServer::new(recording)?;
+  let mut viewer = GameAsPlayer::new(UUID::random(), Box::new(PlaybackTx {}));
+  viewer.state.status = GameStatus::Lobby;
+  let end = TICK * env.game_server.tick as u32;
+  let frame = Duration::from_millis(37);
+  while playback.current_time() + frame < end {
+    playback.simulate(frame, &mut viewer, false)?;
+  }
+  playback.simulate(end - playback.current_time(), &mut viewer, false)?;
+  assert_eq!(playback.current_time(), end);
+  assert_eq!(playback.game_state().players, env.game_server.state.players);
+
+  Ok(())
+}
+
+#[test]
+fn test_each_rounds_recording_starts_at_tick_zero() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  for _ in 0..4 {
+    env.create_and_connect_player()?;
+  }
+  vote_out_the_impostor(&mut env)?;
+  env.time_passes(Duration::from_secs(1))?;
+  assert!(env.game_server.tick > 60);
+
+  let host = env.game_server.state.host.unwrap();
+  env.press(
+    host,
+    InputState {
+      play: true,
+      ..InputState::default()
+    },
+  )?;
+  assert_eq!(env.game_server.state.status, GameStatus::Lobby);
+  assert_eq!(env.game_server.tick, 0);
+  env.time_passes(Duration::from_millis(100))?;
+  let mover = *env.players.keys().next().unwrap();
+  env
+    .players
+    .get_mut(&mover)
+    .unwrap()
+    .take_input(InputState {
+      direction: Velocity { dx: 1.0, dy: 0.0 },
+      ..InputState::default()
+    })?;
+  env.dispatch_messages()?;
+
+  let recording = env.game_server.get_recording().unwrap();
+  assert_eq!(recording.entries.first().unwrap().tick, 0);
+  assert_eq!(recording.entries.last().unwrap().tick, 7);
+  let playback = PlaybackServer::new(recording)?;
+  assert_eq!(playback.duration(), TICK * 7);
+
+  Ok(())
+}
+
+#[test]
+fn test_recording_from_newer_format_is_rejected() {
+  let current = RecordingFormatVersion::CURRENT;
+  let newer_versions = [
+    RecordingFormatVersion {
+      major: current.major + 1,
+      minor: 0,
+    },
+    RecordingFormatVersion {
+      major: current.major,
+      minor: current.minor + 1,
+    },
+  ];
+  for newer in newer_versions {
+    let encoded = format!(
+      r#"{{"format_version": {{"major": {}, "minor": {}}}, "version": "abc", "entries": []}}"#,
+      newer.major, newer.minor
+    );
+    let error = serde_json::from_str::<RecordedGame>(&encoded).unwrap_err();
+    assert!(
+      format!("{}", error).contains("newer than this version"),
+      "{}: {}",
+      newer,
+      error
+    );
+
+    let mut recording = RecordedGame::new(vec![], None);
+    recording.format_version = newer;
+    assert!(!newer.can_play_back());
+    assert!(PlaybackServer::new(recording).is_err());
+  }
+}
+
+// Starts a game, and plays it out with the impostor killing someone and then
+// being voted out. Returns the impostor, their victim, and who reported the body.
+fn vote_out_the_impostor(
+  env: &mut TestEnvironment,
+) -> Result<(UUID, Player, UUID), Box<dyn Error>> {
+  env.start_game()?;
+
+  let state = env.game_server.state.clone();
+  let impostor = state.players.values().find(|p| p.impostor).unwrap().uuid;
+  let mut crew = state.players.values().filter(|p| !p.impostor);
+  let victim = crew.next().unwrap().clone();
+  let reporter = crew.next().unwrap().uuid;
+  let crew_ids: Vec<UUID> = crew.map(|p| p.uuid).collect();
+
+  // The impostor kills someone, right next to the reporter.
+  let body = DeadBody {
+    color: victim.color,
+    position: state.players[&reporter].position,
+  };
+  env
+    .game_server
+    .handle_message(impostor, ClientToServerMessage::Killed(body))?;
+  env.game_server.handle_message(
+    reporter,
+    ClientToServerMessage::ReportBody {
+      dead_body_color: victim.color,
+    },
+  )?;
+  // Everyone votes for the impostor, who votes to skip.
+  for voter in crew_ids.iter().chain(Some(&reporter)) {
+    env.game_server.handle_message(
+      *voter,
+      ClientToServerMessage::Vote {
+        target: VoteTarget::Player { uuid: impostor },
+      },
+    )?;
+  }
+  env.game_server.handle_message(
+    impostor,
+    ClientToServerMessage::Vote {
+      target: VoteTarget::Skip,
+    },
+  )?;
+  env.dispatch_messages()?;
+  // Count the votes, then show the outcome, then the crew wins.
+  env.time_passes(Duration::from_millis(16))?;
+  env.time_passes(Duration::from_secs(10))?;
+  env.time_passes(Duration::from_secs(7))?;
+  env.dispatch_messages()?;
+  env.expect_everyone_agrees_on_game_state(4)?;
+  assert_eq!(env.game_server.state.status, GameStatus::Won(Team::Crew));
+  Ok((impostor, victim, reporter))
+}
+
+#[test]
+fn test_post_game_stats() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  for _ in 0..4 {
+    env.create_and_connect_player()?;
+  }
+  let (impostor, victim, reporter) = vote_out_the_impostor(&mut env)?;
+
+  for player in env.players.values() {
+    let stats = player.game_stats.as_ref().unwrap();
+    assert_eq!(stats.winner, Team::Crew);
+    let impostors: Vec<UUID> = stats.impostors().map(|p| p.uuid).collect();
+    assert_eq!(impostors, vec![impostor]);
+    assert_eq!(stats.player(impostor).unwrap().kills, 1);
+    assert!(stats.player(victim.uuid).unwrap().dead);
+    assert_eq!(stats.player(reporter).unwrap().tasks_finished, 0);
+    assert_eq!(stats.player(reporter).unwrap().tasks_total, 6);
+
+    assert_eq!(stats.meetings.len(), 1);
+    let meeting = &stats.meetings[0];
+    assert_eq!(meeting.called_by, reporter);
+    assert_eq!(meeting.body, victim.color);
+    assert_eq!(meeting.votes.len(), 3);
+    assert_eq!(
+      meeting.votes[&reporter],
+      VoteTarget::Player { uuid: impostor }
+    );
+    assert_eq!(meeting.votes[&impostor], VoteTarget::Skip);
+    assert_eq!(
+      meeting.outcome,
+      Some(VoteOutcome::Player { uuid: impostor })
+    );
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_play_again() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  for _ in 0..4 {
+    env.create_and_connect_player()?;
+  }
+  let settings = Settings {
+    num_tasks: 3,
+    ..Settings::default()
+  };
+  let host = env.game_server.state.host.unwrap();
+  env
+    .players
+    .get_mut(&host)
+    .unwrap()
+    .update_settings(settings.clone())?;
+  env.dispatch_messages()?;
+  let before_first_game = env.expect_everyone_agrees_on_game_state(4)?;
+  assert_eq!(before_first_game.settings, settings);
+
+  vote_out_the_impostor(&mut env)?;
+  // Only the host can take everyone back to the lobby, and only they are
+  // asked to.
+  let was_told = |env: &TestEnvironment, uuid: &UUID, text: &str| {
+    env.players[uuid]
+      .displayed_messages
+      .iter()
+      .any(|m| m.message == Message::PlainString(text.to_string()))
+  };
+  for uuid in env.players.keys() {
+    let is_host = *uuid == host;
+    assert_eq!(was_told(&env, uuid, "Press P to play again"), is_host);
+    assert_eq!(
+      was_told(&env, uuid, "Waiting for the host to start another game"),
+      !is_host
+    );
+  }
+  let play = InputState {
+    play: true,
+    ..InputState::default()
+  };
+  let not_host = *env.players.keys().find(|uuid| **uuid != host).unwrap();
+  env.press(not_host, play)?;
+  assert!(matches!(env.game_server.state.status, GameStatus::Won(_)));
+  env.press(host, play)?;
+
+  let lobby = env.expect_everyone_agrees_on_game_state(4)?;
+  assert_eq!(lobby.status, GameStatus::Lobby);
+  assert_eq!(lobby.settings, settings);
+  assert!(lobby.bodies.is_empty());
+  for (uuid, player) in lobby.players.iter() {
+    let before = &before_first_game.players[uuid];
+    assert_eq!(player.name, before.name);
+    assert_eq!(player.color, before.color);
+    assert!(!player.dead && !player.impostor && player.tasks.is_empty());
+  }
+  assert!(env.players.values().all(|p| p.game_stats.is_none()));
+
+  // The second round is recorded separately, and plays back on its own.
+  vote_out_the_impostor(&mut env)?;
+  let recording = env.game_server.get_recording().unwrap();
+  assert_eq!(recording.winner, Some(Team::Crew));
+  let mut playback = PlaybackServer::new(recording)?;
+  let mut viewer = GameAsPlayer::new(UUID::random(), Box::new(PlaybackTx {}));
+  viewer.state.status = GameStatus::Lobby;
+  let end = playback.duration() + Duration::from_secs(20);
+  playback.skip_to(end, &mut viewer)?;
+  assert_eq!(viewer.state.status, GameStatus::Won(Team::Crew));
+  assert_eq!(viewer.state.settings, settings);
+  assert_eq!(viewer.state.players.len(), 4);
+  assert!(viewer.state.players.values().all(|p| p.tasks.len() == 3));
+
+  Ok(())
+}
+
+#[test]
+fn test_host_kick_and_ban() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let host = env.create_and_connect_player()?;
+  let player2 = env.create_and_connect_player()?;
+  let player3 = env.create_and_connect_player()?;
+  let token = UUID::random();
+  let griefer = env.create_and_join("Griefer", Some(token))?;
+  let state = env.expect_everyone_agrees_on_game_state(4)?;
+  assert_eq!(state.host, Some(host));
+
+  // Only the host can start the game.
+  let start = InputState {
+    play: true,
+    ..InputState::default()
+  };
+  env.press(player2, start)?;
+  assert_eq!(env.game_server.state.status, GameStatus::Lobby);
+
+  // The host kicks player 3, who can come back.
+  env
+    .game_server
+    .handle_message(host, ClientToServerMessage::Kick { target: player3 })?;
+  env.forget_player(player3);
+  env.dispatch_messages()?;
+  env.expect_everyone_agrees_on_game_state(3)?;
+  let returned = env.create_and_connect_player()?;
+
+  // Players other than the host can't kick anyone.
+  env
+    .game_server
+    .handle_message(player2, ClientToServerMessage::Ban { target: griefer })?;
+  env.dispatch_messages()?;
+  env.expect_everyone_agrees_on_game_state(4)?;
+
+  // A banned player can't come back, with the same token or the same name.
+  env
+    .game_server
+    .handle_message(host, ClientToServerMessage::Ban { target: griefer })?;
+  env.forget_player(griefer);
+  env.dispatch_messages()?;
+  env.expect_everyone_agrees_on_game_state(3)?;
+  let same_token = env.create_and_join("Innocent Bystander", Some(token))?;
+  assert!(!env.game_server.state.players.contains_key(&same_token));
+  env.forget_player(same_token);
+  let same_name = env.create_and_join("  griefer ", None)?;
+  assert!(!env.game_server.state.players.contains_key(&same_name));
+  env.forget_player(same_name);
+  env.expect_everyone_agrees_on_game_state(3)?;
+
+  // When the host leaves, someone else takes over.
+  env.remove_player(host)?;
+  env.dispatch_messages()?;
+  let state = env.expect_everyone_agrees_on_game_state(2)?;
+  let new_host = state.host.unwrap();
+  assert!(new_host == player2 || new_host == returned);
+  env.create_and_connect_player()?;
+  env.create_and_connect_player()?;
+  env.start_game()?;
+  assert_eq!(
+    env.game_server.state.status,
+    GameStatus::Playing(PlayState::Night)
+  );
+
+  // Once the game has started, the host can't throw anyone out.
+  let target = *env
+    .game_server
+    .state
+    .players
+    .keys()
+    .find(|uuid| **uuid != new_host)
+    .unwrap();
+  for message in [
+    ClientToServerMessage::Kick { target },
+    ClientToServerMessage::Ban { target },
+  ] {
+    env.game_server.handle_message(new_host, message)?;
+    env.dispatch_messages()?;
+    assert!(env.game_server.state.players.contains_key(&target));
+    assert_eq!(env.players[&target].fatal_error, None);
+    assert_eq!(
+      env.players[&new_host]
+        .displayed_messages
+        .last()
+        .unwrap()
+        .message,
+      Message::PlainString("Players can only be kicked or banned in the lobby".to_string())
+    );
+  }
+  env.expect_everyone_agrees_on_game_state(4)?;
+
+  // The recording doesn't give away anyone's token, and plays back the same way.
+  let recording = env.game_server.get_recording().unwrap();
+  assert!(!serde_json::to_string(&recording)?.contains(&token.to_string()));
+  let mut playback = PlaybackServer::new(recording)?;
+  let mut viewer = GameAsPlayer::new(UUID::random(), Box::new(PlaybackTx {}));
+  viewer.state.status = GameStatus::Lobby;
+  let end = playback.duration() + Duration::from_millis(16);
+  playback.skip_to(end, &mut viewer)?;
+  assert_eq!(playback.game_state().host, Some(new_host));
+  assert_eq!(
+    playback.game_state().players.keys().collect::<Vec<_>>(),
+    env.game_server.state.players.keys().collect::<Vec<_>>()
+  );
+
+  Ok(())
+}
+
+#[test]
+fn test_kicked_players_connection_closing() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let host = env.create_and_connect_player()?;
+  for _ in 0..4 {
+    env.create_and_connect_player()?;
+  }
+  let kicked = *env.players.keys().find(|uuid| **uuid != host).unwrap();
+  let start = InputState {
+    play: true,
+    ..InputState::default()
+  };
+
+  env.press(host, start)?;
+  assert!(env.game_server.state.start_countdown.is_some());
+  env
+    .game_server
+    .handle_message(host, ClientToServerMessage::Kick { target: kicked })?;
+  env.dispatch_messages()?;
+  assert_eq!(env.game_server.state.start_countdown, None);
+  env.press(host, start)?;
+
+  // Their connection closes after they've been removed, which changes nothing.
+  env.forget_player(kicked);
+  env.game_server.disconnected(kicked)?;
+  env.dispatch_messages()?;
+  assert!(env.game_server.state.start_countdown.is_some());
+  let recording = env.game_server.get_recording().unwrap();
+  assert!(!recording
+    .entries
+    .iter()
+    .any(|e| matches!(e.event, RecordingEvent::Disconnect(_))));
+
+  Ok(())
+}
+
+#[test]
+fn test_ban_is_on_the_name_asked_for() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let host = env.create_and_connect_player()?;
+  env.create_and_join("Bob", None)?;
+  let second_bob = env.create_and_join("Bob", None)?;
+  assert_ne!(env.game_server.state.players[&second_bob].name, "Bob");
+
+  env
+    .game_server
+    .handle_message(host, ClientToServerMessage::Ban { target: second_bob })?;
+  env.forget_player(second_bob);
+  env.dispatch_messages()?;
+  let bob_again = env.create_and_join("bob", None)?;
+  assert!(!env.game_server.state.players.contains_key(&bob_again));
+
+  Ok(())
+}
+
+#[test]
+fn test_starting_the_game() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let host = env.create_and_connect_player()?;
+  let player2 = env.create_and_connect_player()?;
+  let player3 = env.create_and_connect_player()?;
+  let start = InputState {
+    play: true,
+    ..InputState::default()
+  };
+  let ready = InputState {
+    report: true,
+    ..InputState::default()
+  };
+  let was_told = |env: &TestEnvironment, uuid: UUID, text: &str| {
+    env.players[&uuid]
+      .displayed_messages
+      .iter()
+      .any(|m| m.message == Message::PlainString(text.to_string()))
+  };
+
+  // Every refused start says why.
+  env.press(host, start)?;
+  assert_eq!(env.game_server.state.status, GameStatus::Lobby);
+  assert!(was_told(
+    &env,
+    host,
+    "Need at least 4 players to start, but there are only 3"
+  ));
+  env.press(player2, start)?;
+  assert!(was_told(&env, player2, "Only the host can start the game"));
+
+  let mut settings = Settings {
+    min_players: 3,
+    num_impostors: 2,
+    ..Settings::default()
+  };
+  env
+    .players
+    .get_mut(&host)
+    .unwrap()
+    .update_settings(settings.clone())?;
+  env.dispatch_messages()?;
+  env.press(host, start)?;
+  assert!(was_told(
+    &env,
+    host,
+    "There need to be fewer impostors than crew, so 2 impostors need at least 5 players"
+  ));
+
+  settings.num_impostors = 1;
+  settings.require_ready = true;
+  env
+    .players
+    .get_mut(&host)
+    .unwrap()
+    .update_settings(settings)?;
+  env.dispatch_messages()?;
+  env.press(host, ready)?;
+  env.press(player2, ready)?;
+  env.press(host, start)?;
+  assert!(was_told(
+    &env,
+    host,
+    "Waiting for Test Player 3 to be ready"
+  ));
+  assert_eq!(env.game_server.state.start_countdown, None);
+
+  // Once everyone's ready, the countdown begins. Changing your mind stops it.
+  env.press(player3, ready)?;
+  env.press(host, start)?;
+  assert_eq!(
+    env.game_server.state.start_countdown,
+    Some(Duration::from_secs(5))
+  );
+  env.time_passes(Duration::from_secs(2))?;
+  env.press(player2, ready)?;
+  assert!(!env.game_server.state.players[&player2].ready);
+  assert_eq!(env.game_server.state.start_countdown, None);
+  assert!(was_told(
+    &env,
+    host,
+    "Not starting the game, Test Player 2 isn't ready"
+  ));
+  env.time_passes(Duration::from_secs(5))?;
+  assert_eq!(env.game_server.state.status, GameStatus::Lobby);
+
+  env.press(player2, ready)?;
+  env.press(host, start)?;
+  env.time_passes(Duration::from_secs(4))?;
+  env.dispatch_messages()?;
+  let state = env.expect_everyone_agrees_on_game_state(3)?;
+  assert_eq!(state.status, GameStatus::Lobby);
+  env.time_passes(Duration::from_secs(1))?;
+  env.dispatch_messages()?;
+  let state = env.expect_everyone_agrees_on_game_state(3)?;
+  assert_eq!(state.status, GameStatus::Playing(PlayState::Night));
+  assert_eq!(state.players.values().filter(|p| p.impostor).count(), 1);
+  assert!(state.players.values().all(|p| !p.ready));
+
+  // The game starts when the recording says it did, with the same teams.
+  let recording = env.game_server.get_recording().unwrap();
+  let mut playback = PlaybackServer::new(recording)?;
+  let mut viewer = GameAsPlayer::new(UUID::random(), Box::new(PlaybackTx {}));
+  viewer.state.status = GameStatus::Lobby;
+  let end = playback.duration() + Duration::from_millis(16);
+  playback.skip_to(end, &mut viewer)?;
+  assert_eq!(
+    playback.game_state().status,
+    GameStatus::Playing(PlayState::Night)
+  );
+  assert_eq!(playback.game_state().players, state.players);
+
+  Ok(())
+}
+
+#[test]
+fn test_players_are_only_sent_what_they_can_see() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  for _ in 0..4 {
+    env.create_and_connect_player()?;
+  }
+  let spectator = env.create_player();
+  env.game_server.handle_message(
+    spectator,
+    ClientToServerMessage::Join {
+      version: get_version_sha().to_string(),
+      details: JoinRequest::JoinAsSpectator,
+      reconnect_token: None,
+    },
+  )?;
+  // Far enough to see across the table, if it weren't in the way.
+  env.game_server.state.settings.crew_vision = 300.0;
+  env.start_game()?;
+  let impostor = env
+    .game_server
+    .state
+    .players
+    .values()
+    .find(|p| p.impostor)
+    .unwrap()
+    .uuid;
+  let crew: Vec<UUID> = env
+    .game_server
+    .state
+    .players
+    .values()
+    .filter(|p| !p.impostor)
+    .map(|p| p.uuid)
+    .collect();
+  let (left, right, below) = (crew[0], crew[1], crew[2]);
+
+  // Left and right are close enough to see each other, but for the table
+  // between them. Everyone stands well clear of the table, so that no one
+  // gets pushed away from it, and of where they started, because clients
+  // ignore small corrections to where people are.
+  let spots = [
+    (left, Position { x: 140.0, y: 240.0 }),
+    (right, Position { x: 410.0, y: 310.0 }),
+    (below, Position { x: 140.0, y: 420.0 }),
+    (impostor, Position { x: 410.0, y: 420.0 }),
+  ];
+  for (uuid, position) in spots {
+    env.game_server.handle_message(
+      uuid,
+      ClientToServerMessage::Move(MoveMessage {
+        speed: Velocity { dx: 0.0, dy: 0.0 },
+        position,
+      }),
+    )?;
+  }
+  env.dispatch_messages()?;
+  let snapshot = env
+    .game_server
+    .snapshot_for(&env.game_server.state.players[&left])
+    .unwrap();
+  let seen: BTreeSet<UUID> = snapshot.players.iter().map(|p| p.uuid).collect();
+  assert_eq!(seen, BTreeSet::from([left, below]));
+  assert_eq!(snapshot.hidden_players, BTreeSet::from([right, impostor]));
+  let client = &env.players[&left];
+  assert_eq!(client.hidden_players, BTreeSet::from([right, impostor]));
+  assert_ne!(client.state.players[&right].position, spots[1].1);
+  assert_eq!(client.state.players[&below].position, spots[2].1);
+  env.expect_everyone_agrees_on_game_state(5)?;
+
+  // Spectators see everyone.
+  let client = &env.players[&spectator];
+  assert!(client.hidden_players.is_empty());
+  for (uuid, position) in spots {
+    assert_eq!(client.state.players[&uuid].position, position);
+  }
+
+  // So do the dead.
+  let left_color = env.game_server.state.players[&left].color;
+  env.game_server.handle_message(
+    impostor,
+    ClientToServerMessage::Killed(DeadBody {
+      color: left_color,
+      position: spots[0].1,
+    }),
+  )?;
+  env.dispatch_messages()?;
+  assert!(env
+    .game_server
+    .snapshot_for(&env.game_server.state.players[&left])
+    .is_none());
+  let client = &env.players[&left];
+  assert!(client.hidden_players.is_empty());
+  assert_eq!(client.state.players[&right].position, spots[1].1);
+  // The body is behind the table from where the one on the right stands.
+  assert!(env.players[&right].state.bodies.is_empty());
+  assert_eq!(env.players[&below].state.bodies.len(), 1);
+
+  Ok(())
+}
+
+#[test]
+fn test_large_lobby_and_changing_color() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let host = env.create_and_connect_player()?;
+  env
+    .players
+    .get_mut(&host)
+    .unwrap()
+    .update_settings(Settings {
+      max_players: 12,
+      ..Settings::default()
+    })?;
+  env.dispatch_messages()?;
+  for _ in 1..12 {
+    env.create_and_connect_player()?;
+  }
+  let state = env.expect_everyone_agrees_on_game_state(12)?;
+  let colors: BTreeSet<Color> = state.players.values().map(|p| p.color).collect();
+  assert_eq!(colors.len(), 12);
+
+  // There's no room for anyone else to play.
+  let late = env.create_and_join("Latecomer", None)?;
+  assert!(!env.game_server.state.players.contains_key(&late));
+  env.forget_player(late);
+  env.expect_everyone_agrees_on_game_state(12)?;
+
+  // Players can't take someone else's color, but can pick a free one.
+  let other_color = state
+    .players
+    .values()
+    .find(|p| p.uuid != host)
+    .unwrap()
+    .color;
+  env.game_server.handle_message(
+    host,
+    ClientToServerMessage::ChangeColor { color: other_color },
+  )?;
+  env.dispatch_messages()?;
+  assert_eq!(
+    env.game_server.state.players[&host].color,
+    state.players[&host].color
+  );
+  assert!(env.players[&host]
+    .displayed_messages
+    .iter()
+    .any(|m| m.message == Message::PlainString(format!("{:?} is already taken", other_color))));
+  env.press(
+    host,
+    InputState {
+      change_color: true,
+      ..InputState::default()
+    },
+  )?;
+  let state = env.expect_everyone_agrees_on_game_state(12)?;
+  let new_color = state.players[&host].color;
+  assert!(!colors.contains(&new_color));
+
+  env.start_game()?;
+  assert_eq!(
+    env.game_server.state.status,
+    GameStatus::Playing(PlayState::Night)
+  );
+  // Everyone gets a spot on the voting screen, with skip after them all.
+  assert_eq!(voting_grid_player_rows(12), 6);
+  assert_eq!(voting_grid_player_rows(3), 5);
+
+  Ok(())
+}
+
+#[test]
+fn test_player_names() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let alice = env.create_and_join(" Alice\u{200B}\n", None)?;
+  let other_alice = env.create_and_join("ALICE", None)?;
+  let state = env.expect_everyone_agrees_on_game_state(2)?;
+  assert_eq!(state.players[&alice].name, "Alice");
+  assert_eq!(state.players[&other_alice].name, "ALICE 2");
+
+  // Names that are empty or too long are turned away.
+  for name in &["", " \u{FEFF}\t", "This name is much too long to fit"] {
+    let refused = env.create_and_join(name, None)?;
+    assert!(!env.game_server.state.players.contains_key(&refused));
+    env.forget_player(refused);
+  }
+  env.expect_everyone_agrees_on_game_state(2)?;
+
+  Ok(())
+}
+
+#[test]
+fn test_hostile_settings_are_rejected() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let host = env.create_and_connect_player()?;
+  env.create_and_connect_player()?;
+  assert_eq!(Settings::default().validate(), Ok(()));
+
+  let hostile = [
+    Settings {
+      num_tasks: usize::MAX,
+      ..Settings::default()
+    },
+    Settings {
+      num_impostors: usize::MAX,
+      ..Settings::default()
+    },
+    Settings {
+      speed: f64::NAN,
+      ..Settings::default()
+    },
+    Settings {
+      crew_vision: -1.0,
+      ..Settings::default()
+    },
+    Settings {
+      impostor_vision: f64::INFINITY,
+      ..Settings::default()
+    },
+    Settings {
+      voting_time: Duration::MAX,
+      ..Settings::default()
+    },
+    Settings {
+      min_players: 8,
+      max_players: 6,
+      ..Settings::default()
+    },
+  ];
+  for settings in hostile {
+    assert!(settings.validate().is_err(), "{:?}", settings);
+    env
+      .game_server
+      .handle_message(host, ClientToServerMessage::UpdateSettings(settings))?;
+    env.dispatch_messages()?;
+    let state = env.expect_everyone_agrees_on_game_state(2)?;
+    assert_eq!(state.settings, Settings::default());
+    let told = &env.players[&host]
+      .displayed_messages
+      .last()
+      .unwrap()
+      .message;
+    assert!(
+      matches!(told, Message::PlainString(text) if text.starts_with("Those settings won't work")),
+      "{:?}",
+      told
+    );
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_error_messages() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let host = env.create_and_connect_player()?;
+  let player2 = env.create_and_connect_player()?;
+
+  // Rejected actions and malformed messages are shown, but the player stays.
+  env.press(
+    host,
+    InputState {
+      play: true,
+      ..InputState::default()
+    },
+  )?;
+  env
+    .game_server
+    .malformed_message(player2, "expected value")?;
+  env.dispatch_messages()?;
+  env.expect_everyone_agrees_on_game_state(2)?;
+  let last_message = |env: &TestEnvironment, uuid: UUID| match &env.players[&uuid]
+    .displayed_messages
+    .last()
+    .unwrap()
+    .message
+  {
+    Message::PlainString(text) => text.clone(),
+    other => panic!("Expected a plain message, got {:?}", other),
+  };
+  assert!(last_message(&env, host).starts_with("Need at least 4 players"));
+  assert!(last_message(&env, player2).contains("expected value"));
+  assert_eq!(env.players[&host].fatal_error, None);
+  assert_eq!(env.players[&player2].fatal_error, None);
+
+  // Refused joins and kicks close the connection, after saying why.
+  let refused = env.create_and_join("", None)?;
+  assert_eq!(
+    env.players[&refused].fatal_error.as_deref(),
+    Some("Your name can't be empty")
+  );
+  assert_eq!(env.players[&refused].state.status, GameStatus::Disconnected);
+  env.forget_player(refused);
+  env
+    .game_server
+    .handle_message(host, ClientToServerMessage::Kick { target: player2 })?;
+  env.dispatch_messages()?;
+  assert!(env.players[&player2].fatal_error.is_some());
+  assert_eq!(env.players[&player2].state.status, GameStatus::Disconnected);
+  env.forget_player(player2);
+  env.expect_everyone_agrees_on_game_state(1)?;
+
+  // As are clients that break the protocol.
+  let spammer = env.create_and_connect_player()?;
+  env
+    .game_server
+    .protocol_violation(spammer, "sending too many messages")?;
+  env.dispatch_messages()?;
+  assert_eq!(
+    env.players[&spammer].fatal_error.as_deref(),
+    Some("Disconnected for sending too many messages")
+  );
+  assert_eq!(env.players[&spammer].state.status, GameStatus::Disconnected);
+  env.forget_player(spammer);
+  env.expect_everyone_agrees_on_game_state(1)?;
+
+  Ok(())
+}
+
+#[test]
+fn test_restarting_the_server() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  for _ in 0..4 {
+    env.create_and_connect_player()?;
+  }
+  env.start_game()?;
+
+  // The round that's being played can finish.
+  env.game_server.prepare_for_restart()?;
+  env.dispatch_messages()?;
+  assert!(env.game_server.round_in_progress());
+  vote_out_the_impostor(&mut env)?;
+  assert!(!env.game_server.round_in_progress());
+
+  // But no more can start.
+  env.start_game()?;
+  env.start_game()?;
+  let state = env.expect_everyone_agrees_on_game_state(4)?;
+  assert_eq!(state.status, GameStatus::Lobby);
+  let host = state.host.unwrap();
+  assert!(env.players[&host]
+    .displayed_messages
+    .iter()
+    .any(|m| m.message
+      == Message::PlainString(
+        "The server is restarting soon, so new games can't start".to_string()
+      )));
+
+  // When the server restarts, everyone is told why they were disconnected.
+  env.game_server.end_for_restart()?;
+  env.dispatch_messages()?;
+  assert!(env.game_server.simulate_tick()?);
+  for player in env.players.values() {
+    assert_eq!(
+      player.fatal_error.as_deref(),
+      Some("The server restarted. Reload the page to play again")
+    );
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_actions_from_outside_the_game() -> Result<(), Box<dyn Error>> {
+  let mut env = TestEnvironment::new();
+  let player1 = env.create_and_connect_player()?;
+  let player2 = env.create_and_connect_player()?;
+  env.create_and_connect_player()?;
+
+  env.game_server.announce(DisplayMessage {
+    message: Message::PlainString("Hello from the admin".to_string()),
+    duration: Duration::from_secs(15),
+    delay_before_show: Duration::from_secs(0),
+  })?;
+  env.dispatch_messages()?;
+  for player in env.players.values() {
+    assert!(player
+      .displayed_messages
+      .iter()
+      .any(|m| m.message == Message::PlainString("Hello from the admin".to_string())));
+  }
+
+  env
+    .game_server
+    .kick(player2, "Removed by the admin".to_string())?;
+  env.dispatch_messages()?;
+  assert_eq!(
+    env.players[&player2].fatal_error.as_deref(),
+    Some("Removed by the admin")
+  );
+  env.forget_player(player2);
+  let state = env.expect_everyone_agrees_on_game_state(2)?;
+  assert_eq!(
+    env.game_server.snapshot().players.len(),
+    state.players.len()
+  );
+
+  env
+    .game_server
+    .end_game(ErrorCode::GameEnded, "Game over, man".to_string())?;
+  env.dispatch_messages()?;
+  assert_eq!(
+    env.players[&player1].fatal_error.as_deref(),
+    Some("Game over, man")
+  );
+  assert!(env.game_server.state.status.finished());
+
+  Ok(())
+}
@@ -0,0 +1,193 @@
+use crate::*;
+use std::f64::consts::PI;
+
+// Enough that the edge of someone's vision looks round.
+const NUM_RAYS: usize = 180;
+// How far either side of a shape's edge to aim the extra rays, so that the
+// shadow it casts starts right at its edge.
+const EDGE_EPSILON: f64 = 0.0001;
+
+// Everything a player can see: as far as their vision goes in every
+// direction, unless something on the map is in the way. The client draws its
+// fog of war around this, and uses it to decide who to show.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VisibilityPolygon {
+  center: Position,
+  radius: f64,
+  // The angle of each point around the center, from 0 up to 2π.
+  angles: Vec<f64>,
+  points: Vec<Position>,
+  // How far away each point is, which is the full radius if nothing blocked it.
+  distances: Vec<f64>,
+}
+
+impl VisibilityPolygon {
+  pub fn new(map: &Map, center: Position, radius: f64) -> Self {
+    let shapes: Vec<&Shape> = map
+      .shapes_near(center, radius)
+      // Ghosts see from wherever they are, even inside the furniture.
+      .filter(|shape| !shape.collides_with(center, 0.0))
+      .collect();
+
+    let mut angles: Vec<f64> = (0..NUM_RAYS)
+      .map(|i| (i as f64) * 2.0 * PI / (NUM_RAYS as f64))
+      .collect();
+    for shape in shapes.iter() {
+      let (shape_center, shape_radius) = shape.bounding_circle();
+      let distance = center.distance(&shape_center);
+      let towards = (shape_center.y - center.y).atan2(shape_center.x - center.x);
+      let half_width = (shape_radius / distance).asin();
+      for edge in [towards - half_width, towards + half_width].iter() {
+        angles.extend_from_slice(&[edge - EDGE_EPSILON, *edge, edge + EDGE_EPSILON]);
+      }
+    }
+    let mut angles: Vec<f64> = angles
+      .into_iter()
+      .map(|angle| angle.rem_euclid(2.0 * PI))
+      .collect();
+    angles.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    angles.dedup();
+
+    let mut points = Vec::with_capacity(angles.len());
+    let mut distances = Vec::with_capacity(angles.len());
+    for angle in angles.iter() {
+      let direction = Velocity {
+        dx: angle.cos(),
+        dy: angle.sin(),
+      };
+      let distance = shapes
+        .iter()
+        .filter_map(|shape| shape.ray_hit(center, direction))
+        .fold(radius, f64::min);
+      points.push(center.move_by(direction.times(distance)));
+      distances.push(distance);
+    }
+
+    VisibilityPolygon {
+      center,
+      radius,
+      angles,
+      points,
+      distances,
+    }
+  }
+
+  // The corners of the polygon, going around clockwise on screen.
+  pub fn points(&self) -> &[Position] {
+    &self.points
+  }
+
+  pub fn contains(&self, position: &Position) -> bool {
+    let distance = self.center.distance(position);
+    if distance > self.radius {
+      return false;
+    }
+    if distance == 0.0 {
+      return true;
+    }
+    let angle = (position.y - self.center.y)
+      .atan2(position.x - self.center.x)
+      .rem_euclid(2.0 * PI);
+    let after = self.angles.partition_point(|a| *a <= angle) % self.angles.len();
+    let before = (after + self.angles.len() - 1) % self.angles.len();
+    // With nothing in the way, vision is a circle, not the polygon inside it.
+    if self.distances[before] == self.radius && self.distances[after] == self.radius {
+      return true;
+    }
+    // Otherwise it has to be on our side of the edge between the two rays.
+    let (a, b) = (self.points[before], self.points[after]);
+    let side = |p: &Position| (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x);
+    side(position) * side(&self.center) >= 0.0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn map_with_a_table() -> Map {
+    const TABLE: &[Shape] = &[Shape::Circle {
+      radius: 50.0,
+      center: Position { x: 500.0, y: 500.0 },
+      fill_color: "#000",
+      outline_width: 1.0,
+      outline_color: "#000",
+    }];
+    let middle = (Position { x: 500.0, y: 500.0 }, 100.0);
+    Map::new(1000.0, 1000.0, middle, middle, TABLE)
+  }
+
+  #[test]
+  fn test_tables_block_vision() {
+    let map = map_with_a_table();
+    let me = Position { x: 400.0, y: 500.0 };
+    let vision = VisibilityPolygon::new(&map, me, 300.0);
+
+    // Right behind the table.
+    assert!(!vision.contains(&Position { x: 600.0, y: 500.0 }));
+    assert!(!vision.contains(&Position { x: 600.0, y: 530.0 }));
+    // Just in front of it, and off to the side of it.
+    assert!(vision.contains(&Position { x: 440.0, y: 500.0 }));
+    assert!(vision.contains(&Position { x: 600.0, y: 650.0 }));
+    assert!(vision.contains(&Position { x: 400.0, y: 200.0 }));
+    // Too far away, even with nothing in the way.
+    assert!(!vision.contains(&Position { x: 400.0, y: 150.0 }));
+    // Where I'm standing.
+    assert!(vision.contains(&me));
+  }
+
+  #[test]
+  fn test_players_cant_see_through_the_conference_table() {
+    let mut state = GameState::new();
+    state.status = GameStatus::Playing(PlayState::Night);
+    let (table, _) = state.map.static_geometry[0].bounding_circle();
+    let me = Player::new(
+      UUID::random(),
+      "Me".to_string(),
+      Color::Red,
+      Position {
+        x: table.x - 90.0,
+        y: table.y,
+      },
+    );
+    assert!(!me.can_see(
+      &state,
+      &Position {
+        x: table.x + 90.0,
+        y: table.y
+      }
+    ));
+    assert!(me.can_see(
+      &state,
+      &Position {
+        x: table.x - 90.0,
+        y: table.y + 90.0
+      }
+    ));
+    // Until they're a ghost.
+    let ghost = Player { dead: true, ..me };
+    assert!(ghost.can_see(
+      &state,
+      &Position {
+        x: table.x + 90.0,
+        y: table.y
+      }
+    ));
+  }
+
+  #[test]
+  fn test_nothing_in_the_way_is_a_circle() {
+    let map = map_with_a_table();
+    let me = Position { x: 100.0, y: 100.0 };
+    let vision = VisibilityPolygon::new(&map, me, 176.0);
+    for i in 0..360 {
+      let angle = (i as f64).to_radians();
+      let at = |distance: f64| Position {
+        x: me.x + distance * angle.cos(),
+        y: me.y + distance * angle.sin(),
+      };
+      assert!(vision.contains(&at(175.9)), "{} degrees", i);
+      assert!(!vision.contains(&at(176.1)), "{} degrees", i);
+    }
+  }
+}
@@ -20,7 +20,16 @@ if [[ "$OSTYPE" == "linux-gnu"* ]]; then
   sudo setcap CAP_NET_BIND_SERVICE=+eip target/release/prod
 fi
 
-# kill the previous server, if any
-kill `ps aux | grep -v grep | grep target/release/prod | tr -s ' ' | cut -d ' ' -f 2`
-nohup ./target/release/prod >./nohup.out &
+# Ask the previous server, if any, to shut down. It keeps serving the games in
+# progress until they finish (or until its shutdown deadline), then saves their
+# recordings and exits, freeing up the ports for the new server.
+previous=`ps aux | grep -v grep | grep target/release/prod | tr -s ' ' | cut -d ' ' -f 2`
+if [ -n "$previous" ]; then
+  echo "Waiting for the previous server to finish its games..."
+  kill -TERM $previous
+  while kill -0 $previous 2>/dev/null; do
+    sleep 1
+  done
+fi
+nohup ./target/release/prod >./nohup.out 2>&1 &
 tail -f nohup.out
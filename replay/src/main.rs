@@ -0,0 +1,256 @@
+#![warn(rust_2018_idioms)]
+
+mod timeline;
+
+use crate::timeline::Timeline;
+use rust_us_core::{RecordedGame, RecordingFormatVersion};
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = r"Plays back recorded airlock.chat games and prints what happened.
+
+Usage: replay [--validate] [--format text|json|csv] <recording.json or directory>...
+
+Directories are searched for .json recordings.
+
+Options:
+  -h, --help     Print this message.
+  -V, --version  Print the version of this tool.
+  --validate     Check that each replay ends the same way as the recorded game,
+                 and exit with an error if any don't.
+  --format FMT   Print the timeline as text (the default), json or csv.";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+  Text,
+  Json,
+  Csv,
+}
+
+struct Options {
+  validate: bool,
+  format: Format,
+  paths: Vec<PathBuf>,
+}
+
+// What we were asked to do on the command line.
+enum Command {
+  Help,
+  Version,
+  Replay(Options),
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+  let mut options = Options {
+    validate: false,
+    format: Format::Text,
+    paths: Vec::new(),
+  };
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--validate" => options.validate = true,
+      "--format" => {
+        options.format = match args.next().as_deref() {
+          Some("text") => Format::Text,
+          Some("json") => Format::Json,
+          Some("csv") => Format::Csv,
+          Some(other) => return Err(format!("Unknown format {:?}", other)),
+          None => return Err("Expected a format after --format".to_string()),
+        }
+      }
+      "-h" | "--help" => return Ok(Command::Help),
+      "-V" | "--version" => return Ok(Command::Version),
+      flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+      path => options.paths.push(PathBuf::from(path)),
+    }
+  }
+  if options.paths.is_empty() {
+    return Err(USAGE.to_string());
+  }
+  Ok(Command::Replay(options))
+}
+
+// The recordings to process, with any directories expanded into the recordings inside them.
+fn find_recordings(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+  let mut recordings = Vec::new();
+  for path in paths {
+    if !path.is_dir() {
+      recordings.push(path.clone());
+      continue;
+    }
+    let mut in_dir = Vec::new();
+    for entry in fs::read_dir(path)? {
+      let entry_path = entry?.path();
+      if entry_path.extension().map(|e| e == "json").unwrap_or(false) {
+        in_dir.push(entry_path);
+      }
+    }
+    in_dir.sort();
+    recordings.extend(in_dir);
+  }
+  Ok(recordings)
+}
+
+// Reads a recording, either on its own or wrapped in the Replay message
+// that the client saves to localStorage.
+fn load_recording(path: &Path) -> Result<RecordedGame, Box<dyn Error>> {
+  let encoded: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+  let encoded = match encoded {
+    serde_json::Value::Object(mut fields) if fields.len() == 1 && fields.contains_key("Replay") => {
+      fields.remove("Replay").unwrap()
+    }
+    other => other,
+  };
+  Ok(serde_json::from_value(encoded)?)
+}
+
+#[derive(Serialize)]
+struct Report {
+  recording: String,
+  format_version: RecordingFormatVersion,
+  recorded_by: String,
+  #[serde(flatten)]
+  timeline: Timeline,
+}
+
+impl Report {
+  fn new(path: &Path) -> Result<Self, Box<dyn Error>> {
+    let recording = load_recording(path)?;
+    Ok(Report {
+      recording: path.display().to_string(),
+      format_version: recording.format_version,
+      recorded_by: recording.version.clone(),
+      timeline: Timeline::play_back(recording)?,
+    })
+  }
+
+  fn print_text(&self) {
+    println!(
+      "{} (format {}, recorded by {})",
+      self.recording, self.format_version, self.recorded_by
+    );
+    for event in self.timeline.events.iter() {
+      println!("{}", event);
+    }
+    match self.timeline.winner {
+      Some(team) => println!("{:?} win!", team),
+      None => println!("The game didn't finish."),
+    }
+    println!();
+  }
+}
+
+fn print_csv(reports: &[Report]) {
+  println!("recording,seconds,event,actor,target,detail");
+  for report in reports {
+    for event in report.timeline.events.iter() {
+      println!(
+        "{},{:.3},{:?},{},{},{}",
+        csv_field(&report.recording),
+        event.seconds,
+        event.kind,
+        csv_field(event.actor.as_deref().unwrap_or("")),
+        csv_field(event.target.as_deref().unwrap_or("")),
+        csv_field(event.detail.as_deref().unwrap_or("")),
+      );
+    }
+  }
+}
+
+// Player names can contain anything, so quote fields that need it.
+fn csv_field(value: &str) -> String {
+  if value.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    value.to_string()
+  }
+}
+
+fn main() {
+  let options = match parse_args(std::env::args().skip(1)) {
+    Ok(Command::Replay(options)) => options,
+    Ok(Command::Help) => {
+      println!("{}", USAGE);
+      return;
+    }
+    Ok(Command::Version) => {
+      println!("replay {}", env!("CARGO_PKG_VERSION"));
+      return;
+    }
+    Err(message) => {
+      eprintln!("{}", message);
+      process::exit(2);
+    }
+  };
+  let paths = match find_recordings(&options.paths) {
+    Ok(paths) => paths,
+    Err(e) => {
+      eprintln!("Unable to find recordings: {}", e);
+      process::exit(2);
+    }
+  };
+
+  let mut failed = false;
+  let mut reports = Vec::new();
+  for path in paths.iter() {
+    let report = match Report::new(path) {
+      Ok(report) => report,
+      Err(e) => {
+        eprintln!("{}: unable to play back: {}", path.display(), e);
+        failed = true;
+        continue;
+      }
+    };
+    if options.validate {
+      match report.timeline.validate() {
+        Ok(()) => eprintln!("{}: replay matches the recording", path.display()),
+        Err(e) => {
+          eprintln!("{}: {}", path.display(), e);
+          failed = true;
+        }
+      }
+    }
+    if options.format == Format::Text {
+      report.print_text();
+    }
+    reports.push(report);
+  }
+
+  match options.format {
+    Format::Text => (),
+    Format::Json => match serde_json::to_string_pretty(&reports) {
+      Ok(json) => println!("{}", json),
+      Err(e) => {
+        eprintln!("Unable to encode timeline as json: {}", e);
+        failed = true;
+      }
+    },
+    Format::Csv => print_csv(&reports),
+  }
+
+  if failed {
+    process::exit(1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &[&str]) -> Result<Command, String> {
+    parse_args(args.iter().map(|a| a.to_string()))
+  }
+
+  #[test]
+  fn test_help_and_version_are_not_errors() {
+    assert!(matches!(parse(&["--help"]), Ok(Command::Help)));
+    assert!(matches!(parse(&["game.json", "-h"]), Ok(Command::Help)));
+    assert!(matches!(parse(&["--version"]), Ok(Command::Version)));
+    assert!(matches!(parse(&["game.json"]), Ok(Command::Replay(_))));
+    assert!(parse(&[]).is_err());
+    assert!(parse(&["--bogus", "game.json"]).is_err());
+  }
+}
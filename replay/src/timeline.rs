@@ -0,0 +1,350 @@
+use rust_us_core::*;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+// After the last recorded message, the game may still need time to finish, e.g.
+// for a vote to run out its clock. Give up if it takes longer than this.
+const MAX_TIME_AFTER_LAST_ENTRY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+  Joined,
+  Started,
+  Killed,
+  Reported,
+  Voted,
+  Ejected,
+  Disconnected,
+  Kicked,
+  Banned,
+  Won,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TimelineEvent {
+  pub seconds: f64,
+  pub kind: EventKind,
+  pub actor: Option<String>,
+  pub target: Option<String>,
+  pub detail: Option<String>,
+}
+
+impl Display for TimelineEvent {
+  fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let actor = self.actor.as_deref().unwrap_or("Someone");
+    let target = self.target.as_deref().unwrap_or("someone");
+    write!(fmt, "{:>8.1}s  ", self.seconds)?;
+    match self.kind {
+      EventKind::Joined => write!(fmt, "{} joined", actor)?,
+      EventKind::Started => write!(fmt, "{} started the game", actor)?,
+      EventKind::Killed => write!(fmt, "{} killed {}", actor, target)?,
+      EventKind::Reported => write!(fmt, "{} reported the body of {}", actor, target)?,
+      EventKind::Voted => write!(fmt, "{} voted for {}", actor, target)?,
+      EventKind::Ejected => match &self.target {
+        Some(target) => write!(fmt, "{} was thrown out the airlock", target)?,
+        None => write!(fmt, "No one was thrown out the airlock")?,
+      },
+      EventKind::Disconnected => write!(fmt, "{} disconnected", actor)?,
+      EventKind::Kicked => write!(fmt, "{} kicked out {}", actor, target)?,
+      EventKind::Banned => write!(fmt, "{} banned {}", actor, target)?,
+      EventKind::Won => write!(fmt, "Game over")?,
+    }
+    if let Some(detail) = &self.detail {
+      write!(fmt, " ({})", detail)?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Timeline {
+  pub events: Vec<TimelineEvent>,
+  // Who won when we played the recording back.
+  pub winner: Option<Team>,
+  // Who won according to the recording itself.
+  pub recorded_winner: Option<Team>,
+}
+
+impl Timeline {
+  // Plays back the recording from start to finish, noting what happened along the way.
+  pub fn play_back(recording: RecordedGame) -> Result<Timeline, Box<dyn Error>> {
+    let recorded_winner = recording.winner;
+    let mut playback = PlaybackServer::new(recording)?;
+    let mut viewer = GameAsPlayer::new(UUID::random(), Box::new(PlaybackTx {}));
+    viewer.state.status = GameStatus::Lobby;
+    let give_up_at = playback.duration() + MAX_TIME_AFTER_LAST_ENTRY;
+
+    let mut events = Vec::new();
+    loop {
+      let before = playback.game_state().clone();
+      let played_before = playback.entries_played();
+      let finished = playback.simulate(TICK, &mut viewer, true)?;
+      // Don't let the viewer's messages pile up.
+      viewer.simulate(TICK);
+      let after = playback.game_state();
+      let seconds = playback.current_time().as_secs_f64();
+      let played = &playback.recording().entries[played_before..playback.entries_played()];
+      for entry in played {
+        if let Some(event) = describe_entry(&entry.event, &before, after) {
+          events.push(TimelineEvent { seconds, ..event });
+        }
+      }
+      if let Some(event) = describe_status_change(&before.status, after) {
+        events.push(TimelineEvent { seconds, ..event });
+      }
+      if finished || playback.current_time() > give_up_at {
+        break;
+      }
+    }
+
+    let winner = match playback.game_state().status {
+      GameStatus::Won(team) => Some(team),
+      _ => None,
+    };
+    Ok(Timeline {
+      events,
+      winner,
+      recorded_winner,
+    })
+  }
+
+  // Checks that playing back the recording ended the same way as the original game.
+  pub fn validate(&self) -> Result<(), String> {
+    match (self.recorded_winner, self.winner) {
+      (None, _) => Err("the recording doesn't say how the game ended".to_string()),
+      (Some(recorded), Some(replayed)) if recorded == replayed => Ok(()),
+      (Some(recorded), replayed) => Err(format!(
+        "{:?} won the recorded game, but {} when played back",
+        recorded,
+        match replayed {
+          Some(team) => format!("{:?} won", team),
+          None => "no one won".to_string(),
+        }
+      )),
+    }
+  }
+}
+
+fn describe_entry(
+  event: &RecordingEvent,
+  before: &GameState,
+  after: &GameState,
+) -> Option<TimelineEvent> {
+  let message = match event {
+    RecordingEvent::Disconnect(uuid) => {
+      before.players.get(uuid)?;
+      return Some(event_of(EventKind::Disconnected, before, Some(*uuid), None));
+    }
+    // Whoever started the countdown, it's the host's game.
+    RecordingEvent::CountdownFinished(_) => {
+      return describe_start(&before.status, after, after.host);
+    }
+    RecordingEvent::Message(message) => message,
+  };
+  let sender = message.sender;
+  match &message.message {
+    ClientToServerMessage::Join {
+      details: JoinRequest::JoinAsPlayer { .. },
+      ..
+    } => {
+      if before.players.contains_key(&sender) || !after.players.contains_key(&sender) {
+        return None;
+      }
+      Some(event_of(EventKind::Joined, after, Some(sender), None))
+    }
+    ClientToServerMessage::Killed(body) => {
+      // Several messages can land in the same tick, so the victim may have
+      // only just joined. Either way, they're dead afterwards.
+      let victim = after
+        .players
+        .values()
+        .find(|p| p.color == body.color && p.dead)?;
+      let was_alive = before.players.get(&victim.uuid).map(|p| !p.dead);
+      if was_alive == Some(false) {
+        return None;
+      }
+      Some(event_of(
+        EventKind::Killed,
+        after,
+        Some(sender),
+        Some(victim.uuid),
+      ))
+    }
+    ClientToServerMessage::ReportBody { dead_body_color } => {
+      let meeting_called = before.status == GameStatus::Playing(PlayState::Night)
+        && matches!(after.status, GameStatus::Playing(PlayState::Voting(_)));
+      if !meeting_called {
+        return None;
+      }
+      let victim = before
+        .players
+        .values()
+        .find(|p| p.color == *dead_body_color)
+        .map(|p| p.uuid);
+      Some(event_of(EventKind::Reported, before, Some(sender), victim))
+    }
+    ClientToServerMessage::Vote { target } => {
+      let counted = match &after.status {
+        GameStatus::Playing(PlayState::Voting(voting)) => voting.votes.get(&sender) == Some(target),
+        GameStatus::Playing(PlayState::TallyingVotes(tally)) => tally
+          .votes_against
+          .get(target)
+          .map(|voters| voters.contains(&sender))
+          .unwrap_or(false),
+        _ => false,
+      };
+      let already_voted = match &before.status {
+        GameStatus::Playing(PlayState::Voting(voting)) => voting.votes.contains_key(&sender),
+        _ => true,
+      };
+      if !counted || already_voted {
+        return None;
+      }
+      Some(match target {
+        VoteTarget::Skip => TimelineEvent {
+          target: Some("skip".to_string()),
+          ..event_of(EventKind::Voted, before, Some(sender), None)
+        },
+        VoteTarget::Player { uuid } => {
+          event_of(EventKind::Voted, before, Some(sender), Some(*uuid))
+        }
+      })
+    }
+    ClientToServerMessage::Kick { target } | ClientToServerMessage::Ban { target } => {
+      if !before.players.contains_key(target) || after.players.contains_key(target) {
+        return None;
+      }
+      let kind = match &message.message {
+        ClientToServerMessage::Ban { .. } => EventKind::Banned,
+        _ => EventKind::Kicked,
+      };
+      Some(event_of(kind, before, Some(sender), Some(*target)))
+    }
+    ClientToServerMessage::StartGame() => describe_start(&before.status, after, Some(sender)),
+    ClientToServerMessage::Move(_)
+    | ClientToServerMessage::FinishedTask(_)
+    | ClientToServerMessage::SetReady { .. }
+    | ClientToServerMessage::ChangeColor { .. }
+    | ClientToServerMessage::ReturnToLobby()
+    | ClientToServerMessage::UpdateSettings(_) => None,
+    ClientToServerMessage::Join { .. } => None,
+  }
+}
+
+fn describe_start(
+  before: &GameStatus,
+  after: &GameState,
+  actor: Option<UUID>,
+) -> Option<TimelineEvent> {
+  // Several entries can land in the same tick, so the game may have been
+  // won in the same tick that it started.
+  if before != &GameStatus::Lobby
+    || !matches!(after.status, GameStatus::Playing(_) | GameStatus::Won(_))
+  {
+    return None;
+  }
+  let impostors: Vec<String> = after
+    .players
+    .values()
+    .filter(|p| p.impostor)
+    .map(describe_player)
+    .collect();
+  Some(TimelineEvent {
+    detail: Some(format!("impostors: {}", impostors.join(", "))),
+    ..event_of(EventKind::Started, after, actor, None)
+  })
+}
+
+fn describe_status_change(before: &GameStatus, after: &GameState) -> Option<TimelineEvent> {
+  match &after.status {
+    GameStatus::Playing(PlayState::ViewingOutcome(viewing))
+      if !matches!(before, GameStatus::Playing(PlayState::ViewingOutcome(_))) =>
+    {
+      Some(match viewing.outcome {
+        VoteOutcome::Player { uuid } => event_of(EventKind::Ejected, after, None, Some(uuid)),
+        VoteOutcome::Skip => TimelineEvent {
+          detail: Some("voted to skip".to_string()),
+          ..event_of(EventKind::Ejected, after, None, None)
+        },
+        VoteOutcome::Tie => TimelineEvent {
+          detail: Some("tie".to_string()),
+          ..event_of(EventKind::Ejected, after, None, None)
+        },
+      })
+    }
+    GameStatus::Won(team) if !matches!(before, GameStatus::Won(_)) => Some(TimelineEvent {
+      detail: Some(format!("{:?} win", team)),
+      ..event_of(EventKind::Won, after, None, None)
+    }),
+    _ => None,
+  }
+}
+
+fn event_of(
+  kind: EventKind,
+  state: &GameState,
+  actor: Option<UUID>,
+  target: Option<UUID>,
+) -> TimelineEvent {
+  let describe = |uuid: UUID| match state.players.get(&uuid) {
+    Some(player) => describe_player(player),
+    None => format!("{}", uuid),
+  };
+  TimelineEvent {
+    seconds: 0.0,
+    kind,
+    actor: actor.map(describe),
+    target: target.map(describe),
+    detail: None,
+  }
+}
+
+// Names aren't unique, so include the player's color too.
+fn describe_player(player: &Player) -> String {
+  format!("{} ({:?})", player.name, player.color)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn v1_fixture() -> RecordedGame {
+    serde_json::from_str(include_str!("../../core/fixtures/recording_v1.json")).unwrap()
+  }
+
+  #[test]
+  fn test_timeline_of_recorded_game() -> Result<(), Box<dyn Error>> {
+    let timeline = Timeline::play_back(v1_fixture())?;
+    let kinds: Vec<EventKind> = timeline.events.iter().map(|e| e.kind).collect();
+    assert_eq!(
+      kinds,
+      vec![
+        EventKind::Joined,
+        EventKind::Joined,
+        EventKind::Joined,
+        EventKind::Started,
+        EventKind::Killed,
+        EventKind::Won,
+      ]
+    );
+    assert_eq!(timeline.winner, Some(Team::Impostors));
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate() -> Result<(), Box<dyn Error>> {
+    let mut timeline = Timeline::play_back(v1_fixture())?;
+    // Format 1 recordings didn't say who won.
+    assert!(timeline.validate().is_err());
+
+    timeline.recorded_winner = Some(Team::Impostors);
+    assert_eq!(timeline.validate(), Ok(()));
+
+    timeline.recorded_winner = Some(Team::Crew);
+    assert!(timeline.validate().is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,275 @@
+use crate::server::{Game, WebsocketServer};
+use rust_us_core::{DisplayMessage, ErrorCode, Message, UUID};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+type State = Arc<Mutex<WebsocketServer>>;
+
+#[derive(Serialize)]
+struct RoomSummary {
+  id: UUID,
+  status: &'static str,
+  players: usize,
+  // Players plus spectators.
+  connections: usize,
+}
+
+#[derive(Deserialize)]
+struct Announcement {
+  text: String,
+  #[serde(default = "default_announcement_secs")]
+  duration_secs: u64,
+}
+
+fn default_announcement_secs() -> u64 {
+  15
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+// For whatever keeps the server running to check that it's still up.
+pub fn healthz_route() -> impl Filter<Extract = (&'static str,), Error = Rejection> + Clone {
+  warp::path("healthz").and(warp::path::end()).map(|| "ok")
+}
+
+// Routes under /admin for looking after the live rooms. They need an
+// `Authorization: Bearer <token>` header, and don't exist at all unless the
+// server was given a token.
+pub fn admin_routes(
+  ws_server: State,
+  token: Option<String>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  let state = warp::any().map(move || ws_server.clone());
+  let list = warp::path!("rooms")
+    .and(warp::get())
+    .and(state.clone())
+    .and_then(list_rooms);
+  let show = warp::path!("rooms" / UUID)
+    .and(warp::get())
+    .and(state.clone())
+    .and_then(show_room);
+  let end = warp::path!("rooms" / UUID / "end")
+    .and(warp::post())
+    .and(state.clone())
+    .and_then(end_room);
+  let kick = warp::path!("rooms" / UUID / "kick" / UUID)
+    .and(warp::post())
+    .and(state.clone())
+    .and_then(kick_player);
+  let announce_to_room = warp::path!("rooms" / UUID / "announce")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(state.clone())
+    .and_then(announce_to_room);
+  let announce_to_all = warp::path!("announce")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(state)
+    .and_then(announce_to_all);
+
+  warp::path("admin")
+    .and(authorized(token))
+    .and(
+      list
+        .or(show)
+        .unify()
+        .or(end)
+        .unify()
+        .or(kick)
+        .unify()
+        .or(announce_to_room)
+        .unify()
+        .or(announce_to_all)
+        .unify(),
+    )
+    .recover(unauthorized)
+}
+
+// Turns a failed `authorized` check into a 401.
+pub(crate) async fn unauthorized(rejection: Rejection) -> Result<impl Reply, Rejection> {
+  if rejection.find::<Unauthorized>().is_some() {
+    Ok(warp::reply::with_status(
+      "Unauthorized",
+      StatusCode::UNAUTHORIZED,
+    ))
+  } else {
+    Err(rejection)
+  }
+}
+
+// Passes requests with the given bearer token. Without a token, nothing passes,
+// as if the route didn't exist.
+pub(crate) fn authorized(
+  token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+  let expected = Arc::new(token.map(|token| format!("Bearer {}", token)));
+  warp::header::optional::<String>("authorization")
+    .and_then(move |header: Option<String>| {
+      let expected = expected.clone();
+      async move {
+        match (expected.as_ref(), header) {
+          (None, _) => Err(warp::reject::not_found()),
+          (Some(expected), Some(header)) if same_secret(expected, &header) => Ok(()),
+          _ => Err(warp::reject::custom(Unauthorized)),
+        }
+      }
+    })
+    .untuple_one()
+}
+
+// Compares every byte, so that how long it takes doesn't give away how much
+// of the token was right.
+fn same_secret(a: &str, b: &str) -> bool {
+  a.len() == b.len()
+    && a
+      .bytes()
+      .zip(b.bytes())
+      .fold(0, |difference, (a, b)| difference | (a ^ b))
+      == 0
+}
+
+fn find_game(ws_server: &State, id: UUID) -> Option<Game> {
+  let games = ws_server.lock().unwrap().live_games();
+  games.into_iter().find(|game| game.id == id)
+}
+
+fn not_found(what: &str) -> Response {
+  warp::reply::with_status(format!("No such {}", what), StatusCode::NOT_FOUND).into_response()
+}
+
+// The game's result, or None if it finished before it got to the request.
+fn done(result: Option<Result<(), String>>) -> Result<Response, Rejection> {
+  Ok(match result {
+    None => not_found("room"),
+    Some(Ok(())) => warp::reply::with_status("Done", StatusCode::OK).into_response(),
+    Some(Err(e)) => warp::reply::with_status(e, StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+  })
+}
+
+fn display_message(announcement: Announcement) -> DisplayMessage {
+  DisplayMessage {
+    message: Message::PlainString(announcement.text),
+    duration: Duration::from_secs(announcement.duration_secs),
+    delay_before_show: Duration::from_secs(0),
+  }
+}
+
+async fn list_rooms(ws_server: State) -> Result<Response, Rejection> {
+  let games = ws_server.lock().unwrap().live_games();
+  let mut rooms = vec![];
+  for game in games {
+    let summary = game
+      .call(|game_server| {
+        (
+          game_server.state.status.kind(),
+          game_server.state.players.len(),
+        )
+      })
+      .await;
+    if let Some((status, players)) = summary {
+      rooms.push(RoomSummary {
+        id: game.id,
+        status,
+        players,
+        connections: game.connections(),
+      });
+    }
+  }
+  rooms.sort_by_key(|room| room.id);
+  Ok(warp::reply::json(&rooms).into_response())
+}
+
+async fn show_room(id: UUID, ws_server: State) -> Result<Response, Rejection> {
+  let snapshot = match find_game(&ws_server, id) {
+    None => None,
+    Some(game) => game.call(|game_server| game_server.snapshot()).await,
+  };
+  Ok(match snapshot {
+    None => not_found("room"),
+    Some(snapshot) => warp::reply::json(&snapshot).into_response(),
+  })
+}
+
+async fn end_room(id: UUID, ws_server: State) -> Result<Response, Rejection> {
+  let game = match find_game(&ws_server, id) {
+    None => return Ok(not_found("room")),
+    Some(game) => game,
+  };
+  info!(room = %id, "Admin is ending the game");
+  let result = game
+    .call(|game_server| {
+      game_server
+        .end_game(
+          ErrorCode::GameEnded,
+          "This game was ended by the server's admin".to_string(),
+        )
+        .map_err(|e| e.to_string())
+    })
+    .await;
+  done(result)
+}
+
+async fn kick_player(id: UUID, player: UUID, ws_server: State) -> Result<Response, Rejection> {
+  let game = match find_game(&ws_server, id) {
+    None => return Ok(not_found("room")),
+    Some(game) => game,
+  };
+  if !game.is_connected(&player) {
+    return Ok(not_found("player"));
+  }
+  info!(room = %id, player = %player, "Admin is kicking a player");
+  let result = game
+    .call(move |game_server| {
+      game_server
+        .kick(
+          player,
+          "The server's admin removed you from this game".to_string(),
+        )
+        .map_err(|e| e.to_string())
+    })
+    .await;
+  done(result)
+}
+
+async fn announce_to_room(
+  id: UUID,
+  announcement: Announcement,
+  ws_server: State,
+) -> Result<Response, Rejection> {
+  let game = match find_game(&ws_server, id) {
+    None => return Ok(not_found("room")),
+    Some(game) => game,
+  };
+  info!(room = %id, "Admin announced {:?}", announcement.text);
+  let message = display_message(announcement);
+  done(announce(&game, message).await)
+}
+
+async fn announce_to_all(
+  announcement: Announcement,
+  ws_server: State,
+) -> Result<Response, Rejection> {
+  info!("Admin announced {:?} to every room", announcement.text);
+  let message = display_message(announcement);
+  let games = ws_server.lock().unwrap().live_games();
+  for game in games {
+    // Games that finish in the meantime don't need to hear it.
+    if let Some(Err(e)) = announce(&game, message.clone()).await {
+      return done(Some(Err(e)));
+    }
+  }
+  done(Some(Ok(())))
+}
+
+async fn announce(game: &Game, message: DisplayMessage) -> Option<Result<(), String>> {
+  game
+    .call(|game_server| game_server.announce(message).map_err(|e| e.to_string()))
+    .await
+}
@@ -0,0 +1,221 @@
+use rust_us_core::{Settings, DEFAULT_IDLE_TIMEOUT};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+// Everything about how the server runs. The defaults are for prod, each
+// binary can start from its own defaults, and then a TOML file given with
+// --config overrides those, and the other flags override the file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+  // Where the websocket server listens, along with the static files in prod.
+  pub port: u16,
+  // In prod, where the https server listens, if there's a certificate.
+  pub tls_port: u16,
+  pub static_dir: PathBuf,
+  pub tls_cert: PathBuf,
+  pub tls_key: PathBuf,
+  // Where http requests are redirected to once https is available.
+  pub public_url: String,
+  pub idle_timeout_secs: u64,
+  // When asked to shut down, how long to wait for games in progress to finish.
+  pub shutdown_deadline_secs: u64,
+  // Clients that send a bigger websocket message than this are disconnected.
+  pub max_message_bytes: usize,
+  // Where finished games are saved, if anywhere.
+  pub recording_dir: Option<PathBuf>,
+  // A filter like "info" or "debug,warp=info". RUST_LOG is used if this isn't set.
+  pub log_level: Option<String>,
+  // Needed to use the admin API and see the metrics, which are turned off if
+  // this isn't set.
+  pub admin_token: Option<String>,
+  // The settings that new games start with.
+  pub default_settings: Settings,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      port: 80,
+      tls_port: 443,
+      static_dir: PathBuf::from("./server/dist"),
+      // Managed by certbot, see https://certbot.eff.org/lets-encrypt/debianbuster-other
+      tls_cert: PathBuf::from("/etc/letsencrypt/live/airlock.chat/cert.pem"),
+      tls_key: PathBuf::from("/etc/letsencrypt/live/airlock.chat/privkey.pem"),
+      public_url: "https://airlock.chat".to_string(),
+      idle_timeout_secs: DEFAULT_IDLE_TIMEOUT.as_secs(),
+      shutdown_deadline_secs: 10 * /* minutes */ 60,
+      max_message_bytes: 16 * 1024,
+      recording_dir: None,
+      log_level: None,
+      admin_token: None,
+      default_settings: Settings::default(),
+    }
+  }
+}
+
+#[derive(StructOpt, Debug)]
+struct Flags {
+  /// A TOML file with any of the settings below, plus default_settings for new games
+  #[structopt(long, parse(from_os_str))]
+  config: Option<PathBuf>,
+  #[structopt(long)]
+  port: Option<u16>,
+  #[structopt(long)]
+  tls_port: Option<u16>,
+  #[structopt(long, parse(from_os_str))]
+  static_dir: Option<PathBuf>,
+  #[structopt(long, parse(from_os_str))]
+  tls_cert: Option<PathBuf>,
+  #[structopt(long, parse(from_os_str))]
+  tls_key: Option<PathBuf>,
+  #[structopt(long)]
+  public_url: Option<String>,
+  /// End games when no one has sent anything for this long
+  #[structopt(long)]
+  idle_timeout_secs: Option<u64>,
+  /// On SIGTERM, wait this long for games in progress to finish
+  #[structopt(long)]
+  shutdown_deadline_secs: Option<u64>,
+  /// Disconnect clients that send a websocket message bigger than this
+  #[structopt(long)]
+  max_message_bytes: Option<usize>,
+  /// Save finished games here
+  #[structopt(long, parse(from_os_str))]
+  recording_dir: Option<PathBuf>,
+  /// e.g. info or debug. Defaults to RUST_LOG, or info
+  #[structopt(long)]
+  log_level: Option<String>,
+  /// Turns on the admin API and metrics, for requests with this bearer token
+  #[structopt(long)]
+  admin_token: Option<String>,
+}
+
+impl Config {
+  // Loads the config from the command line flags, starting from the given defaults.
+  pub fn load(defaults: Config) -> Result<Config, Box<dyn Error>> {
+    Config::from_flags(defaults, Flags::from_args())
+  }
+
+  fn from_flags(defaults: Config, flags: Flags) -> Result<Config, Box<dyn Error>> {
+    let mut config = match &flags.config {
+      None => defaults,
+      Some(path) => {
+        let contents = std::fs::read_to_string(path)
+          .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Config::from_toml(defaults, &contents)
+          .map_err(|e| format!("Unable to load {}: {}", path.display(), e))?
+      }
+    };
+    let Flags {
+      config: _,
+      port,
+      tls_port,
+      static_dir,
+      tls_cert,
+      tls_key,
+      public_url,
+      idle_timeout_secs,
+      shutdown_deadline_secs,
+      max_message_bytes,
+      recording_dir,
+      log_level,
+      admin_token,
+    } = flags;
+    config.port = port.unwrap_or(config.port);
+    config.tls_port = tls_port.unwrap_or(config.tls_port);
+    config.static_dir = static_dir.unwrap_or(config.static_dir);
+    config.tls_cert = tls_cert.unwrap_or(config.tls_cert);
+    config.tls_key = tls_key.unwrap_or(config.tls_key);
+    config.public_url = public_url.unwrap_or(config.public_url);
+    config.idle_timeout_secs = idle_timeout_secs.unwrap_or(config.idle_timeout_secs);
+    config.shutdown_deadline_secs = shutdown_deadline_secs.unwrap_or(config.shutdown_deadline_secs);
+    config.max_message_bytes = max_message_bytes.unwrap_or(config.max_message_bytes);
+    config.recording_dir = recording_dir.or(config.recording_dir);
+    config.log_level = log_level.or(config.log_level);
+    config.admin_token = admin_token.or(config.admin_token);
+    Ok(config)
+  }
+
+  // Anything the file leaves out keeps its value from the defaults.
+  fn from_toml(defaults: Config, contents: &str) -> Result<Config, Box<dyn Error>> {
+    let mut config = toml::Value::try_from(defaults)?;
+    merge(&mut config, contents.parse()?);
+    let config: Config = config.try_into()?;
+    config
+      .default_settings
+      .validate()
+      .map_err(|e| format!("Invalid default_settings: {}", e))?;
+    Ok(config)
+  }
+}
+
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+  match (base, overrides) {
+    (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+      for (key, value) in overrides {
+        match base.get_mut(&key) {
+          Some(existing) => merge(existing, value),
+          None => {
+            base.insert(key, value);
+          }
+        }
+      }
+    }
+    (base, overrides) => *base = overrides,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn test_file_overrides_defaults() -> Result<(), Box<dyn Error>> {
+    let defaults = Config {
+      port: 3012,
+      ..Config::default()
+    };
+    let config = Config::from_toml(
+      defaults,
+      r#"
+        recording_dir = "/tmp/recordings"
+        [default_settings]
+        min_players = 6
+        voting_time = { secs = 60 }
+      "#,
+    )?;
+    assert_eq!(config.port, 3012);
+    assert_eq!(config.recording_dir, Some(PathBuf::from("/tmp/recordings")));
+    assert_eq!(config.default_settings.min_players, 6);
+    assert_eq!(config.default_settings.voting_time, Duration::from_secs(60));
+    assert_eq!(
+      config.default_settings.num_tasks,
+      Settings::default().num_tasks
+    );
+
+    assert!(Config::from_toml(Config::default(), "prot = 80").is_err());
+    assert!(
+      Config::from_toml(Config::default(), "[default_settings]\nnum_tasks = 1000000").is_err()
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_flags_override_file() -> Result<(), Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("airlock-config-{}.toml", std::process::id()));
+    std::fs::write(&path, "port = 8080\nlog_level = \"debug\"\n")?;
+    let flags =
+      Flags::from_iter_safe(&["prod", "--config", path.to_str().unwrap(), "--port", "9000"])?;
+    let config = Config::from_flags(Config::default(), flags);
+    std::fs::remove_file(&path)?;
+    let config = config?;
+    assert_eq!(config.port, 9000);
+    assert_eq!(config.log_level.as_deref(), Some("debug"));
+    assert_eq!(config.tls_port, 443);
+    Ok(())
+  }
+}
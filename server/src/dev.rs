@@ -1,27 +1,37 @@
 #![warn(rust_2018_idioms)]
 
-mod server;
-use crate::server::{client_connected, WebsocketServer};
+use rust_us_server::config::Config;
+use rust_us_server::logging::init_logging;
+use rust_us_server::routes;
+use rust_us_server::server::{drain_games, shutdown_requested, WebsocketServer};
 use std::error::Error;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
-use warp::Filter;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-  let addr: SocketAddr = ([0, 0, 0, 0], 3012).into();
-  let gameserver: Arc<Mutex<WebsocketServer>> = Arc::default();
-  let gameserver = warp::any().map(move || gameserver.clone());
-  let websocket_server = warp::ws()
-    .and(gameserver)
-    .map(|ws: warp::ws::Ws, gameserver| {
-      ws.on_upgrade(move |socket| client_connected(socket, gameserver))
-    });
+  let config = Config::load(Config {
+    port: 3012,
+    ..Config::default()
+  })?;
+  init_logging(config.log_level.as_deref());
+  let addr: SocketAddr = ([0, 0, 0, 0], config.port).into();
+  let shutdown_deadline = Duration::from_secs(config.shutdown_deadline_secs);
+  let config = Arc::new(config);
+  let websocket_server_state = Arc::new(Mutex::new(WebsocketServer::new(config.clone())));
 
-  let server = warp::serve(websocket_server);
-  println!("Listening on: {}", addr);
-  server.run(addr).await;
+  // Bind up front, so that a port that's taken is an error rather than a
+  // panic in the server's task.
+  let (addr, server) =
+    warp::serve(routes(websocket_server_state.clone(), &config)).try_bind_ephemeral(addr)?;
+  tracing::info!("Listening on: {}", addr);
+  // Keep serving while we wait for games to finish, so that players can
+  // reconnect to them.
+  tokio::spawn(server);
+  shutdown_requested().await;
+  drain_games(&websocket_server_state, shutdown_deadline).await;
 
   Ok(())
 }
@@ -0,0 +1,26 @@
+#![warn(rust_2018_idioms)]
+
+pub mod admin;
+pub mod config;
+pub mod logging;
+pub mod metrics;
+mod rate_limit;
+pub mod server;
+
+use crate::admin::{admin_routes, healthz_route};
+use crate::config::Config;
+use crate::metrics::metrics_route;
+use crate::server::{websocket_route, WebsocketServer};
+use std::sync::{Arc, Mutex};
+use warp::{Filter, Rejection, Reply};
+
+// Everything the dev server serves. The prod server serves static files too.
+pub fn routes(
+  ws_server: Arc<Mutex<WebsocketServer>>,
+  config: &Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  websocket_route(ws_server.clone(), config.max_message_bytes)
+    .or(healthz_route())
+    .or(metrics_route(config.admin_token.clone()))
+    .or(admin_routes(ws_server, config.admin_token.clone()))
+}
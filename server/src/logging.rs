@@ -0,0 +1,16 @@
+use tracing_subscriber::EnvFilter;
+
+// Logs to stderr. How much gets logged is controlled by the given filter, or
+// else by the RUST_LOG environment variable, e.g. RUST_LOG=debug, or
+// RUST_LOG=info,rust_us_core=trace to also see every message the game server
+// handles.
+pub fn init_logging(filter: Option<&str>) {
+  let filter = match filter {
+    Some(filter) => EnvFilter::new(filter),
+    None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+  };
+  tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_writer(std::io::stderr)
+    .init();
+}
@@ -0,0 +1,119 @@
+use crate::admin::{authorized, unauthorized};
+use lazy_static::lazy_static;
+use prometheus::{
+  register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+  register_int_gauge_vec, Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+  TextEncoder,
+};
+use rust_us_core::GameStatus;
+use warp::{Filter, Rejection, Reply};
+
+lazy_static! {
+  pub static ref CONNECTIONS: IntGauge = register_int_gauge!(
+    "airlock_connections",
+    "Websocket connections that are currently open"
+  )
+  .unwrap();
+  pub static ref GAMES: IntGaugeVec = register_int_gauge_vec!(
+    "airlock_games",
+    "Games being simulated, by status",
+    &["status"]
+  )
+  .unwrap();
+  pub static ref MESSAGES_RECEIVED: IntCounterVec = register_int_counter_vec!(
+    "airlock_messages_received_total",
+    "Messages received from players, by kind",
+    &["kind"]
+  )
+  .unwrap();
+  // A broadcast counts once for each player it's sent to.
+  pub static ref MESSAGES_SENT: IntCounterVec = register_int_counter_vec!(
+    "airlock_messages_sent_total",
+    "Messages sent to players, by kind",
+    &["kind"]
+  )
+  .unwrap();
+  pub static ref SIMULATION_TICK_SECONDS: Histogram = register_histogram!(
+    "airlock_simulation_tick_seconds",
+    "How long it takes to simulate one tick of a game",
+    vec![0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.016, 0.025, 0.05]
+  )
+  .unwrap();
+  // direction is "in" for messages from players we couldn't parse, and "out"
+  // for messages to players we couldn't encode.
+  pub static ref SERIALIZATION_ERRORS: IntCounterVec = register_int_counter_vec!(
+    "airlock_serialization_errors_total",
+    "Messages that couldn't be serialized or deserialized",
+    &["direction"]
+  )
+  .unwrap();
+  // reason is "rate_limited" or "too_large". Messages turned away before we
+  // parsed them have the kind "unknown".
+  pub static ref MESSAGES_REJECTED: IntCounterVec = register_int_counter_vec!(
+    "airlock_messages_rejected_total",
+    "Messages from players that were dropped without being handled, by kind and reason",
+    &["kind", "reason"]
+  )
+  .unwrap();
+  pub static ref ABUSIVE_DISCONNECTIONS: IntCounter = register_int_counter!(
+    "airlock_abusive_disconnections_total",
+    "Connections closed for sending too many messages, or ones that were too big"
+  )
+  .unwrap();
+}
+
+// Keeps the count of games by status up to date for one game, as its status
+// changes over time.
+#[derive(Default)]
+pub struct GameStatusGauge {
+  current: Option<&'static str>,
+}
+
+impl GameStatusGauge {
+  pub fn set(&mut self, status: &GameStatus) {
+    let kind = status.kind();
+    if self.current == Some(kind) {
+      return;
+    }
+    if let Some(previous) = self.current.replace(kind) {
+      GAMES.with_label_values(&[previous]).dec();
+    }
+    GAMES.with_label_values(&[kind]).inc();
+  }
+}
+
+impl Drop for GameStatusGauge {
+  fn drop(&mut self) {
+    if let Some(previous) = self.current {
+      GAMES.with_label_values(&[previous]).dec();
+    }
+  }
+}
+
+// Serves the metrics in the Prometheus text format at /metrics, to anyone with
+// the admin token, like the admin API.
+pub fn metrics_route(
+  token: Option<String>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  // Metrics are registered when they're first used, so make sure they're all
+  // there from the start, rather than appearing as things happen.
+  lazy_static::initialize(&CONNECTIONS);
+  lazy_static::initialize(&GAMES);
+  lazy_static::initialize(&MESSAGES_RECEIVED);
+  lazy_static::initialize(&MESSAGES_SENT);
+  lazy_static::initialize(&SIMULATION_TICK_SECONDS);
+  lazy_static::initialize(&SERIALIZATION_ERRORS);
+  lazy_static::initialize(&MESSAGES_REJECTED);
+  lazy_static::initialize(&ABUSIVE_DISCONNECTIONS);
+  warp::path("metrics")
+    .and(warp::path::end())
+    .and(authorized(token))
+    .map(|| {
+      let mut buffer = vec![];
+      if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Unable to encode metrics: {}", e);
+      }
+      String::from_utf8(buffer).unwrap_or_default()
+    })
+    .recover(unauthorized)
+}
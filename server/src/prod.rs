@@ -1,24 +1,30 @@
 #![warn(rust_2018_idioms)]
 
-mod server;
-
-use crate::server::{client_connected, WebsocketServer};
-use futures::join;
+use futures::{future, join};
+use rust_us_server::config::Config;
+use rust_us_server::logging::init_logging;
+use rust_us_server::routes;
+use rust_us_server::server::{drain_games, shutdown_requested, WebsocketServer};
 use std::error::Error;
-use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use warp::Filter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-  let path = Path::new("./server/dist");
+  let config = Config::load(Config::default())?;
+  init_logging(config.log_level.as_deref());
+  let path = config.static_dir.clone();
   if !path.exists() {
     return Err(
-      r"Web directory isn't built. Be sure to do (cd www && npm run build) first.
-Also this server must be run from the server directory."
-        .into(),
+      format!(
+        r"Web directory {} isn't built. Be sure to do (cd www && npm run build) first.
+Also this server must be run from the server directory, or be given --static-dir.",
+        path.display()
+      )
+      .into(),
     );
   }
 
@@ -28,42 +34,56 @@ Also this server must be run from the server directory."
   let fileserver = warp::fs::dir(path).with(warp::compression::gzip());
 
   // Define the websocket server
-  let gameserver: Arc<Mutex<WebsocketServer>> = Arc::default();
-  let gameserver = warp::any().map(move || gameserver.clone());
-  let websocket_server = warp::ws()
-    .and(gameserver)
-    .map(|ws: warp::ws::Ws, gameserver| {
-      ws.on_upgrade(move |socket| client_connected(socket, gameserver))
-    });
+  let config = Arc::new(config);
+  let websocket_server_state = Arc::new(Mutex::new(WebsocketServer::new(config.clone())));
 
   // If an incoming request looks like a websockets request, serve it as one,
-  // otherwise treat it as a request for a static file
-  let server = websocket_server.or(fileserver);
+  // otherwise treat it as a request for one of the operational routes, or
+  // for a static file
+  let server = routes(websocket_server_state.clone(), &config).or(fileserver);
 
-  // Managed by certbot, see https://certbot.eff.org/lets-encrypt/debianbuster-other
-  let privkey = Path::new("/etc/letsencrypt/live/airlock.chat/privkey.pem");
-  let cert = Path::new("/etc/letsencrypt/live/airlock.chat/cert.pem");
-
-  if privkey.exists() && cert.exists() {
-    let tls_server = warp::serve(server)
+  // Bind before spawning the servers, so that a port that's taken or a bad
+  // certificate stops the server with an error, instead of panicking in a
+  // task we never hear from again. They're never shut down gracefully, since
+  // they keep serving until the process exits.
+  if config.tls_key.exists() && config.tls_cert.exists() {
+    let (_, tls_server) = warp::serve(server)
       .tls()
-      .cert_path(cert)
-      .key_path(privkey)
-      .run(([0, 0, 0, 0], 443));
-    let http_server = warp::serve(warp::path::tail().map(move |path: warp::path::Tail| {
+      .cert_path(&config.tls_cert)
+      .key_path(&config.tls_key)
+      .try_bind_with_graceful_shutdown(([0, 0, 0, 0], config.tls_port), future::pending())?;
+    let public_url = config.public_url.trim_end_matches('/').to_string();
+    let redirect = warp::path::tail().map(move |path: warp::path::Tail| {
       warp::redirect::redirect(
-        warp::http::Uri::from_str(&format!("https://airlock.chat/{}", path.as_str()))
+        warp::http::Uri::from_str(&format!("{}/{}", public_url, path.as_str()))
           .expect("illegal URL path?"),
       )
-    }))
-    .run(([0, 0, 0, 0], 80));
-    println!("Listening for static file traffic on https://airlock.chat/");
-    join!(tls_server, http_server);
+    });
+    let (_, http_server) = warp::serve(redirect).try_bind_ephemeral(([0, 0, 0, 0], config.port))?;
+    tracing::info!(
+      "Listening for static file traffic on {}/",
+      config.public_url.trim_end_matches('/')
+    );
+    tokio::spawn(async {
+      join!(tls_server, http_server);
+    });
   } else {
-    let server = warp::serve(server).run(([0, 0, 0, 0], 80));
-    println!("Listening for static file traffic on http://localhost");
-    server.await;
+    let (_, server) = warp::serve(server).try_bind_ephemeral(([0, 0, 0, 0], config.port))?;
+    tracing::info!(
+      "Listening for static file traffic on http://localhost:{}",
+      config.port
+    );
+    tokio::spawn(server);
   }
 
+  // Keep serving while we wait for games to finish, so that players can
+  // reconnect to them.
+  shutdown_requested().await;
+  drain_games(
+    &websocket_server_state,
+    Duration::from_secs(config.shutdown_deadline_secs),
+  )
+  .await;
+
   Ok(())
 }
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+// Allows bursts of up to `capacity` at once, and `per_second` on average.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+  capacity: f64,
+  per_second: f64,
+  tokens: f64,
+  last_refill: Instant,
+}
+
+impl TokenBucket {
+  pub fn new(capacity: f64, per_second: f64, now: Instant) -> Self {
+    TokenBucket {
+      capacity,
+      per_second,
+      tokens: capacity,
+      last_refill: now,
+    }
+  }
+
+  // Whether there was a token left to take.
+  pub fn take(&mut self, now: Instant) -> bool {
+    let elapsed = now.saturating_duration_since(self.last_refill);
+    self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_second).min(self.capacity);
+    self.last_refill = now;
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+// Why a connection's message was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+  // The message is dropped (or for a Move, put off until the next tick), but
+  // the connection can carry on.
+  RateLimited,
+  // The connection has been misbehaving for too long, and should be closed.
+  Abusive,
+}
+
+// Keeps track of how many messages a connection has sent recently. Clients
+// only send Move when their input changes, so even a client that sends one
+// every frame stays well under these limits.
+#[derive(Debug)]
+pub struct ConnectionLimits {
+  // All frames, checked before we spend any time parsing them.
+  frames: TokenBucket,
+  by_kind: HashMap<&'static str, TokenBucket>,
+  // Every rejected message uses one of these up, and a connection that runs
+  // out is disconnected.
+  strikes: TokenBucket,
+}
+
+impl ConnectionLimits {
+  pub fn new(now: Instant) -> Self {
+    ConnectionLimits {
+      frames: TokenBucket::new(120.0, 90.0, now),
+      by_kind: HashMap::new(),
+      strikes: TokenBucket::new(50.0, 5.0, now),
+    }
+  }
+
+  pub fn check_frame(&mut self, now: Instant) -> Result<(), Rejection> {
+    if self.frames.take(now) {
+      return Ok(());
+    }
+    Err(self.strike(now))
+  }
+
+  pub fn check_kind(&mut self, kind: &'static str, now: Instant) -> Result<(), Rejection> {
+    let bucket = self.by_kind.entry(kind).or_insert_with(|| {
+      let (capacity, per_second) = limits_for_kind(kind);
+      TokenBucket::new(capacity, per_second, now)
+    });
+    if bucket.take(now) {
+      return Ok(());
+    }
+    Err(self.strike(now))
+  }
+
+  fn strike(&mut self, now: Instant) -> Rejection {
+    if self.strikes.take(now) {
+      Rejection::RateLimited
+    } else {
+      Rejection::Abusive
+    }
+  }
+}
+
+// The burst size and average rate allowed for each kind of message.
+fn limits_for_kind(kind: &str) -> (f64, f64) {
+  match kind {
+    "Move" => (60.0, 60.0),
+    "Join" => (5.0, 1.0),
+    _ => (20.0, 5.0),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn test_token_bucket() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(3.0, 2.0, start);
+    assert!((0..3).all(|_| bucket.take(start)));
+    assert!(!bucket.take(start));
+    // Half a second later, one more token has come in.
+    let later = start + Duration::from_millis(500);
+    assert!(bucket.take(later));
+    assert!(!bucket.take(later));
+    // But it never holds more than its capacity.
+    let much_later = start + Duration::from_secs(60);
+    assert_eq!((0..10).filter(|_| bucket.take(much_later)).count(), 3);
+  }
+
+  #[test]
+  fn test_spammers_are_cut_off() {
+    let start = Instant::now();
+    let mut limits = ConnectionLimits::new(start);
+    for _ in 0..20 {
+      assert_eq!(limits.check_kind("Vote", start), Ok(()));
+    }
+    // Other kinds of message have their own limits.
+    assert_eq!(limits.check_kind("Move", start), Ok(()));
+    for _ in 0..50 {
+      assert_eq!(
+        limits.check_kind("Vote", start),
+        Err(Rejection::RateLimited)
+      );
+    }
+    assert_eq!(limits.check_kind("Vote", start), Err(Rejection::Abusive));
+  }
+}
@@ -1,48 +1,163 @@
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
-use rust_us_core::ServerToClientMessage;
+use crate::config::Config;
+use crate::metrics::{
+  GameStatusGauge, ABUSIVE_DISCONNECTIONS, CONNECTIONS, MESSAGES_RECEIVED, MESSAGES_REJECTED,
+  MESSAGES_SENT, SERIALIZATION_ERRORS, SIMULATION_TICK_SECONDS,
+};
+use crate::rate_limit::{ConnectionLimits, Rejection};
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_channel::oneshot;
+use futures_util::{future, pin_mut, stream::TryStreamExt, SinkExt, StreamExt};
 use rust_us_core::{Broadcaster, ClientToServerMessage, GameServer, GameStatus, UUID};
-use std::collections::HashMap;
+use rust_us_core::{ErrorCode, MoveMessage, RecordedGame, ServerToClientMessage, TICK};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
-use tokio::time::delay_for;
+use tokio::time::{interval, sleep};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use warp::ws::Message;
 use warp::ws::WebSocket;
+use warp::{Filter, Reply};
 
 type Tx = UnboundedSender<Message>;
 type Room = Arc<Mutex<HashMap<UUID, Tx>>>;
 
-#[derive(Clone)]
+// The games being simulated, by id.
+type LiveGames = Arc<Mutex<HashMap<UUID, Game>>>;
+
 pub struct WebsocketServer {
+  config: Arc<Config>,
+  // The game that new connections join.
+  current_game: Game,
+  live_games: LiveGames,
+  // Once we're shutting down, no new games are created.
+  shutting_down: bool,
+}
+
+impl WebsocketServer {
+  pub fn new(config: Arc<Config>) -> Self {
+    let live_games = LiveGames::default();
+    WebsocketServer {
+      current_game: Game::new(&config, live_games.clone()),
+      config,
+      live_games,
+      shutting_down: false,
+    }
+  }
+
+  pub fn live_games(&self) -> Vec<Game> {
+    self.live_games.lock().unwrap().values().cloned().collect()
+  }
+}
+
+// One game, and the connections to the people in it. The game's GameServer is
+// owned by its own task, and everything else asks that task to do things
+// with it, so rooms never wait on each other.
+#[derive(Clone)]
+pub struct Game {
+  // Identifies the game in the logs, and its recording.
+  pub id: UUID,
   room: Room,
-  game_server: Arc<Mutex<GameServer>>,
+  commands: UnboundedSender<RoomCommand>,
 }
 
-impl Default for WebsocketServer {
-  fn default() -> Self {
+// What a game's task can be asked to do.
+enum RoomCommand {
+  // A message from one of the game's connections.
+  Message(UUID, ClientToServerMessage),
+  // A Move that was over the rate limit. Only the latest is kept, and it's
+  // applied on the next tick, so that the player doesn't end up stuck where
+  // the last Move to get through left them.
+  LateMove(UUID, MoveMessage),
+  Disconnected(UUID),
+  // Anything else, like the admin API looking at the game.
+  Run(Box<dyn FnOnce(&mut GameServer) + Send>),
+}
+
+impl Game {
+  // Starts the game's task. It waits for the first connection before it
+  // starts simulating.
+  fn new(config: &Config, live_games: LiveGames) -> Self {
+    let id = UUID::random();
     let room = Room::default();
-    let game_server = Arc::new(Mutex::new(GameServer::new(
-      Box::new(BroadCastServer { room: room.clone() }),
-      true,
-    )));
-    WebsocketServer { room, game_server }
+    let mut game_server = GameServer::new(Box::new(BroadCastServer { room: room.clone() }), true);
+    game_server.idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    game_server.state.settings = config.default_settings.clone();
+    let (commands, receiver) = unbounded();
+    let room_task = run_room(
+      id,
+      room.clone(),
+      game_server,
+      receiver,
+      config.recording_dir.clone(),
+      live_games,
+    );
+    tokio::spawn(room_task.instrument(info_span!("room", room = %id)));
+    Game { id, room, commands }
   }
-}
 
-impl WebsocketServer {}
+  // How many players and spectators are connected.
+  pub fn connections(&self) -> usize {
+    self.room.lock().unwrap().len()
+  }
+
+  pub fn is_connected(&self, uuid: &UUID) -> bool {
+    self.room.lock().unwrap().contains_key(uuid)
+  }
+
+  // Whether the game's task is done, so that it can't be joined any more.
+  fn finished(&self) -> bool {
+    self.commands.is_closed()
+  }
+
+  fn send(&self, command: RoomCommand) {
+    // This only fails once the game has finished, when there's nothing left
+    // to do with the command anyway.
+    let _ = self.commands.unbounded_send(command);
+  }
+
+  // Has the game's task run `f`, without waiting for it to.
+  fn run(&self, f: impl FnOnce(&mut GameServer) + Send + 'static) {
+    self.send(RoomCommand::Run(Box::new(f)));
+  }
+
+  // Has the game's task run `f`, and waits for the result. Returns None if
+  // the game finished first.
+  pub async fn call<R: Send + 'static>(
+    &self,
+    f: impl FnOnce(&mut GameServer) -> R + Send + 'static,
+  ) -> Option<R> {
+    let (respond, response) = oneshot::channel();
+    self.run(move |game_server| {
+      let _ = respond.send(f(game_server));
+    });
+    response.await.ok()
+  }
+}
 
 struct BroadCastServer {
   room: Room,
 }
 
 impl Broadcaster for BroadCastServer {
-  fn broadcast(&self, message: &ServerToClientMessage) -> Result<(), Box<dyn Error>> {
-    println!("Broadcasting {:?}", message);
-    let message = Message::text(serde_json::to_string(message)?);
-    broadcast(self.room.clone(), &message);
+  fn broadcast_except(
+    &self,
+    message: &ServerToClientMessage,
+    except: &BTreeSet<UUID>,
+  ) -> Result<(), Box<dyn Error>> {
+    if let ServerToClientMessage::Snapshot(_) = message {
+      // Sent every frame, so only worth seeing when tracing.
+      trace!("Broadcasting {}", message.kind());
+    } else {
+      debug!("Broadcasting {:?}", message);
+    }
+    let kind = message.kind();
+    let message = encode(message)?;
+    let sent = broadcast(self.room.clone(), &message, except);
+    MESSAGES_SENT.with_label_values(&[kind]).inc_by(sent);
     Ok(())
   }
 
@@ -57,120 +172,349 @@ impl Broadcaster for BroadCastServer {
       None => {
         // This could be a race condition with a player disconnecting.
         // Not a fatal error.
-        println!("No player connection with UUID {:?}", uuid);
+        debug!(player = %uuid, "No connection to send {} to", message.kind());
         return Ok(());
       }
       Some(p) => p,
     };
-    println!("Sending {:?} to {:?}", message, uuid);
-    let encoded = Message::text(serde_json::to_string(message)?);
-    if let Err(e) = player_connection.unbounded_send(encoded) {
-      println!("Error with unbounded send: {}", e);
+    if let ServerToClientMessage::Snapshot(_) = message {
+      // Players are sent their own snapshots every frame at night.
+      trace!(player = %uuid, "Sending {}", message.kind());
+    } else {
+      debug!(player = %uuid, "Sending {:?}", message);
+    }
+    let encoded = encode(message)?;
+    match player_connection.unbounded_send(encoded) {
+      Ok(()) => MESSAGES_SENT.with_label_values(&[message.kind()]).inc(),
+      Err(e) => warn!(player = %uuid, "Error with unbounded send: {}", e),
+    }
+    Ok(())
+  }
+
+  fn disconnect_player(&self, uuid: &UUID) -> Result<(), Box<dyn Error>> {
+    // Closing the channel ends the connection once everything already
+    // sent has been delivered.
+    if let Some(player_connection) = self.room.lock().unwrap().get(uuid) {
+      player_connection.close_channel();
     }
     Ok(())
   }
 }
 
-pub async fn client_connected(ws: WebSocket, ws_server: Arc<Mutex<WebsocketServer>>) {
-  let game_server;
-  let room;
+// Upgrades requests for a websocket into connections to the current game.
+pub fn websocket_route(
+  ws_server: Arc<Mutex<WebsocketServer>>,
+  max_message_bytes: usize,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+  // Messages over max_message_bytes get their sender disconnected, but past
+  // this we won't even read them into memory.
+  let max_buffered_bytes = max_message_bytes * 4;
+  // Clients connect to /. Anywhere else, the websocket upgrade failing would
+  // hide that there's nothing there.
+  warp::path::end()
+    .and(warp::ws())
+    .and(warp::any().map(move || ws_server.clone()))
+    .map(move |ws: warp::ws::Ws, ws_server| {
+      ws.max_message_size(max_buffered_bytes)
+        .on_upgrade(move |socket| client_connected(socket, ws_server))
+    })
+}
+
+async fn client_connected(ws: WebSocket, ws_server: Arc<Mutex<WebsocketServer>>) {
+  let game;
+  let live_games;
+  let max_message_bytes;
   {
     let mut ws_server = ws_server.lock().unwrap();
-    let prev_game_finished;
-    {
-      let game_server = ws_server.game_server.lock().unwrap();
-      prev_game_finished = game_server.state.status.finished();
+    if ws_server.shutting_down {
+      // Even the current game's lobby, as no new rounds can start in it.
+      game = None;
+    } else {
+      if ws_server.current_game.finished() {
+        // The previous game is finished. Create a new game and direct future players to it.
+        ws_server.current_game = Game::new(&ws_server.config, ws_server.live_games.clone());
+        info!(room = %ws_server.current_game.id, "Starting a new game for the new client");
+      }
+      game = Some(ws_server.current_game.clone());
+    }
+    live_games = ws_server.live_games.clone();
+    max_message_bytes = ws_server.config.max_message_bytes;
+  }
+  match game {
+    None => refuse_connection(ws).await,
+    Some(game) => {
+      let span = info_span!("room", room = %game.id);
+      tokio::spawn(handle_connection(game, live_games, ws, max_message_bytes).instrument(span));
+    }
+  }
+}
+
+// Tells someone who connects while we're shutting down to come back later.
+async fn refuse_connection(mut ws: WebSocket) {
+  info!("Refusing a new connection, because the server is shutting down");
+  let refusal = ServerToClientMessage::Error {
+    code: ErrorCode::ServerRestarting,
+    message: "The server is restarting. Try again in a minute".to_string(),
+  };
+  let refusal = encode(&refusal).ok();
+  if let Some(refusal) = refusal {
+    if let Err(e) = ws.send(refusal).await {
+      debug!("Unable to tell them why: {}", e);
     }
-    if prev_game_finished {
-      // The previous game is finished. Create a new game and direct future players to it.
-      let room = Room::default();
-      let broadcast_server = BroadCastServer { room: room.clone() };
-      ws_server.room = room;
-      ws_server.game_server = Arc::new(Mutex::new(GameServer::new(
-        Box::new(broadcast_server),
-        true,
-      )));
-      println!("Starting a new game for the new client!");
+  }
+  if let Err(e) = ws.close().await {
+    debug!("Unable to close the connection: {}", e);
+  }
+}
+
+fn encode(message: &ServerToClientMessage) -> Result<Message, Box<dyn Error>> {
+  match serde_json::to_string(message) {
+    Ok(text) => Ok(Message::text(text)),
+    Err(e) => {
+      SERIALIZATION_ERRORS.with_label_values(&["out"]).inc();
+      Err(e.into())
     }
-    game_server = ws_server.game_server.clone();
-    room = ws_server.room.clone();
   }
-  tokio::spawn(handle_connection(game_server, room, ws));
 }
 
-async fn simulation_loop(game_server: Arc<Mutex<GameServer>>, room: Room) {
+// The most ticks a room simulates at once after falling behind.
+const MAX_TICKS_TO_CATCH_UP: u32 = 5;
+
+// Owns a game, handling the commands sent to it in between simulating it.
+async fn run_room(
+  id: UUID,
+  room: Room,
+  mut game_server: GameServer,
+  mut commands: UnboundedReceiver<RoomCommand>,
+  recording_dir: Option<PathBuf>,
+  live_games: LiveGames,
+) {
+  let mut ticks = interval(TICK);
   let mut prev = Instant::now();
+  // Time that's passed which we haven't simulated yet.
+  let mut unsimulated = Duration::from_secs(0);
+  let mut status_gauge = GameStatusGauge::default();
+  let mut late_moves = BTreeMap::new();
   loop {
-    delay_for(Duration::from_millis(16)).await;
-    let now = Instant::now();
-    let elapsed = now - prev;
-    prev = now;
-    let mut game_server = game_server.lock().unwrap();
-    // The server wants to disconnect the players (e.g. timeout),
-    // so close all the connections.
-    let finished = match game_server.simulate(elapsed) {
-      Err(e) => {
-        println!("Error handling simulation: {}", e);
-        true
+    tokio::select! {
+      command = commands.next() => match command {
+        Some(command) => {
+          handle_command(&mut game_server, &mut late_moves, command);
+          // Going back to the lobby starts a new recording, so save the last one now.
+          for (round, recording) in game_server.take_finished_rounds() {
+            save_round(id, round, &recording, recording_dir.as_deref());
+          }
+        }
+        // Everyone has forgotten about the game, so no one can join it.
+        None => return,
+      },
+      _ = ticks.tick() => {
+        let now = Instant::now();
+        unsimulated += now - prev;
+        prev = now;
+        // Nothing happens until the first player connects.
+        if game_server.state.status == GameStatus::Connecting {
+          unsimulated = Duration::from_secs(0);
+          continue;
+        }
+        for (uuid, moved) in std::mem::take(&mut late_moves) {
+          let message = RoomCommand::Message(uuid, ClientToServerMessage::Move(moved));
+          handle_command(&mut game_server, &mut late_moves, message);
+        }
+        // If we fall far behind, drop the time rather than trying to catch
+        // up all at once.
+        unsimulated = unsimulated.min(TICK * MAX_TICKS_TO_CATCH_UP);
+        let mut finished = false;
+        while unsimulated >= TICK && !finished {
+          unsimulated -= TICK;
+          let timer = SIMULATION_TICK_SECONDS.start_timer();
+          finished = match game_server.simulate_tick() {
+            Err(e) => {
+              error!("Error handling simulation: {}", e);
+              true
+            }
+            Ok(b) => b,
+          };
+          timer.observe_duration();
+        }
+        status_gauge.set(&game_server.state.status);
+        if finished {
+          break;
+        }
       }
-      Ok(b) => b,
-    };
-    if finished {
-      println!("Game finished, done simulating it on the server.");
-      let mut room = room.lock().unwrap();
-      for (_, player) in room.iter_mut() {
-        player.disconnect();
+    }
+  }
+
+  // The server wants to disconnect the players (e.g. timeout),
+  // so close all the connections.
+  info!("Game finished, done simulating it on the server");
+  if let Some(recording) = game_server.get_recording() {
+    save_round(
+      id,
+      game_server.round(),
+      &recording,
+      recording_dir.as_deref(),
+    );
+  }
+  live_games.lock().unwrap().remove(&id);
+  let mut room = room.lock().unwrap();
+  for (_, player) in room.iter_mut() {
+    player.disconnect();
+  }
+}
+
+fn handle_command(
+  game_server: &mut GameServer,
+  late_moves: &mut BTreeMap<UUID, MoveMessage>,
+  command: RoomCommand,
+) {
+  match command {
+    RoomCommand::Message(uuid, message) => {
+      // A newer Move supersedes one that's still waiting.
+      if let ClientToServerMessage::Move(_) = message {
+        late_moves.remove(&uuid);
+      }
+      let _entered = info_span!("player", player = %uuid).entered();
+      if let Err(e) = game_server.handle_message(uuid, message) {
+        warn!("Failed to handle message: {}", e);
+      }
+    }
+    RoomCommand::Disconnected(uuid) => {
+      late_moves.remove(&uuid);
+      let _entered = info_span!("player", player = %uuid).entered();
+      if game_server.state.status.finished() {
+        // The game is done, and the room will clean up, just return.
+      } else if let Err(e) = game_server.disconnected(uuid) {
+        warn!("Error handling disconnection: {}", e);
       }
-      break;
+    }
+    RoomCommand::Run(f) => f(game_server),
+    RoomCommand::LateMove(uuid, moved) => {
+      late_moves.insert(uuid, moved);
     }
   }
 }
 
-async fn handle_connection(game_server: Arc<Mutex<GameServer>>, room: Room, socket: WebSocket) {
-  // Ensure we're simulating this game.
-  {
-    let mut game_server_unlocked = game_server.lock().unwrap();
-    if game_server_unlocked.state.status == GameStatus::Connecting {
-      game_server_unlocked.state.status = GameStatus::Lobby;
-      tokio::spawn(simulation_loop(game_server.clone(), room.clone()));
+// Saves the recording of one round of a game as {game id}-{round}.json, if
+// we're keeping recordings.
+fn save_round(id: UUID, round: u32, recording: &RecordedGame, recording_dir: Option<&Path>) {
+  if let Some(dir) = recording_dir {
+    let path = dir.join(format!("{}-{}.json", id, round));
+    if let Err(e) = save_recording(recording, &path) {
+      error!("Unable to save the recording to {}: {}", path.display(), e);
     }
   }
+}
+
+fn save_recording(recording: &RecordedGame, path: &Path) -> Result<(), Box<dyn Error>> {
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+  std::fs::write(path, serde_json::to_string(recording)?)?;
+  info!("Saved the recording to {}", path.display());
+  Ok(())
+}
+
+async fn handle_connection(
+  game: Game,
+  live_games: LiveGames,
+  socket: WebSocket,
+  max_message_bytes: usize,
+) {
+  // Generate a connection id for our user, and ensure we're simulating this
+  // game.
+  let registered_game = game.clone();
+  let uuid = game
+    .call(move |game_server| {
+      if game_server.state.status == GameStatus::Connecting {
+        game_server.state.status = GameStatus::Lobby;
+        live_games
+          .lock()
+          .unwrap()
+          .insert(registered_game.id, registered_game);
+      }
+      game_server.get_uuid_for_new_connection()
+    })
+    .await;
+  let uuid = match uuid {
+    Some(uuid) => uuid,
+    None => {
+      debug!("The game finished while they were connecting");
+      return;
+    }
+  };
+  let span = info_span!("player", player = %uuid);
+  span.in_scope(|| info!("Connected"));
+  CONNECTIONS.inc();
 
   // Insert the write part of this peer to the peer map.
   let (tx, rx) = unbounded();
   let (outgoing, incoming) = socket.split();
 
-  // Generate a connection id for our user.
-  let uuid = game_server.lock().unwrap().get_uuid_for_new_connection();
-
   // Hook up the ability to send messages to this client.
-  room.lock().unwrap().insert(uuid, tx);
+  game.room.lock().unwrap().insert(uuid, tx);
 
   // Buffer and forward messages.
   let receive_from_others = rx.map(Ok).forward(outgoing);
 
   // Handle incoming messages from the client.
+  let mut limits = ConnectionLimits::new(Instant::now());
+  // Once we've decided to disconnect them, ignore whatever else they send.
+  let mut cut_off = false;
   let broadcast_incoming = incoming.try_for_each(|msg| {
+    if cut_off {
+      return future::ok(());
+    }
     let message_text = match msg.to_str() {
       Ok(s) => s,
       Err(_) => return future::ok(()), // other kind of message, ignore
     };
-    println!("Received a message from {}: {:?}", uuid, message_text);
-    let message: ClientToServerMessage = match serde_json::from_str(&message_text) {
+    // Check the limits before parsing, so that floods are cheap to turn away.
+    let now = Instant::now();
+    if message_text.len() > max_message_bytes {
+      MESSAGES_REJECTED
+        .with_label_values(&["unknown", "too_large"])
+        .inc();
+      cut_off = true;
+      disconnect_abusive(
+        &game,
+        uuid,
+        format!(
+          "sending a message of {} bytes, when the most allowed is {}",
+          message_text.len(),
+          max_message_bytes
+        ),
+      );
+      return future::ok(());
+    }
+    if let Err(rejection) = limits.check_frame(now) {
+      cut_off = reject_message(&game, uuid, "unknown", rejection);
+      return future::ok(());
+    }
+    trace!("Received {:?}", message_text);
+    let message: ClientToServerMessage = match serde_json::from_str(message_text) {
       Ok(m) => m,
       Err(e) => {
-        println!("Unable to deserialize {:?} – {:?}", message_text, e);
+        SERIALIZATION_ERRORS.with_label_values(&["in"]).inc();
+        warn!("Unable to deserialize {:?}: {}", message_text, e);
+        let problem = e.to_string();
+        game.run(move |game_server| {
+          if let Err(e) = game_server.malformed_message(uuid, &problem) {
+            warn!(player = %uuid, "Error telling them about their malformed message: {}", e);
+          }
+        });
         return future::ok(());
       }
     };
-    let mut game_server = game_server.lock().unwrap();
-    match game_server.handle_message(uuid, message) {
-      Ok(_) => (),
-      Err(e) => {
-        println!("Failed to handle message from {:?}: {}", uuid, e);
-        return future::ok(());
+    if let Err(rejection) = limits.check_kind(message.kind(), now) {
+      cut_off = reject_message(&game, uuid, message.kind(), rejection);
+      if let (false, ClientToServerMessage::Move(moved)) = (cut_off, message) {
+        game.send(RoomCommand::LateMove(uuid, moved));
       }
+      return future::ok(());
     }
+    MESSAGES_RECEIVED.with_label_values(&[message.kind()]).inc();
+    game.send(RoomCommand::Message(uuid, message));
     future::ok(())
   });
 
@@ -178,28 +522,145 @@ async fn handle_connection(game_server: Arc<Mutex<GameServer>>, room: Room, sock
   pin_mut!(broadcast_incoming, receive_from_others);
 
   // Wait for either side to close the connection
-  future::select(broadcast_incoming, receive_from_others).await;
+  future::select(broadcast_incoming, receive_from_others)
+    .instrument(span.clone())
+    .await;
 
-  println!("{} disconnected", uuid);
-  room.lock().unwrap().remove(&uuid);
+  let _entered = span.enter();
+  info!("Disconnected");
+  CONNECTIONS.dec();
+  game.room.lock().unwrap().remove(&uuid);
+  // Disconnect this connection from the game server.
+  game.send(RoomCommand::Disconnected(uuid));
+}
 
-  let mut game_server = game_server.lock().unwrap();
-  if game_server.state.status.finished() {
-    // The game is done, and the simulation loop will clean up, just return.
-  } else {
-    // Disconnect this connection from the game server.
-    match game_server.disconnected(uuid) {
-      Ok(()) => (),
-      Err(e) => println!("Error handling disconnection: {}", e),
+// Drops a message that's over the rate limits, and disconnects the sender if
+// they've kept it up for too long. Returns whether they were disconnected.
+fn reject_message(game: &Game, uuid: UUID, kind: &'static str, rejection: Rejection) -> bool {
+  MESSAGES_REJECTED
+    .with_label_values(&[kind, "rate_limited"])
+    .inc();
+  match rejection {
+    Rejection::RateLimited => {
+      debug!("A {} message was over the rate limit", kind);
+      false
+    }
+    Rejection::Abusive => {
+      disconnect_abusive(game, uuid, "sending too many messages".to_string());
+      true
     }
   }
 }
 
-fn broadcast(room: Room, msg: &Message) {
+fn disconnect_abusive(game: &Game, uuid: UUID, problem: String) {
+  warn!("Disconnecting them for {}", problem);
+  ABUSIVE_DISCONNECTIONS.inc();
+  game.run(move |game_server| {
+    if let Err(e) = game_server.protocol_violation(uuid, &problem) {
+      warn!(player = %uuid, "Error disconnecting them: {}", e);
+    }
+  });
+}
+
+// Sends the message to everyone in the room but `except`. Returns how many
+// players it was sent to.
+fn broadcast(room: Room, msg: &Message, except: &BTreeSet<UUID>) -> u64 {
   let peers = room.lock().unwrap();
-  for (_, recp) in peers.iter() {
-    if let Err(e) = recp.unbounded_send(msg.clone()) {
-      println!("Error with unbounded send: {}", e);
+  let mut sent = 0;
+  for (uuid, recp) in peers.iter() {
+    if except.contains(uuid) {
+      continue;
+    }
+    match recp.unbounded_send(msg.clone()) {
+      Ok(()) => sent += 1,
+      Err(e) => warn!("Error with unbounded send: {}", e),
+    }
+  }
+  sent
+}
+
+// Waits until we're asked to stop, with SIGTERM or ctrl-c.
+pub async fn shutdown_requested() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+      Ok(mut terminate) => {
+        tokio::select! {
+          _ = tokio::signal::ctrl_c() => {},
+          _ = terminate.recv() => {},
+        }
+        return;
+      }
+      Err(e) => warn!("Unable to listen for SIGTERM: {}", e),
+    }
+  }
+  if let Err(e) = tokio::signal::ctrl_c().await {
+    warn!("Unable to listen for ctrl-c: {}", e);
+    future::pending::<()>().await;
+  }
+}
+
+// Stops new games from starting, and waits for up to the deadline for the
+// rounds being played to finish. Then ends every game, saving their recordings.
+pub async fn drain_games(ws_server: &Mutex<WebsocketServer>, deadline: Duration) {
+  let (live_games, current_game) = {
+    let mut ws_server = ws_server.lock().unwrap();
+    ws_server.shutting_down = true;
+    (ws_server.live_games.clone(), ws_server.current_game.clone())
+  };
+  // Don't hold the lock on the live games while waiting on them.
+  let games = || -> Vec<Game> { live_games.lock().unwrap().values().cloned().collect() };
+  // The current game may not be live yet, if someone's only just connecting to it.
+  let mut preparing = games();
+  if !preparing.iter().any(|game| game.id == current_game.id) {
+    preparing.push(current_game);
+  }
+  for game in preparing {
+    game.run(|game_server| {
+      if let Err(e) = game_server.prepare_for_restart() {
+        warn!("Unable to tell players about the restart: {}", e);
+      }
+    });
+  }
+
+  let start = Instant::now();
+  loop {
+    let mut in_progress = 0;
+    for game in games() {
+      if game
+        .call(|game_server| game_server.round_in_progress())
+        .await
+        == Some(true)
+      {
+        in_progress += 1;
+      }
+    }
+    if in_progress == 0 {
+      break;
+    }
+    if start.elapsed() >= deadline {
+      warn!("Cutting short {} games in progress", in_progress);
+      break;
     }
+    info!("Waiting for {} games in progress to finish", in_progress);
+    sleep(Duration::from_secs(5).min(deadline)).await;
+  }
+
+  for game in games() {
+    game.run(|game_server| {
+      if let Err(e) = game_server.end_for_restart() {
+        warn!("Unable to end the game: {}", e);
+      }
+    });
+  }
+  // The rooms notice that their games have ended, save their
+  // recordings and close their connections.
+  let start = Instant::now();
+  while !live_games.lock().unwrap().is_empty() && start.elapsed() < Duration::from_secs(5) {
+    sleep(Duration::from_millis(16)).await;
   }
+  // Give the connections a moment to send their last messages.
+  sleep(Duration::from_millis(100)).await;
+  info!("All games have ended");
 }
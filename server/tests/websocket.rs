@@ -0,0 +1,721 @@
+use futures_util::{SinkExt, StreamExt};
+use rust_us_core::*;
+use rust_us_server::config::Config;
+use rust_us_server::routes;
+use rust_us_server::server::{drain_games, WebsocketServer};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+// Long enough for a vote to be counted and its outcome shown.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Serves the same routes as the dev server, on a free port.
+fn start_server(config: Config) -> SocketAddr {
+  start_server_with_state(config).0
+}
+
+fn start_server_with_state(config: Config) -> (SocketAddr, Arc<Mutex<WebsocketServer>>) {
+  let config = Arc::new(config);
+  let ws_server = Arc::new(Mutex::new(WebsocketServer::new(config.clone())));
+  let (addr, server) =
+    warp::serve(routes(ws_server.clone(), &config)).bind_ephemeral(([127, 0, 0, 1], 0));
+  tokio::spawn(server);
+  (addr, ws_server)
+}
+
+// Makes a plain HTTP/1.1 request, and returns the response's status code and
+// body.
+async fn http(
+  addr: SocketAddr,
+  method: &str,
+  path: &str,
+  token: Option<&str>,
+  body: &str,
+) -> Result<(u16, String), Box<dyn Error>> {
+  let mut stream = TcpStream::connect(addr).await?;
+  let authorization = match token {
+    Some(token) => format!("Authorization: Bearer {}\r\n", token),
+    None => String::new(),
+  };
+  let request = format!(
+    "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    method,
+    path,
+    addr,
+    authorization,
+    body.len(),
+    body
+  );
+  stream.write_all(request.as_bytes()).await?;
+  let mut response = String::new();
+  tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response)).await??;
+  let (head, body) = response
+    .split_once("\r\n\r\n")
+    .ok_or("No end to the response's headers")?;
+  let status = head
+    .split(' ')
+    .nth(1)
+    .ok_or("No status in the response")?
+    .parse()?;
+  Ok((status, body.to_string()))
+}
+
+// Players can only see the people near them at night, and the start table
+// blocks the view across it. So this moves everyone into a row below the
+// table, where they can all see each other, and returns what the first
+// client sees once they're there.
+async fn gather(clients: &mut [TestClient]) -> Result<Snapshot, Box<dyn Error>> {
+  let mut spots = vec![];
+  for (i, client) in clients.iter_mut().enumerate() {
+    let position = Position {
+      x: 200.0 + 40.0 * i as f64,
+      y: 400.0,
+    };
+    client
+      .send(ClientToServerMessage::Move(MoveMessage {
+        speed: Velocity { dx: 0.0, dy: 0.0 },
+        position,
+      }))
+      .await?;
+    spots.push((client.uuid, position));
+  }
+  let mut seen = vec![];
+  for client in clients.iter_mut() {
+    seen.push(client.wait_to_see(&spots).await?);
+  }
+  Ok(seen.swap_remove(0))
+}
+
+// A player connected to the server over a real websocket, speaking the same
+// JSON protocol as the browser client.
+struct TestClient {
+  socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+  uuid: UUID,
+  // Everything the server has sent, in order.
+  received: Vec<ServerToClientMessage>,
+}
+
+impl TestClient {
+  async fn join(addr: SocketAddr, name: &str) -> Result<TestClient, Box<dyn Error>> {
+    let (socket, _) = connect_async(format!("ws://{}/", addr)).await?;
+    let mut client = TestClient {
+      socket,
+      uuid: UUID::random(),
+      received: vec![],
+    };
+    client
+      .send(ClientToServerMessage::Join {
+        version: get_version_sha().to_string(),
+        details: JoinRequest::JoinAsPlayer {
+          preferred_color: Color::all()[0],
+          name: name.to_string(),
+        },
+        reconnect_token: None,
+      })
+      .await?;
+    client.uuid = client
+      .wait_for(|message| match message {
+        ServerToClientMessage::Welcome { connection_id } => Some(*connection_id),
+        _ => None,
+      })
+      .await?;
+    Ok(client)
+  }
+
+  async fn send(&mut self, message: ClientToServerMessage) -> Result<(), Box<dyn Error>> {
+    self.send_text(serde_json::to_string(&message)?).await
+  }
+
+  async fn send_text(&mut self, text: String) -> Result<(), Box<dyn Error>> {
+    self.socket.send(WsMessage::Text(text)).await?;
+    Ok(())
+  }
+
+  // Reads messages from the server until `matches` picks one out.
+  async fn wait_for<T>(
+    &mut self,
+    mut matches: impl FnMut(&ServerToClientMessage) -> Option<T>,
+  ) -> Result<T, Box<dyn Error>> {
+    let TestClient {
+      socket, received, ..
+    } = self;
+    let reading = async {
+      while let Some(message) = socket.next().await {
+        if let WsMessage::Text(text) = message? {
+          let message: ServerToClientMessage = serde_json::from_str(&text)?;
+          let found = matches(&message);
+          received.push(message);
+          if let Some(found) = found {
+            return Ok(found);
+          }
+        }
+      }
+      Err("The server closed the connection".into())
+    };
+    tokio::time::timeout(TIMEOUT, reading)
+      .await
+      .map_err(|_| "Timed out waiting for a message from the server")?
+  }
+
+  async fn wait_for_snapshot(
+    &mut self,
+    mut matches: impl FnMut(&Snapshot) -> bool,
+  ) -> Result<Snapshot, Box<dyn Error>> {
+    self
+      .wait_for(|message| match message {
+        ServerToClientMessage::Snapshot(snapshot) if matches(snapshot) => Some(snapshot.clone()),
+        _ => None,
+      })
+      .await
+  }
+
+  // The names of the players in the next snapshot with the given number of players.
+  async fn wait_for_players(&mut self, count: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    let snapshot = self
+      .wait_for_snapshot(|snapshot| snapshot.players.len() == count)
+      .await?;
+    let mut names: Vec<String> = snapshot.players.iter().map(|p| p.name.clone()).collect();
+    names.sort();
+    Ok(names)
+  }
+
+  // Waits until this client can see every one of the given players where
+  // they've moved to.
+  async fn wait_to_see(&mut self, spots: &[(UUID, Position)]) -> Result<Snapshot, Box<dyn Error>> {
+    self
+      .wait_for_snapshot(|s| {
+        spots.iter().all(|(uuid, position)| {
+          s.players
+            .iter()
+            .any(|p| p.uuid == *uuid && p.position == *position)
+        })
+      })
+      .await
+  }
+
+  fn was_shown(&self, text: &str) -> bool {
+    self.received.iter().any(|message| match message {
+      ServerToClientMessage::DisplayMessage(DisplayMessage {
+        message: Message::PlainString(shown),
+        ..
+      }) => shown == text,
+      _ => false,
+    })
+  }
+}
+
+#[tokio::test]
+async fn test_players_join_and_leave_over_websockets() -> Result<(), Box<dyn Error>> {
+  let addr = start_server(Config::default());
+
+  let mut alice = TestClient::join(addr, "Alice").await?;
+  assert_eq!(alice.wait_for_players(1).await?, vec!["Alice"]);
+
+  let bob = TestClient::join(addr, "Bob").await?;
+  assert_ne!(alice.uuid, bob.uuid);
+  assert_eq!(alice.wait_for_players(2).await?, vec!["Alice", "Bob"]);
+
+  let TestClient { mut socket, .. } = bob;
+  socket.close(None).await?;
+  assert_eq!(alice.wait_for_players(1).await?, vec!["Alice"]);
+
+  // Nonsense is answered with an error, but doesn't cost Alice her place.
+  alice.send_text("nonsense".to_string()).await?;
+  let code = alice
+    .wait_for(|message| match message {
+      ServerToClientMessage::Error { code, .. } => Some(*code),
+      _ => None,
+    })
+    .await?;
+  assert_eq!(code, ErrorCode::MalformedMessage);
+  alice
+    .send(ClientToServerMessage::SetReady { ready: true })
+    .await?;
+  let alice_id = alice.uuid;
+  alice
+    .wait_for_snapshot(|snapshot| {
+      snapshot
+        .players
+        .iter()
+        .any(|p| p.uuid == alice_id && p.ready)
+    })
+    .await?;
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_playing_a_game_over_websockets() -> Result<(), Box<dyn Error>> {
+  let addr = start_server(Config {
+    default_settings: Settings {
+      start_countdown: Duration::from_secs(0),
+      ..Settings::default()
+    },
+    ..Config::default()
+  });
+
+  let mut clients = vec![];
+  for name in &["P1", "P2", "P3", "P4"] {
+    clients.push(TestClient::join(addr, name).await?);
+  }
+  for client in clients.iter_mut() {
+    client.wait_for_players(4).await?;
+  }
+
+  // The first to join is the host, and starts the game.
+  clients[0].send(ClientToServerMessage::StartGame()).await?;
+  for client in clients.iter_mut() {
+    client
+      .wait_for_snapshot(|s| s.status == GameStatus::Playing(PlayState::Night))
+      .await?;
+  }
+  for client in clients.iter_mut() {
+    client
+      .wait_for(|message| match message {
+        ServerToClientMessage::DisplayMessage(DisplayMessage {
+          message: Message::PlainString(text),
+          ..
+        }) if text == "The game has begun!" => Some(()),
+        _ => None,
+      })
+      .await?;
+  }
+  let started = gather(&mut clients).await?;
+  let impostor = started.players.iter().find(|p| p.impostor).unwrap().clone();
+  let mut crew = started.players.iter().filter(|p| !p.impostor);
+  let victim = crew.next().unwrap().clone();
+  let reporter = crew.next().unwrap().clone();
+  let bystander = crew.next().unwrap().clone();
+  let index_of = |player: &Player| {
+    clients
+      .iter()
+      .position(|client| client.uuid == player.uuid)
+      .unwrap()
+  };
+  let (impostor_client, victim_client, reporter_client, bystander_client) = (
+    index_of(&impostor),
+    index_of(&victim),
+    index_of(&reporter),
+    index_of(&bystander),
+  );
+
+  // The reporter moves, and everyone sees them do it.
+  let moved_to = Position {
+    x: reporter.position.x + 10.0,
+    y: reporter.position.y,
+  };
+  clients[reporter_client]
+    .send(ClientToServerMessage::Move(MoveMessage {
+      speed: Velocity { dx: 0.0, dy: 0.0 },
+      position: moved_to,
+    }))
+    .await?;
+  for client in clients.iter_mut() {
+    client
+      .wait_for_snapshot(|s| {
+        s.players
+          .iter()
+          .any(|p| p.uuid == reporter.uuid && p.position == moved_to)
+      })
+      .await?;
+  }
+
+  // The impostor kills someone right next to them.
+  clients[impostor_client]
+    .send(ClientToServerMessage::Killed(DeadBody {
+      color: victim.color,
+      position: moved_to,
+    }))
+    .await?;
+  for client in clients.iter_mut() {
+    client
+      .wait_for_snapshot(|s| {
+        s.bodies.len() == 1 && s.players.iter().any(|p| p.uuid == victim.uuid && p.dead)
+      })
+      .await?;
+  }
+
+  // They report the body, which calls a meeting.
+  clients[reporter_client]
+    .send(ClientToServerMessage::ReportBody {
+      dead_body_color: victim.color,
+    })
+    .await?;
+  for client in clients.iter_mut() {
+    client
+      .wait_for_snapshot(|s| matches!(s.status, GameStatus::Playing(PlayState::Voting(_))))
+      .await?;
+  }
+
+  // The crew vote the impostor out, and so win the game.
+  let vote_for_impostor = ClientToServerMessage::Vote {
+    target: VoteTarget::Player {
+      uuid: impostor.uuid,
+    },
+  };
+  clients[reporter_client]
+    .send(vote_for_impostor.clone())
+    .await?;
+  clients[bystander_client].send(vote_for_impostor).await?;
+  clients[impostor_client]
+    .send(ClientToServerMessage::Vote {
+      target: VoteTarget::Skip,
+    })
+    .await?;
+
+  for client in clients.iter_mut() {
+    let replay = client
+      .wait_for(|message| match message {
+        ServerToClientMessage::Replay(recording) => Some(recording.clone()),
+        _ => None,
+      })
+      .await?;
+    assert_eq!(replay.winner, Some(Team::Crew));
+    let stats = client
+      .wait_for(|message| match message {
+        ServerToClientMessage::GameOver(stats) => Some(stats.clone()),
+        _ => None,
+      })
+      .await?;
+    assert_eq!(stats.winner, Team::Crew);
+    assert!(client.was_shown("Crew win!"));
+  }
+  assert!(clients[victim_client].received.iter().any(|message| matches!(
+    message,
+    ServerToClientMessage::Snapshot(s) if s.players.iter().any(|p| p.uuid == victim.uuid && p.dead)
+  )));
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_each_round_is_saved() -> Result<(), Box<dyn Error>> {
+  let recording_dir = std::env::temp_dir().join(format!("recordings-{}", UUID::random()));
+  let addr = start_server(Config {
+    recording_dir: Some(recording_dir.clone()),
+    default_settings: Settings {
+      start_countdown: Duration::from_secs(0),
+      ..Settings::default()
+    },
+    ..Config::default()
+  });
+
+  let mut clients = vec![];
+  for name in &["P1", "P2", "P3", "P4"] {
+    clients.push(TestClient::join(addr, name).await?);
+  }
+  clients[0].wait_for_players(4).await?;
+
+  for round in 0..2 {
+    if round > 0 {
+      clients[0]
+        .send(ClientToServerMessage::ReturnToLobby())
+        .await?;
+      clients[0]
+        .wait_for_snapshot(|s| s.status == GameStatus::Lobby)
+        .await?;
+    }
+    clients[0].send(ClientToServerMessage::StartGame()).await?;
+    for client in clients.iter_mut() {
+      client
+        .wait_for_snapshot(|s| s.status == GameStatus::Playing(PlayState::Night))
+        .await?;
+    }
+    let started = gather(&mut clients).await?;
+    // The impostor kills two of the crew, which leaves them even with the
+    // crew, and so they win.
+    let impostor = started.players.iter().find(|p| p.impostor).unwrap();
+    let impostor_client = clients
+      .iter()
+      .position(|client| client.uuid == impostor.uuid)
+      .unwrap();
+    for victim in started.players.iter().filter(|p| !p.impostor).take(2) {
+      clients[impostor_client]
+        .send(ClientToServerMessage::Killed(DeadBody {
+          color: victim.color,
+          position: victim.position,
+        }))
+        .await?;
+    }
+    clients[0]
+      .wait_for(|message| match message {
+        ServerToClientMessage::Replay(recording) => recording.winner,
+        _ => None,
+      })
+      .await?;
+  }
+
+  // The last round is saved when everyone leaves.
+  for client in clients {
+    let TestClient { mut socket, .. } = client;
+    socket.close(None).await?;
+  }
+  let mut saved = vec![];
+  let start = std::time::Instant::now();
+  while saved.len() < 2 && start.elapsed() < TIMEOUT {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    saved = match std::fs::read_dir(&recording_dir) {
+      Ok(entries) => entries
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>, std::io::Error>>()?,
+      Err(_) => vec![],
+    };
+  }
+  saved.sort();
+  assert_eq!(saved.len(), 2, "saved {:?}", saved);
+  assert!(saved[0].ends_with("-1.json"));
+  assert!(saved[1].ends_with("-2.json"));
+  for file in saved.iter() {
+    let recording: RecordedGame =
+      serde_json::from_str(&std::fs::read_to_string(recording_dir.join(file))?)?;
+    assert_eq!(recording.winner, Some(Team::Impostors));
+  }
+  std::fs::remove_dir_all(&recording_dir)?;
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_no_one_joins_while_shutting_down() -> Result<(), Box<dyn Error>> {
+  let (addr, ws_server) = start_server_with_state(Config {
+    default_settings: Settings {
+      start_countdown: Duration::from_secs(0),
+      ..Settings::default()
+    },
+    ..Config::default()
+  });
+  let mut clients = vec![];
+  for name in &["P1", "P2", "P3", "P4"] {
+    clients.push(TestClient::join(addr, name).await?);
+  }
+  clients[0].wait_for_players(4).await?;
+  clients[0].send(ClientToServerMessage::StartGame()).await?;
+  clients[0]
+    .wait_for_snapshot(|s| s.status == GameStatus::Playing(PlayState::Night))
+    .await?;
+
+  // The round in progress can finish, but no one new can join the game,
+  // even to watch.
+  let draining = ws_server.clone();
+  tokio::spawn(async move { drain_games(&draining, TIMEOUT).await });
+  clients[0]
+    .wait_for(|message| match message {
+      ServerToClientMessage::DisplayMessage(DisplayMessage {
+        message: Message::PlainString(text),
+        ..
+      }) if text.starts_with("The server is restarting soon") => Some(()),
+      _ => None,
+    })
+    .await?;
+  let (socket, _) = connect_async(format!("ws://{}/", addr)).await?;
+  let mut latecomer = TestClient {
+    socket,
+    uuid: UUID::random(),
+    received: vec![],
+  };
+  let code = latecomer
+    .wait_for(|message| match message {
+      ServerToClientMessage::Error { code, .. } => Some(*code),
+      _ => None,
+    })
+    .await?;
+  assert_eq!(code, ErrorCode::ServerRestarting);
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_metrics_need_the_admin_token() -> Result<(), Box<dyn Error>> {
+  let addr = start_server(Config {
+    admin_token: Some("secret".to_string()),
+    ..Config::default()
+  });
+  let (status, _) = http(addr, "GET", "/metrics", None, "").await?;
+  assert_eq!(status, 401);
+  let (status, _) = http(addr, "GET", "/metrics", Some("guess"), "").await?;
+  assert_eq!(status, 401);
+  let (status, body) = http(addr, "GET", "/metrics", Some("secret"), "").await?;
+  assert_eq!(status, 200);
+  assert!(body.contains("airlock_connections"), "{}", body);
+
+  // Without a token, there are no metrics to see.
+  let addr = start_server(Config::default());
+  let (status, _) = http(addr, "GET", "/metrics", None, "").await?;
+  assert_eq!(status, 404);
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_routes_need_the_admin_token() -> Result<(), Box<dyn Error>> {
+  let addr = start_server(Config {
+    admin_token: Some("secret".to_string()),
+    ..Config::default()
+  });
+  // Checking the server is up doesn't need a token.
+  assert_eq!(
+    http(addr, "GET", "/healthz", None, "").await?,
+    (200, "ok".to_string())
+  );
+  let (status, _) = http(addr, "GET", "/admin/rooms", None, "").await?;
+  assert_eq!(status, 401);
+  let (status, _) = http(addr, "GET", "/admin/rooms", Some("guess"), "").await?;
+  assert_eq!(status, 401);
+  let (status, _) = http(addr, "POST", "/admin/announce", Some("secre"), "{}").await?;
+  assert_eq!(status, 401);
+  assert_eq!(
+    http(addr, "GET", "/admin/rooms", Some("secret"), "").await?,
+    (200, "[]".to_string())
+  );
+
+  // Without a token, the admin API isn't there at all.
+  let addr = start_server(Config::default());
+  assert_eq!(http(addr, "GET", "/healthz", None, "").await?.0, 200);
+  let (status, _) = http(addr, "GET", "/admin/rooms", None, "").await?;
+  assert_eq!(status, 404);
+  let (status, _) = http(addr, "GET", "/admin/rooms", Some("secret"), "").await?;
+  assert_eq!(status, 404);
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_looking_after_rooms_over_http() -> Result<(), Box<dyn Error>> {
+  let addr = start_server(Config {
+    admin_token: Some("secret".to_string()),
+    ..Config::default()
+  });
+  let token = Some("secret");
+  let mut alice = TestClient::join(addr, "Alice").await?;
+  let mut bob = TestClient::join(addr, "Bob").await?;
+  alice.wait_for_players(2).await?;
+
+  let (status, body) = http(addr, "GET", "/admin/rooms", token, "").await?;
+  assert_eq!(status, 200);
+  let rooms: Vec<serde_json::Value> = serde_json::from_str(&body)?;
+  assert_eq!(rooms.len(), 1, "{}", body);
+  assert_eq!(rooms[0]["status"], "Lobby");
+  assert_eq!(rooms[0]["players"], 2);
+  assert_eq!(rooms[0]["connections"], 2);
+  let room: UUID = rooms[0]["id"].as_str().ok_or("No room id")?.parse()?;
+
+  let (status, body) = http(addr, "GET", &format!("/admin/rooms/{}", room), token, "").await?;
+  assert_eq!(status, 200);
+  let snapshot: Snapshot = serde_json::from_str(&body)?;
+  let mut names: Vec<_> = snapshot.players.iter().map(|p| p.name.as_str()).collect();
+  names.sort_unstable();
+  assert_eq!(names, vec!["Alice", "Bob"]);
+  let (status, _) = http(
+    addr,
+    "GET",
+    &format!("/admin/rooms/{}", UUID::random()),
+    token,
+    "",
+  )
+  .await?;
+  assert_eq!(status, 404);
+
+  // Announcements reach the room they're sent to, or every room.
+  let announce = r#"{"text": "Hello, room", "duration_secs": 5}"#;
+  let (status, _) = http(
+    addr,
+    "POST",
+    &format!("/admin/rooms/{}/announce", room),
+    token,
+    announce,
+  )
+  .await?;
+  assert_eq!(status, 200);
+  let announce = r#"{"text": "Hello, everyone"}"#;
+  let (status, _) = http(addr, "POST", "/admin/announce", token, announce).await?;
+  assert_eq!(status, 200);
+  for text in &["Hello, room", "Hello, everyone"] {
+    alice
+      .wait_for(|message| match message {
+        ServerToClientMessage::DisplayMessage(DisplayMessage {
+          message: Message::PlainString(shown),
+          ..
+        }) if shown == text => Some(()),
+        _ => None,
+      })
+      .await?;
+  }
+
+  // Bob is kicked, and Alice sees him go.
+  let kick = format!("/admin/rooms/{}/kick/{}", room, bob.uuid);
+  let (status, _) = http(addr, "POST", &kick, token, "").await?;
+  assert_eq!(status, 200);
+  let code = bob
+    .wait_for(|message| match message {
+      ServerToClientMessage::Error { code, .. } => Some(*code),
+      _ => None,
+    })
+    .await?;
+  assert_eq!(code, ErrorCode::Kicked);
+  assert_eq!(alice.wait_for_players(1).await?, vec!["Alice"]);
+  let kick = format!("/admin/rooms/{}/kick/{}", room, UUID::random());
+  let (status, _) = http(addr, "POST", &kick, token, "").await?;
+  assert_eq!(status, 404);
+
+  // Ending the room sends Alice away.
+  let (status, _) = http(
+    addr,
+    "POST",
+    &format!("/admin/rooms/{}/end", room),
+    token,
+    "",
+  )
+  .await?;
+  assert_eq!(status, 200);
+  let code = alice
+    .wait_for(|message| match message {
+      ServerToClientMessage::Error { code, .. } => Some(*code),
+      _ => None,
+    })
+    .await?;
+  assert_eq!(code, ErrorCode::GameEnded);
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_the_last_move_over_the_rate_limit_still_counts() -> Result<(), Box<dyn Error>> {
+  let addr = start_server(Config::default());
+  let mut alice = TestClient::join(addr, "Alice").await?;
+  alice.wait_for_players(1).await?;
+
+  // Far more moves at once than the rate limit allows, but not so many that
+  // Alice is disconnected for it. Where she ends up is the last one.
+  let moves = 100;
+  for i in 1..=moves {
+    alice
+      .send(ClientToServerMessage::Move(MoveMessage {
+        speed: Velocity { dx: 0.0, dy: 0.0 },
+        position: Position {
+          x: 100.0 + i as f64,
+          y: 400.0,
+        },
+      }))
+      .await?;
+  }
+  let alice_id = alice.uuid;
+  let last = Position {
+    x: 100.0 + moves as f64,
+    y: 400.0,
+  };
+  alice
+    .wait_for_snapshot(|s| {
+      s.players
+        .iter()
+        .any(|p| p.uuid == alice_id && p.position == last)
+    })
+    .await?;
+
+  Ok(())
+}
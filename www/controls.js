@@ -0,0 +1,175 @@
+// Ways to play besides the keyboard: a gamepad, and on-screen controls for
+// touch screens. Each keeps track of which way the player is pushing, as {dx,
+// dy} with each from -1 to 1, and which of the game's buttons are held.
+
+// Sticks rarely rest exactly at zero.
+const deadZone = 0.2;
+
+export function applyDeadZone(value) {
+  return Math.abs(value) < deadZone ? 0 : value;
+}
+
+// Button numbers in the standard gamepad layout.
+// https://w3c.github.io/gamepad/#remapping
+const gamepadButtons = {
+  activate: [0], // A
+  report: [1], // B
+  kill: [2], // X
+  play: [3, 9], // Y, Start
+  ban: [4], // Left bumper
+  cycle_perspective: [5], // Right bumper
+  change_color: [8], // Select
+};
+const dpad = {up: 12, down: 13, left: 14, right: 15};
+
+// Turns a gamepad's stick axes, and which of its buttons are pressed, into
+// the direction and buttons the game uses. The d-pad wins over the stick.
+export function mapGamepad(axes, pressed) {
+  const held = (i) => !!pressed[i];
+  let dx = applyDeadZone(axes[0] || 0);
+  let dy = applyDeadZone(axes[1] || 0);
+  if (held(dpad.left)) dx = -1;
+  if (held(dpad.right)) dx = 1;
+  if (held(dpad.up)) dy = -1;
+  if (held(dpad.down)) dy = 1;
+  const buttons = {};
+  for (const [name, indexes] of Object.entries(gamepadButtons)) {
+    buttons[name] = indexes.some(held);
+  }
+  return {direction: {dx, dy}, buttons};
+}
+
+// What the first connected gamepad is doing, or null if there isn't one. The
+// Gamepad API has no events for this, so it has to be checked every frame.
+export function readGamepad() {
+  const gamepads = navigator.getGamepads ? navigator.getGamepads() : [];
+  const gamepad = [...gamepads].find((g) => g && g.connected);
+  if (!gamepad) {
+    return null;
+  }
+  return mapGamepad(gamepad.axes, gamepad.buttons.map((b) => b.pressed));
+}
+
+export function isTouchScreen() {
+  return window.matchMedia('(pointer: coarse)').matches;
+}
+
+function styled(element, style) {
+  Object.assign(element.style, style);
+  return element;
+}
+
+// Adds a joystick in the bottom left of the screen and buttons in the bottom
+// right. Calls onChange whenever any of them move. The returned object is
+// kept up to date with what they're doing.
+export function createTouchControls(onChange) {
+  const state = {direction: {dx: 0, dy: 0}, buttons: {}};
+  const common = {
+    position: 'fixed',
+    bottom: '24px',
+    touchAction: 'none',
+    userSelect: 'none',
+    webkitUserSelect: 'none',
+  };
+
+  const joystickSize = 120;
+  const knobSize = 50;
+  const joystick = styled(document.createElement('div'), {
+    ...common,
+    left: '24px',
+    width: `${joystickSize}px`,
+    height: `${joystickSize}px`,
+    borderRadius: '50%',
+    background: '#0002',
+    border: '2px solid #0004',
+  });
+  const knob = styled(document.createElement('div'), {
+    position: 'absolute',
+    left: `${(joystickSize - knobSize) / 2}px`,
+    top: `${(joystickSize - knobSize) / 2}px`,
+    width: `${knobSize}px`,
+    height: `${knobSize}px`,
+    borderRadius: '50%',
+    background: '#0005',
+  });
+  joystick.appendChild(knob);
+  document.body.appendChild(joystick);
+
+  let joystickPointer = null;
+  function moveJoystick(ev) {
+    const rect = joystick.getBoundingClientRect();
+    const reach = joystickSize / 2;
+    let dx = (ev.clientX - (rect.left + reach)) / reach;
+    let dy = (ev.clientY - (rect.top + reach)) / reach;
+    // Keep the knob inside the circle.
+    const length = Math.hypot(dx, dy);
+    if (length > 1) {
+      dx /= length;
+      dy /= length;
+    }
+    knob.style.transform = `translate(${dx * reach}px, ${dy * reach}px)`;
+    state.direction = {dx: applyDeadZone(dx), dy: applyDeadZone(dy)};
+    onChange();
+  }
+  function releaseJoystick() {
+    joystickPointer = null;
+    knob.style.transform = '';
+    state.direction = {dx: 0, dy: 0};
+    onChange();
+  }
+  joystick.addEventListener('pointerdown', (ev) => {
+    joystickPointer = ev.pointerId;
+    joystick.setPointerCapture(ev.pointerId);
+    moveJoystick(ev);
+    ev.preventDefault();
+  });
+  joystick.addEventListener('pointermove', (ev) => {
+    if (ev.pointerId === joystickPointer) {
+      moveJoystick(ev);
+    }
+  });
+  joystick.addEventListener('pointerup', releaseJoystick);
+  joystick.addEventListener('pointercancel', releaseJoystick);
+
+  const buttons = styled(document.createElement('div'), {
+    ...common,
+    right: '24px',
+    display: 'grid',
+    gridTemplateColumns: 'repeat(3, 72px)',
+    gap: '12px',
+  });
+  // In the lobby, the host kicks with Kill.
+  const labels = [
+    ['ban', 'Ban'],
+    ['kill', 'Kill'],
+    ['report', 'Report'],
+    ['cycle_perspective', 'View'],
+    ['play', 'Start'],
+    ['activate', 'Use'],
+  ];
+  for (const [name, label] of labels) {
+    const button = styled(document.createElement('button'), {
+      width: '72px',
+      height: '72px',
+      borderRadius: '50%',
+      border: '2px solid #0004',
+      background: '#fffa',
+      font: '14px Arial Black',
+      touchAction: 'none',
+    });
+    button.textContent = label;
+    const hold = (held) => (ev) => {
+      state.buttons[name] = held;
+      onChange();
+      ev.preventDefault();
+    };
+    button.addEventListener('pointerdown', hold(true));
+    button.addEventListener('pointerup', hold(false));
+    button.addEventListener('pointercancel', hold(false));
+    button.addEventListener('pointerleave', hold(false));
+    buttons.appendChild(button);
+  }
+  document.body.appendChild(buttons);
+
+  return state;
+}
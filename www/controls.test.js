@@ -0,0 +1,51 @@
+import assert from 'node:assert/strict';
+import {test} from 'node:test';
+import {applyDeadZone, mapGamepad} from './controls.js';
+
+// Which buttons are pressed, as the Gamepad API lists them.
+function pressing(...indexes) {
+  const pressed = new Array(17).fill(false);
+  for (const i of indexes) {
+    pressed[i] = true;
+  }
+  return pressed;
+}
+
+test('small stick movements are ignored', () => {
+  assert.equal(applyDeadZone(0.1), 0);
+  assert.equal(applyDeadZone(-0.19), 0);
+  assert.equal(applyDeadZone(0.2), 0.2);
+  assert.equal(applyDeadZone(-0.8), -0.8);
+});
+
+test('the stick sets the direction', () => {
+  const {direction} = mapGamepad([0.5, -0.1], pressing());
+  assert.deepEqual(direction, {dx: 0.5, dy: 0});
+});
+
+test('the d-pad wins over the stick', () => {
+  const {direction} = mapGamepad([0.5, 0.5], pressing(14, 12));
+  assert.deepEqual(direction, {dx: -1, dy: -1});
+});
+
+test('missing axes count as resting', () => {
+  const {direction} = mapGamepad([], pressing());
+  assert.deepEqual(direction, {dx: 0, dy: 0});
+});
+
+test('buttons map to what the game calls them', () => {
+  assert.deepEqual(mapGamepad([0, 0], pressing(0, 2, 4)).buttons, {
+    activate: true,
+    report: false,
+    kill: true,
+    play: false,
+    ban: true,
+    cycle_perspective: false,
+    change_color: false,
+  });
+  // Both Y and Start start the game.
+  assert.equal(mapGamepad([0, 0], pressing(3)).buttons.play, true);
+  assert.equal(mapGamepad([0, 0], pressing(9)).buttons.play, true);
+  assert.equal(mapGamepad([0, 0], pressing(5, 8)).buttons.cycle_perspective, true);
+  assert.equal(mapGamepad([0, 0], pressing(5, 8)).buttons.change_color, true);
+});
@@ -1,17 +1,26 @@
 import initWasm, * as wasm from './wasm/client.js';
+import {createTouchControls, isTouchScreen, readGamepad} from './controls.js';
 
 const spectatorMode = /spectate/.test(window.location.search);
+// The server turns away names that are empty or longer than this.
+const maxNameLength = 20;
+
+function isValidName(name) {
+  return typeof name === 'string' && name.trim() !== '' &&
+      [...name.trim()].length <= maxNameLength;
+}
 
 async function getName() {
   const input = document.createElement('input');
+  input.maxLength = maxNameLength;
   const label = document.createElement('label');
   label.textContent = 'Nickname: ';
   label.appendChild(input);
   document.body.appendChild(label);
   const name = await new Promise((resolve) => {
     input.addEventListener('keydown', (ev) => {
-      if (ev.key === 'Enter') {
-        resolve(input.value);
+      if (ev.key === 'Enter' && isValidName(input.value)) {
+        resolve(input.value.trim());
       }
     });
   });
@@ -22,7 +31,7 @@ async function getName() {
 async function init() {
   await initWasm();
   let name = window.localStorage.getItem('name');
-  if (!spectatorMode && typeof name !== 'string') {
+  if (!spectatorMode && !isValidName(name)) {
     name = await getName();
     window.localStorage.setItem('name', name);
   }
@@ -49,6 +58,8 @@ async function init() {
   let previousFrameTime = performance.now();
   let running = true;
   let displayPerf = window.localStorage.displayPerf === 'true';
+  let showMinimap = window.localStorage.showMinimap === 'true';
+  game.set_minimap_visible(showMinimap);
   function drawOneFrame() {
     const timestamp = performance.now();
     const elapsed = timestamp - previousFrameTime;
@@ -101,32 +112,67 @@ async function init() {
   const knownButtons = new Set([
     'w', 'a', 's', 'd', 'q', 'e', 'r', ' ', 'p',
     'arrowup', 'arrowdown', 'arrowleft', 'arrowright',
-    'j', 'k', 'l', 'f11'
+    'j', 'k', 'l', 'v', 'b', 'c', 'f11'
   ]);
   const heldButtons = {};
   for (const button of knownButtons) {
     heldButtons[button] = false;
   }
+  const touch = isTouchScreen() ? createTouchControls(updateInput) : null;
+  let gamepad = null;
   function updateInput() {
     const up = heldButtons['w'] || heldButtons['arrowup'];
     const down = heldButtons['s'] || heldButtons['arrowdown'];
     const left = heldButtons['a'] || heldButtons['arrowleft'];
     const right = heldButtons['d'] || heldButtons['arrowright'];
-    const kill = heldButtons['q'];
-    const report = heldButtons['r'];
-    const activate = heldButtons['e'] || heldButtons[' '];
-    const play = heldButtons['p'];
+    // Whichever is being used, or all of them at once.
+    let dx = (right ? 1 : 0) - (left ? 1 : 0);
+    let dy = (down ? 1 : 0) - (up ? 1 : 0);
+    const held = (name) =>
+      !!((touch && touch.buttons[name]) || (gamepad && gamepad.buttons[name]));
+    for (const other of [touch, gamepad]) {
+      if (other) {
+        dx += other.direction.dx;
+        dy += other.direction.dy;
+      }
+    }
+    dx = Math.max(-1, Math.min(1, dx));
+    dy = Math.max(-1, Math.min(1, dy));
+    const kill = heldButtons['q'] || held('kill');
+    const report = heldButtons['r'] || held('report');
+    const activate = heldButtons['e'] || heldButtons[' '] || held('activate');
+    const play = heldButtons['p'] || held('play');
     const skip_back = heldButtons['j'];
     const skip_forward = heldButtons['l'];
     const pause_playback = heldButtons['k'];
+    const cycle_perspective = heldButtons['v'] || held('cycle_perspective');
+    const ban = heldButtons['b'] || held('ban');
+    const change_color = heldButtons['c'] || held('change_color');
     game.set_inputs(
-      up, down, left, right, kill, report,
-      activate, play, skip_back, skip_forward, pause_playback);
+      dx, dy, kill, report,
+      activate, play, skip_back, skip_forward, pause_playback,
+      cycle_perspective, ban, change_color);
     if (!running) {
       running = true;
       requestAnimationFrame(drawOneFrame);
     }
   }
+  // Gamepads have to be checked every frame, even when the game isn't
+  // being drawn.
+  function pollGamepad() {
+    const latest = readGamepad();
+    if (latest === null && gamepad === null) {
+      // Nothing's plugged in, and nothing was.
+      requestAnimationFrame(pollGamepad);
+      return;
+    }
+    if (JSON.stringify(latest) !== JSON.stringify(gamepad)) {
+      gamepad = latest;
+      updateInput();
+    }
+    requestAnimationFrame(pollGamepad);
+  }
+  requestAnimationFrame(pollGamepad);
   document.addEventListener('keydown', (ev) => {
     const key = ev.key.toLowerCase();
     if (key == '/') {
@@ -135,6 +181,13 @@ async function init() {
       ev.preventDefault();
       return;
     }
+    if (key == 'm' && !(ev.ctrlKey || ev.metaKey || ev.altKey)) {
+      showMinimap = !showMinimap;
+      window.localStorage.showMinimap = showMinimap;
+      game.set_minimap_visible(showMinimap);
+      ev.preventDefault();
+      return;
+    }
     if (!knownButtons.has(key)) {
       return;
     }